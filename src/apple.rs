@@ -1,11 +1,29 @@
+#![allow(clippy::needless_return, clippy::question_mark, clippy::single_match)]
+
+use crate::bulk_reader::{BulkReader, TRANSFER_SIZE};
+use crate::error::QTError;
+use crate::packet_buffer::PacketBytes;
+use crate::transport::Transport;
+use log::info;
 use rusb::{
-    Context, Device, DeviceDescriptor, DeviceHandle, Direction, Error, Recipient, RequestType,
-    TransferType, UsbContext,
+    Context, Device, DeviceDescriptor, DeviceHandle, Direction, Error, Hotplug, HotplugBuilder,
+    Recipient, RequestType, TransferType, UsbContext,
 };
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// how long the device may take to come back with the qt configuration
+const QT_ENABLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how often wait_for_device looks at term, and polls the bus without hotplug support
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 pub struct AppleDevice {
+    // declared first so it is dropped, and its transfers are cancelled, before the handle closes
+    reader: Option<BulkReader>,
     device: Device<Context>,
     descriptor: DeviceDescriptor,
     index_config: u8,
@@ -16,6 +34,9 @@ pub struct AppleDevice {
     in_endpoint_address: u8,
     out_endpoint_address: u8,
     handle: DeviceHandle<Context>,
+    // usb serial, the device re-enumerates when the qt config is switched on
+    serial: String,
+    interface_claimed: bool,
 }
 
 impl AppleDevice {
@@ -23,8 +44,10 @@ impl AppleDevice {
         device: Device<Context>,
         descriptor: DeviceDescriptor,
         handle: DeviceHandle<Context>,
+        serial: String,
     ) -> Self {
         return AppleDevice {
+            reader: None,
             device,
             descriptor,
             index_config: 0,
@@ -35,28 +58,17 @@ impl AppleDevice {
             in_endpoint_address: 0,
             out_endpoint_address: 0,
             handle,
+            serial,
+            interface_claimed: false,
         };
     }
 
-    pub fn is_qt_enabled(&self) -> Result<bool, Error> {
-        let num_configuration = self.descriptor.num_configurations();
-        for config_idx in 0..num_configuration {
-            let desc = match self.device.config_descriptor(config_idx) {
-                Ok(e) => e,
-                Err(e) => return Err(e),
-            };
+    pub fn serial(&self) -> &str {
+        self.serial.as_str()
+    }
 
-            for interface in desc.interfaces() {
-                for interface_desc in interface.descriptors() {
-                    if interface_desc.class_code() == 0xFF
-                        && interface_desc.sub_class_code() == 0x2A
-                    {
-                        return Ok(true);
-                    }
-                }
-            }
-        }
-        Ok(false)
+    pub fn is_qt_enabled(&self) -> Result<bool, Error> {
+        has_qt_interface(&self.device, &self.descriptor)
     }
 
     pub fn claim_interface(&mut self) -> Option<Error> {
@@ -91,6 +103,7 @@ impl AppleDevice {
                             Err(e) => return Some(e),
                             _ => {}
                         };
+                        self.interface_claimed = true;
                         return None;
                     }
                 }
@@ -99,6 +112,30 @@ impl AppleDevice {
         Some(Error::NotFound)
     }
 
+    // several large transfers in flight instead of one max packet sized read at a time
+    pub fn start_reader(&mut self) -> Option<Error> {
+        match BulkReader::start(&self.handle, self.in_endpoint_address) {
+            Ok(reader) => {
+                self.reader = Some(reader);
+                None
+            }
+            Err(e) => Some(e),
+        }
+    }
+
+    pub fn release_interface(&mut self) -> Option<Error> {
+        // pending transfers have to be back before the interface goes
+        self.reader = None;
+
+        if !self.interface_claimed {
+            return None;
+        }
+
+        self.interface_claimed = false;
+
+        self.handle.release_interface(self.index_interface).err()
+    }
+
     pub fn init_bulk_endpoint(&mut self) -> Option<Error> {
         let num_configuration = self.descriptor.num_configurations();
         for config_idx in 0..num_configuration {
@@ -169,20 +206,25 @@ impl AppleDevice {
                 Err(e) => return Err(e),
             };
 
+            let deadline = Instant::now() + QT_ENABLE_TIMEOUT;
+
             loop {
-                self.handle = match context.open_device_with_vid_pid(
-                    self.descriptor.vendor_id(),
-                    self.descriptor.product_id(),
-                ) {
+                if Instant::now() > deadline {
+                    return Err(Error::Timeout);
+                }
+
+                // by serial, several phones of the same model share vid/pid
+                let (device, descriptor, handle) = match open_by_serial(&context, self.serial.as_str()) {
                     Some(e) => e,
-                    None => return Err(Error::NotFound),
+                    None => {
+                        sleep(Duration::from_millis(50));
+                        continue;
+                    }
                 };
 
-                self.device = self.handle.device();
-                self.descriptor = match self.device.device_descriptor() {
-                    Ok(d) => d,
-                    Err(e) => return Err(e),
-                };
+                self.device = device;
+                self.descriptor = descriptor;
+                self.handle = handle;
 
                 if match self.is_qt_enabled() {
                     Ok(e) => e,
@@ -240,7 +282,7 @@ impl AppleDevice {
     pub fn read_bulk(&self, buf: &mut [u8]) -> Result<usize, Error> {
         return self
             .handle
-            .read_bulk(self.in_endpoint_address, buf, Duration::from_secs(10));
+            .read_bulk(self.in_endpoint_address, buf, Duration::from_secs(1));
     }
 
     pub fn write_bulk(&self, buf: &[u8]) -> Result<usize, Error> {
@@ -250,7 +292,415 @@ impl AppleDevice {
     }
 }
 
-pub fn get_usb_device(sn: &str) -> Result<AppleDevice, Error> {
+impl Transport for AppleDevice {
+    fn init(&mut self) -> Result<(), QTError> {
+        match self.set_qt_enabled(true) {
+            Err(e) => return Err(QTError::Usb(e)),
+            _ => {}
+        };
+
+        match self.claim_interface() {
+            Some(e) => return Err(QTError::Usb(e)),
+            _ => {}
+        };
+
+        match self.init_bulk_endpoint() {
+            Some(e) => return Err(QTError::Usb(e)),
+            _ => {}
+        };
+
+        match self.clear_feature() {
+            Some(e) => return Err(QTError::Usb(e)),
+            _ => {}
+        };
+
+        match self.start_reader() {
+            Some(e) => return Err(QTError::Usb(e)),
+            _ => {}
+        };
+
+        Ok(())
+    }
+
+    fn max_read_packet_size(&self) -> usize {
+        match self.reader {
+            Some(_) => TRANSFER_SIZE,
+            None => self.in_max_packet_size as usize,
+        }
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, QTError> {
+        if self.reader.is_some() {
+            let data = match self.read_bytes() {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            if buf.len() < data.len() {
+                return Err(QTError::Transport(String::from("read buffer smaller than transfer")));
+            }
+
+            buf[..data.len()].copy_from_slice(data.as_ref());
+            return Ok(data.len());
+        }
+
+        match self.read_bulk(buf) {
+            Ok(e) => Ok(e),
+            // short read timeout so the qt loop can notice term
+            Err(Error::Timeout) => Ok(0),
+            Err(e) => Err(QTError::Usb(e)),
+        }
+    }
+
+    // the transfer buffers go up as they are, no copy
+    fn read_bytes(&mut self) -> Result<PacketBytes, QTError> {
+        let reader = match &mut self.reader {
+            Some(e) => e,
+            None => {
+                let mut buf = vec![0u8; self.in_max_packet_size as usize];
+                let size = match self.read_frame(&mut buf) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                buf.truncate(size);
+                return Ok(PacketBytes::from_vec(buf));
+            }
+        };
+
+        // same 1s as read_bulk so the qt loop can notice term
+        match reader.read(Duration::from_secs(1)) {
+            Ok(Some(e)) => Ok(e),
+            Ok(None) => Ok(PacketBytes::from_vec(Vec::new())),
+            Err(e) => Err(QTError::Usb(e)),
+        }
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, QTError> {
+        match self.write_bulk(buf) {
+            Ok(e) => Ok(e),
+            Err(e) => Err(QTError::Usb(e)),
+        }
+    }
+
+    fn reconnect(&mut self, term: &AtomicBool) -> Result<bool, QTError> {
+        // the old handle is dead, nothing to release
+        self.interface_claimed = false;
+
+        info!("waiting for {} to come back", self.serial);
+
+        match wait_for_device(self.serial.as_str(), term) {
+            Ok(Some(device)) => {
+                *self = device;
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(e) => Err(QTError::Usb(e)),
+        }
+    }
+
+    fn teardown(&mut self) -> Result<(), QTError> {
+        match self.release_interface() {
+            Some(e) => return Err(QTError::Usb(e)),
+            _ => {}
+        };
+
+        let enabled = match self.is_qt_enabled() {
+            Ok(e) => e,
+            Err(e) => return Err(QTError::Usb(e)),
+        };
+
+        if enabled {
+            match self.set_qt_enabled(false) {
+                Err(e) => return Err(QTError::Usb(e)),
+                _ => {}
+            };
+        }
+
+        Ok(())
+    }
+}
+
+// the hidden QuickTime configuration shows up as a vendor interface, class 0xFF subclass 0x2A
+pub fn has_qt_interface(device: &Device<Context>, descriptor: &DeviceDescriptor) -> Result<bool, Error> {
+    let num_configuration = descriptor.num_configurations();
+    for config_idx in 0..num_configuration {
+        let desc = match device.config_descriptor(config_idx) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        for interface in desc.interfaces() {
+            for interface_desc in interface.descriptors() {
+                if interface_desc.class_code() == 0xFF && interface_desc.sub_class_code() == 0x2A {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+pub const APPLE_VENDOR_ID: u16 = 0x05AC;
+
+fn read_serial(handle: &DeviceHandle<Context>, descriptor: &DeviceDescriptor) -> Result<String, Error> {
+    let duration = Duration::from_secs(1);
+
+    let languages = match handle.read_languages(duration) {
+        Ok(l) => l,
+        Err(e) => return Err(e),
+    };
+
+    // no string descriptors at all
+    if languages.is_empty() {
+        return Err(Error::NotFound);
+    }
+
+    handle.read_serial_number_string(languages[0], descriptor, duration)
+}
+
+// usb serials are the udid without the dash, older devices may report more than the udid.
+// sn can be either, the dash is dropped here
+fn serial_matches(usn: &str, sn: &str) -> bool {
+    let sn = sn.replace('-', "");
+    !sn.is_empty() && usn.get(..sn.len()).map(|p| p.eq_ignore_ascii_case(sn.as_str())).unwrap_or(false)
+}
+
+// bus-port.port like the linux sysfs name, the address changes when the qt config is switched
+// on but the port does not
+#[derive(PartialEq)]
+pub struct UsbLocation {
+    bus: u8,
+    ports: Vec<u8>,
+}
+
+impl UsbLocation {
+    // 1-2.3
+    pub fn parse(s: &str) -> Option<UsbLocation> {
+        let (bus, ports) = match s.split_once('-') {
+            Some(e) => e,
+            None => return None,
+        };
+
+        let bus = match bus.parse::<u8>() {
+            Ok(e) => e,
+            Err(_) => return None,
+        };
+
+        let mut list = Vec::new();
+        for port in ports.split('.') {
+            match port.parse::<u8>() {
+                Ok(e) => list.push(e),
+                Err(_) => return None,
+            };
+        }
+
+        Some(UsbLocation { bus, ports: list })
+    }
+
+    fn of(device: &Device<Context>) -> Option<UsbLocation> {
+        match device.port_numbers() {
+            Ok(ports) if !ports.is_empty() => Some(UsbLocation {
+                bus: device.bus_number(),
+                ports,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    pub fn ports(&self) -> &[u8] {
+        self.ports.as_slice()
+    }
+}
+
+impl Display for UsbLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        write!(f, "{}-{}", self.bus, ports.join("."))
+    }
+}
+
+// port path when libusb knows it, bus:address otherwise
+fn describe_location(device: &Device<Context>) -> String {
+    match UsbLocation::of(device) {
+        Some(l) => l.to_string(),
+        None => format!("{:03}:{:03}", device.bus_number(), device.address()),
+    }
+}
+
+#[derive(Debug)]
+pub enum UsbMatchError {
+    // libusb itself failed, nothing was looked at
+    Usb(Error),
+    // no device matched, with every apple device that was skipped and why
+    NotFound(Vec<(String, String)>),
+    // no serial given and more than one device at the location
+    Ambiguous(Vec<String>),
+}
+
+impl Display for UsbMatchError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsbMatchError::Usb(e) => write!(f, "libusb: {}", e),
+            UsbMatchError::NotFound(skipped) => {
+                match write!(f, "no matching apple device") {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+                for (location, reason) in skipped {
+                    match write!(f, "\n  skipped {}: {}", location, reason) {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
+                }
+                Ok(())
+            }
+            UsbMatchError::Ambiguous(locations) => write!(
+                f,
+                "several apple devices match, pick one with -u or -usb: {}",
+                locations.join(", ")
+            ),
+        }
+    }
+}
+
+fn open_by_serial(
+    context: &Context,
+    sn: &str,
+) -> Option<(Device<Context>, DeviceDescriptor, DeviceHandle<Context>)> {
+    let devices = match context.devices() {
+        Ok(d) => d,
+        Err(_) => return None,
+    };
+
+    for device in devices.iter() {
+        let descriptor = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        if descriptor.vendor_id() != APPLE_VENDOR_ID {
+            continue;
+        }
+
+        let handle = match device.open() {
+            Ok(h) => h,
+            Err(_) => continue,
+        };
+
+        match read_serial(&handle, &descriptor) {
+            Ok(usn) if serial_matches(usn.as_str(), sn) => return Some((device, descriptor, handle)),
+            _ => {}
+        };
+    }
+
+    None
+}
+
+// the callback must not do blocking usb io, it only wakes up wait_for_device
+struct ArrivalNotifier {
+    tx: mpsc::Sender<()>,
+}
+
+impl Hotplug<Context> for ArrivalNotifier {
+    fn device_arrived(&mut self, _device: Device<Context>) {
+        let _ = self.tx.send(());
+    }
+
+    fn device_left(&mut self, _device: Device<Context>) {}
+}
+
+// blocks until the device with this serial is on the bus and can be opened, None once term is set
+pub fn wait_for_device(sn: &str, term: &AtomicBool) -> Result<Option<AppleDevice>, Error> {
+    let context = match Context::new() {
+        Ok(ctx) => ctx,
+        Err(e) => return Err(e),
+    };
+
+    let (tx, rx) = mpsc::channel();
+
+    // enumerate reports the devices already attached as arrivals too
+    let registration = match rusb::has_hotplug() {
+        true => match HotplugBuilder::new()
+            .vendor_id(APPLE_VENDOR_ID)
+            .enumerate(true)
+            .register(&context, Box::new(ArrivalNotifier { tx }))
+        {
+            Ok(r) => Some(r),
+            Err(e) => return Err(e),
+        },
+        false => None,
+    };
+
+    // an arrived device may not be openable yet while udev is still setting permissions
+    let mut arrived = registration.is_none();
+
+    while !term.load(Ordering::SeqCst) {
+        match registration {
+            Some(_) => match context.handle_events(Some(DEVICE_POLL_INTERVAL)) {
+                Err(e) => return Err(e),
+                _ => {}
+            },
+            None => sleep(DEVICE_POLL_INTERVAL),
+        };
+
+        while rx.try_recv().is_ok() {
+            arrived = true;
+        }
+
+        if !arrived {
+            continue;
+        }
+
+        if let Some((device, descriptor, handle)) = open_by_serial(&context, sn) {
+            return Ok(Some(AppleDevice::new(device, descriptor, handle, String::from(sn))));
+        }
+    }
+
+    Ok(None)
+}
+
+pub struct UsbDeviceInfo {
+    serial: Option<String>,
+    bus: u8,
+    address: u8,
+    location: Option<UsbLocation>,
+    product_id: u16,
+    qt_enabled: Option<bool>,
+}
+
+impl UsbDeviceInfo {
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn location(&self) -> Option<&UsbLocation> {
+        self.location.as_ref()
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    pub fn qt_enabled(&self) -> Option<bool> {
+        self.qt_enabled
+    }
+}
+
+// every apple device on the bus, the serial is None when we are not allowed to open it
+pub fn list_usb_devices() -> Result<Vec<UsbDeviceInfo>, Error> {
     let usb_context = match Context::new() {
         Ok(usb_context) => usb_context,
         Err(e) => return Err(e),
@@ -261,36 +711,122 @@ pub fn get_usb_device(sn: &str) -> Result<AppleDevice, Error> {
         Err(e) => return Err(e),
     };
 
-    let duration = Duration::from_secs(1);
+    let mut list: Vec<UsbDeviceInfo> = Vec::new();
 
     for device in devices.iter() {
-        let handle = match device.open() {
+        let descriptor = match device.device_descriptor() {
             Ok(d) => d,
-            Err(e) => return Err(e),
+            Err(_) => continue,
+        };
+
+        if descriptor.vendor_id() != APPLE_VENDOR_ID {
+            continue;
+        }
+
+        let serial = match device.open() {
+            Ok(handle) => read_serial(&handle, &descriptor).ok(),
+            Err(_) => None,
         };
 
+        list.push(UsbDeviceInfo {
+            serial,
+            bus: device.bus_number(),
+            address: device.address(),
+            location: UsbLocation::of(&device),
+            product_id: descriptor.product_id(),
+            qt_enabled: has_qt_interface(&device, &descriptor).ok(),
+        });
+    }
+
+    Ok(list)
+}
+
+// apple devices only, the others are never opened so root owned hubs don't get in the way.
+// sn and location narrow the choice down, without either the only apple device is taken
+pub fn get_usb_device(sn: Option<&str>, location: Option<&UsbLocation>) -> Result<AppleDevice, UsbMatchError> {
+    let usb_context = match Context::new() {
+        Ok(usb_context) => usb_context,
+        Err(e) => return Err(UsbMatchError::Usb(e)),
+    };
+
+    let devices = match usb_context.devices() {
+        Ok(d) => d,
+        Err(e) => return Err(UsbMatchError::Usb(e)),
+    };
+
+    let mut skipped: Vec<(String, String)> = Vec::new();
+    let mut found: Vec<AppleDevice> = Vec::new();
+    let mut found_locations: Vec<String> = Vec::new();
+
+    for device in devices.iter() {
         let descriptor = match device.device_descriptor() {
             Ok(d) => d,
-            Err(e) => return Err(e),
+            Err(_) => continue,
         };
 
-        let languages = match handle.read_languages(duration) {
-            Ok(l) => l,
-            Err(e) => return Err(e),
-        };
+        if descriptor.vendor_id() != APPLE_VENDOR_ID {
+            continue;
+        }
 
-        let usn = match handle.read_serial_number_string(languages[0], &descriptor, duration) {
-            Ok(sn) => sn,
-            Err(e) => return Err(e),
+        let device_location = describe_location(&device);
+
+        if let Some(location) = location {
+            if UsbLocation::of(&device).as_ref() != Some(location) {
+                skipped.push((device_location, format!("not at {}", location)));
+                continue;
+            }
+        }
+
+        let handle = match device.open() {
+            Ok(d) => d,
+            Err(e) => {
+                skipped.push((device_location, format!("open: {}", e)));
+                continue;
+            }
         };
 
-        let sn_bytes = sn.as_bytes();
-        let usn_bytes = &usn.as_bytes()[..sn_bytes.len()];
+        // the serial is needed to find the device again once the qt config is on
+        let usn = match read_serial(&handle, &descriptor) {
+            Ok(e) => e,
+            Err(e) => {
+                skipped.push((device_location, format!("serial: {}", e)));
+                continue;
+            }
+        };
 
-        if sn_bytes == usn_bytes {
-            return Ok(AppleDevice::new(device, descriptor, handle));
+        if let Some(sn) = sn {
+            if !serial_matches(usn.as_str(), sn) {
+                skipped.push((device_location, format!("serial {}", usn)));
+                continue;
+            }
         }
+
+        found.push(AppleDevice::new(device, descriptor, handle, usn));
+        found_locations.push(device_location);
     }
 
-    Err(Error::NotFound)
+    match found.len() {
+        0 => Err(UsbMatchError::NotFound(skipped)),
+        1 => Ok(found.remove(0)),
+        _ => match sn {
+            // same serial twice can't be told apart either, take the first
+            Some(_) => Ok(found.remove(0)),
+            None => Err(UsbMatchError::Ambiguous(found_locations)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_matches_with_or_without_the_dash() {
+        let usn = "00008030001A2B3C4D5E6F70";
+        assert!(serial_matches(usn, "00008030-001A2B3C4D5E6F70"));
+        assert!(serial_matches(usn, "00008030001a2b3c4d5e6f70"));
+        assert!(serial_matches("00008030001A2B3C4D5E6F70FFFF", "00008030-001A2B3C4D5E6F70"));
+        assert!(!serial_matches(usn, "00008030-001A2B3C4D5E6F71"));
+        assert!(!serial_matches(usn, "-"));
+    }
 }
@@ -0,0 +1,180 @@
+use crate::coremedia::sample::SampleBuffer;
+use crate::error::QTError;
+use crate::mirror::{init_qt, run_qt, MirrorConfig, SAMPLE_QUEUE};
+use crate::qt::SampleSender;
+use crate::session::{Phase, SessionWatch};
+use crate::transport::Transport;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot, watch};
+
+// feeds one stream from the session thread. it waits while the stream is full, until stop or
+// drop cancels, a stream nobody reads anymore must not keep the teardown from running
+struct StreamSender {
+    tx: mpsc::Sender<SampleBuffer>,
+    cancel: watch::Receiver<bool>,
+    runtime: Handle,
+}
+
+impl SampleSender for StreamSender {
+    fn send_sample(&self, sample: SampleBuffer) -> Result<(), String> {
+        let mut cancel = self.cancel.clone();
+
+        // a stream the consumer dropped just stops getting samples, the session goes on. so does
+        // a canceled one, the sample is dropped
+        self.runtime.block_on(async {
+            tokio::select! {
+                _ = self.tx.send(sample) => {}
+                _ = cancel.wait_for(|canceled| *canceled) => {}
+            }
+        });
+
+        Ok(())
+    }
+}
+
+// video or audio samples in device order, ends once the session is over
+pub struct SampleStream {
+    rx: mpsc::Receiver<SampleBuffer>,
+}
+
+impl SampleStream {
+    pub async fn recv(&mut self) -> Option<SampleBuffer> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for SampleStream {
+    type Item = SampleBuffer;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SampleBuffer>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+// Mirror for tokio. usb and the protocol run on a thread of their own, the streams are woken
+// when a sample arrives and stop waits for the device without blocking the runtime
+pub struct AsyncMirror {
+    term: Arc<AtomicBool>,
+    // wakes the session thread out of a send to a full stream
+    cancel: watch::Sender<bool>,
+    audio_connected: Arc<AtomicBool>,
+    watch: SessionWatch,
+    done: Option<oneshot::Receiver<Result<(), QTError>>>,
+}
+
+impl AsyncMirror {
+    // returns once the device is initialized, with the video and audio streams
+    pub async fn start<T: Transport + Send + 'static>(
+        device: T,
+        mut config: MirrorConfig,
+    ) -> Result<(AsyncMirror, SampleStream, SampleStream), QTError> {
+        let term = match config.term() {
+            Some(e) => e.clone(),
+            None => {
+                let term = Arc::new(AtomicBool::new(false));
+                config.set_term(term.clone());
+                term
+            }
+        };
+
+        let (video_tx, video_rx) = mpsc::channel(SAMPLE_QUEUE);
+        let (audio_tx, audio_rx) = mpsc::channel(SAMPLE_QUEUE);
+        let (cancel, cancel_rx) = watch::channel(false);
+        let (init_tx, init_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+
+        // the session thread waits on the streams through the runtime start was called on
+        let runtime = match Handle::try_current() {
+            Ok(e) => e,
+            Err(e) => return Err(QTError::Transport(e.to_string())),
+        };
+
+        let video_tx = StreamSender {
+            tx: video_tx,
+            cancel: cancel_rx.clone(),
+            runtime: runtime.clone(),
+        };
+        let audio_tx = StreamSender {
+            tx: audio_tx,
+            cancel: cancel_rx,
+            runtime,
+        };
+
+        // usb init blocks too, it runs on the session thread like everything after it
+        thread::spawn(move || {
+            let qt = match init_qt(device, config, Box::new(video_tx), Box::new(audio_tx)) {
+                Ok(e) => e,
+                Err(e) => {
+                    let _ = init_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            let _ = init_tx.send(Ok((qt.session_watch(), qt.audio_connected().clone())));
+            let _ = done_tx.send(run_qt(qt));
+        });
+
+        let (watch, audio_connected) = match init_rx.await {
+            Ok(Ok(e)) => e,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(QTError::Transport(String::from("qt thread ended during init"))),
+        };
+
+        let mirror = AsyncMirror {
+            term,
+            cancel,
+            audio_connected,
+            watch,
+            done: Some(done_rx),
+        };
+
+        Ok((mirror, SampleStream { rx: video_rx }, SampleStream { rx: audio_rx }))
+    }
+
+    pub fn term(&self) -> Arc<AtomicBool> {
+        self.term.clone()
+    }
+
+    pub fn audio_connected(&self) -> Arc<AtomicBool> {
+        self.audio_connected.clone()
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.watch.phase()
+    }
+
+    // waits for the session to end on its own, the error that ended it if any
+    pub async fn wait(&mut self) -> Result<(), QTError> {
+        let done = match self.done.take() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        match done.await {
+            Ok(e) => e,
+            Err(_) => Err(QTError::Transport(String::from("qt thread panicked"))),
+        }
+    }
+
+    // sends HPA0/HPD0 and waits for the device to let go. samples that don't fit into a stream
+    // nobody reads are dropped from here on
+    pub async fn stop(mut self) -> Result<(), QTError> {
+        self.term.store(true, Ordering::SeqCst);
+        self.cancel.send_replace(true);
+        self.wait().await
+    }
+}
+
+impl Drop for AsyncMirror {
+    // the session thread winds down by itself, full streams don't hold it up once canceled
+    fn drop(&mut self) {
+        self.term.store(true, Ordering::SeqCst);
+        self.cancel.send_replace(true);
+    }
+}
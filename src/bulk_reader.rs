@@ -0,0 +1,283 @@
+#![allow(clippy::single_match)]
+
+use libusb1_sys as ffi;
+use libusb1_sys::constants::{
+    LIBUSB_ERROR_IO, LIBUSB_ERROR_NO_DEVICE, LIBUSB_ERROR_PIPE, LIBUSB_TRANSFER_CANCELLED,
+    LIBUSB_TRANSFER_COMPLETED, LIBUSB_TRANSFER_ERROR, LIBUSB_TRANSFER_NO_DEVICE,
+    LIBUSB_TRANSFER_OVERFLOW, LIBUSB_TRANSFER_STALL, LIBUSB_TRANSFER_TIMED_OUT,
+    LIBUSB_TRANSFER_TYPE_BULK,
+};
+use crate::packet_buffer::PacketBytes;
+use rusb::{Context, DeviceHandle, Error, UsbContext};
+use std::ffi::c_void;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// transfers kept in flight, the device always has somewhere to put the next frame
+const TRANSFER_COUNT: usize = 4;
+// a full resolution key frame fits in one or two transfers
+pub const TRANSFER_SIZE: usize = 512 * 1024;
+// how often the event thread comes back to look at in_flight
+const EVENT_TIMEOUT_USEC: i64 = 100_000;
+
+// what the transfer callbacks need, they run on the event thread
+struct Shared {
+    // the completed transfer and how much it holds. a transfer completes at most once per
+    // submit, so TRANSFER_COUNT slots never fill up
+    tx: SyncSender<Result<(usize, usize), Error>>,
+    stopping: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+// the context pointer is only handed to the event thread, the context itself is kept alive by
+// BulkReader
+struct RawContext(*mut ffi::libusb_context);
+
+unsafe impl Send for RawContext {}
+
+// keeps TRANSFER_COUNT bulk in transfers on one endpoint and hands their buffers over in
+// completion order, which libusb guarantees is the order the device sent it. a buffer goes back
+// to the device once the last PacketBytes of it is dropped, so a slow reader holds at most
+// TRANSFER_COUNT buffers and the device waits instead of memory growing
+pub struct BulkReader {
+    // keeps the context alive for the event thread
+    context: Context,
+    shared: Arc<Shared>,
+    transfers: Vec<*mut ffi::libusb_transfer>,
+    buffers: Vec<Arc<Vec<u8>>>,
+    // completed transfers, resubmitted once their buffer is no longer shared
+    idle: Vec<usize>,
+    rx: Receiver<Result<(usize, usize), Error>>,
+    events: Option<thread::JoinHandle<()>>,
+}
+
+// the raw transfers are only touched by libusb, the event thread and drop
+unsafe impl Send for BulkReader {}
+
+fn transfer_status_error(status: i32) -> Error {
+    match status {
+        LIBUSB_TRANSFER_ERROR => Error::Io,
+        LIBUSB_TRANSFER_STALL => Error::Pipe,
+        LIBUSB_TRANSFER_NO_DEVICE => Error::NoDevice,
+        LIBUSB_TRANSFER_OVERFLOW => Error::Overflow,
+        _ => Error::Other,
+    }
+}
+
+fn submit_error(rc: i32) -> Error {
+    match rc {
+        LIBUSB_ERROR_IO => Error::Io,
+        LIBUSB_ERROR_NO_DEVICE => Error::NoDevice,
+        LIBUSB_ERROR_PIPE => Error::Pipe,
+        _ => Error::Other,
+    }
+}
+
+extern "system" fn transfer_done(transfer: *mut ffi::libusb_transfer) {
+    let (shared, status, length) = unsafe {
+        (
+            &*((*transfer).user_data as *const Shared),
+            (*transfer).status,
+            (*transfer).actual_length,
+        )
+    };
+
+    // the buffer stays with the reader until it is free again, nothing is resubmitted here
+    let done = match status {
+        LIBUSB_TRANSFER_COMPLETED => Ok((transfer as usize, length as usize)),
+        // no timeout is set, but a resubmit is all it would need
+        LIBUSB_TRANSFER_TIMED_OUT => Ok((transfer as usize, 0)),
+        LIBUSB_TRANSFER_CANCELLED => {
+            shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        _ => Err(transfer_status_error(status)),
+    };
+
+    // never blocks, the event thread must keep going
+    let _ = shared.tx.try_send(done);
+    shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+}
+
+impl BulkReader {
+    pub fn start(handle: &DeviceHandle<Context>, endpoint: u8) -> Result<BulkReader, Error> {
+        let (tx, rx) = mpsc::sync_channel(TRANSFER_COUNT);
+
+        let shared = Arc::new(Shared {
+            tx,
+            stopping: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+        });
+
+        let mut reader = BulkReader {
+            context: handle.context().clone(),
+            shared,
+            transfers: Vec::new(),
+            buffers: Vec::new(),
+            idle: Vec::new(),
+            rx,
+            events: None,
+        };
+
+        for _ in 0..TRANSFER_COUNT {
+            let transfer = unsafe { ffi::libusb_alloc_transfer(0) };
+            if transfer.is_null() {
+                return Err(Error::NoMem);
+            }
+
+            let mut buffer = Arc::new(vec![0u8; TRANSFER_SIZE]);
+            let buffer_ptr = Arc::get_mut(&mut buffer).expect("new buffer").as_mut_ptr();
+
+            unsafe {
+                (*transfer).dev_handle = handle.as_raw();
+                (*transfer).endpoint = endpoint;
+                (*transfer).transfer_type = LIBUSB_TRANSFER_TYPE_BULK;
+                (*transfer).timeout = 0;
+                (*transfer).buffer = buffer_ptr;
+                (*transfer).length = TRANSFER_SIZE as i32;
+                (*transfer).callback = transfer_done;
+                (*transfer).user_data = Arc::as_ptr(&reader.shared) as *mut c_void;
+            }
+
+            // the heap buffer does not move when the arc is moved into buffers
+            reader.buffers.push(buffer);
+            reader.transfers.push(transfer);
+        }
+
+        let mut error = None;
+
+        for transfer in reader.transfers.iter() {
+            // counted before the submit, its callback may run as soon as events are handled
+            reader.shared.in_flight.fetch_add(1, Ordering::SeqCst);
+
+            let rc = unsafe { ffi::libusb_submit_transfer(*transfer) };
+            if rc < 0 {
+                reader.shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+                error = Some(submit_error(rc));
+                break;
+            }
+        }
+
+        reader.events = Some(reader.spawn_events());
+
+        // drop cancels and waits for whatever was submitted already
+        match error {
+            Some(e) => Err(e),
+            None => Ok(reader),
+        }
+    }
+
+    // runs until drop has the last transfer back, completions only happen while someone handles
+    // events
+    fn spawn_events(&self) -> thread::JoinHandle<()> {
+        let shared = self.shared.clone();
+        let context = RawContext(self.context.as_raw());
+
+        thread::spawn(move || {
+            // the whole wrapper, not just the pointer field, has to move into the closure
+            let context = context;
+            let tv = libc::timeval {
+                tv_sec: 0,
+                tv_usec: EVENT_TIMEOUT_USEC,
+            };
+
+            // all transfers can be idle with the reader for a while, that is not the end
+            while !shared.stopping.load(Ordering::SeqCst) || shared.in_flight.load(Ordering::SeqCst) > 0 {
+                unsafe {
+                    ffi::libusb_handle_events_timeout_completed(context.0, &tv, ptr::null_mut());
+                }
+            }
+        })
+    }
+
+    // the next completed transfer as it is, None when nothing arrived within timeout
+    pub fn read(&mut self, timeout: Duration) -> Result<Option<PacketBytes>, Error> {
+        match self.resubmit() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let (transfer, length) = match self.rx.recv_timeout(timeout) {
+            Ok(Ok(e)) => e,
+            Ok(Err(e)) => return Err(e),
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            // the sender lives in shared as long as we do
+            Err(RecvTimeoutError::Disconnected) => return Err(Error::Other),
+        };
+
+        let index = match self.transfers.iter().position(|t| *t as usize == transfer) {
+            Some(e) => e,
+            None => return Err(Error::Other),
+        };
+        self.idle.push(index);
+
+        if length == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(PacketBytes::from_shared(self.buffers[index].clone(), length)))
+    }
+
+    // hands the buffers nobody reads from anymore back to the device
+    fn resubmit(&mut self) -> Result<(), Error> {
+        let mut i = 0;
+        while i < self.idle.len() {
+            let index = self.idle[i];
+            let buffer = match Arc::get_mut(&mut self.buffers[index]) {
+                Some(e) => e,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let transfer = self.transfers[index];
+            unsafe {
+                (*transfer).buffer = buffer.as_mut_ptr();
+            }
+
+            self.shared.in_flight.fetch_add(1, Ordering::SeqCst);
+
+            let rc = unsafe { ffi::libusb_submit_transfer(transfer) };
+            if rc < 0 {
+                self.shared.in_flight.fetch_sub(1, Ordering::SeqCst);
+                return Err(submit_error(rc));
+            }
+
+            self.idle.swap_remove(i);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for BulkReader {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+
+        // cancel until all are back, idle ones just fail the cancel
+        while self.shared.in_flight.load(Ordering::SeqCst) > 0 {
+            for transfer in self.transfers.iter() {
+                unsafe {
+                    ffi::libusb_cancel_transfer(*transfer);
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        if let Some(events) = self.events.take() {
+            let _ = events.join();
+        }
+
+        for transfer in self.transfers.drain(..) {
+            unsafe {
+                ffi::libusb_free_transfer(transfer);
+            }
+        }
+    }
+}
@@ -0,0 +1,466 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use crate::error::QTError;
+use crate::packet_buffer::PacketBytes;
+use crate::transport::Transport;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::sync::atomic::AtomicBool;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const CAPTURE_MAGIC: u32 = 0x51544350; // QTCP
+const CAPTURE_VERSION: u32 = 1;
+
+pub const DIRECTION_READ: u8 = 0x52; // R - device to host
+pub const DIRECTION_WRITE: u8 = 0x57; // W - host to device
+
+pub struct CaptureRecord {
+    direction: u8,
+    timestamp: u64,
+    data: Vec<u8>,
+}
+
+impl CaptureRecord {
+    pub fn direction(&self) -> u8 {
+        self.direction
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+}
+
+pub struct CaptureWriter {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl CaptureWriter {
+    pub fn create(path: &str) -> Result<CaptureWriter, Error> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(e),
+        };
+
+        let mut writer = BufWriter::new(file);
+
+        match writer.write_u32::<LittleEndian>(CAPTURE_MAGIC) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match writer.write_u32::<LittleEndian>(CAPTURE_VERSION) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        Ok(CaptureWriter {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn write_record(&mut self, direction: u8, data: &[u8]) -> Result<(), Error> {
+        let timestamp = self.start.elapsed().as_micros() as u64;
+
+        match self.writer.write_u8(direction) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_u64::<LittleEndian>(timestamp) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_u32::<LittleEndian>(data.len() as u32) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        // the BufWriter collects records, a flush per usb packet would cost a syscall each
+        self.writer.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+// reads a capture one record at a time, so long captures don't have to fit in memory
+pub struct CaptureReader {
+    reader: BufReader<File>,
+}
+
+impl CaptureReader {
+    pub fn open(path: &str) -> Result<CaptureReader, Error> {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(e),
+        };
+
+        let mut reader = BufReader::new(file);
+
+        let magic = match reader.read_u32::<LittleEndian>() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if magic != CAPTURE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "capture magic not compare"));
+        }
+
+        let version = match reader.read_u32::<LittleEndian>() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if version != CAPTURE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported capture version {}", version),
+            ));
+        }
+
+        Ok(CaptureReader { reader })
+    }
+
+    // None at the end of the file. a capture cut off by a crash or unplug ends in half a
+    // record, that one is dropped and the rest still replays
+    pub fn next_record(&mut self) -> Result<Option<CaptureRecord>, Error> {
+        let direction = match self.reader.read_u8() {
+            Ok(e) => e,
+            Err(e) => match e.kind() {
+                ErrorKind::UnexpectedEof => return Ok(None),
+                _ => return Err(e),
+            },
+        };
+
+        if direction != DIRECTION_READ && direction != DIRECTION_WRITE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("capture invalid direction {:#x}", direction),
+            ));
+        }
+
+        let timestamp = match self.reader.read_u64::<LittleEndian>() {
+            Ok(e) => e,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(truncated()),
+            Err(e) => return Err(e),
+        };
+
+        let data_len = match self.reader.read_u32::<LittleEndian>() {
+            Ok(e) => e,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(truncated()),
+            Err(e) => return Err(e),
+        };
+
+        // read through take, a length cut off mid write is not trusted with an allocation
+        let mut data: Vec<u8> = Vec::new();
+        match (&mut self.reader).take(data_len as u64).read_to_end(&mut data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        if data.len() < data_len as usize {
+            return Ok(truncated());
+        }
+
+        Ok(Some(CaptureRecord {
+            direction,
+            timestamp,
+            data,
+        }))
+    }
+
+    // the next device to host record, host writes are not replayed
+    pub fn next_read(&mut self) -> Result<Option<CaptureRecord>, Error> {
+        loop {
+            match self.next_record() {
+                Ok(Some(r)) if r.direction != DIRECTION_READ => continue,
+                other => return other,
+            };
+        }
+    }
+}
+
+fn truncated() -> Option<CaptureRecord> {
+    warn!("capture ends in a truncated record, replaying up to it");
+    None
+}
+
+pub struct CaptureTransport<T: Transport> {
+    inner: T,
+    writer: CaptureWriter,
+}
+
+impl<T: Transport> CaptureTransport<T> {
+    pub fn new(inner: T, path: &str) -> Result<CaptureTransport<T>, Error> {
+        let writer = match CaptureWriter::create(path) {
+            Ok(w) => w,
+            Err(e) => return Err(e),
+        };
+
+        Ok(CaptureTransport { inner, writer })
+    }
+}
+
+impl<T: Transport> Transport for CaptureTransport<T> {
+    fn init(&mut self) -> Result<(), QTError> {
+        self.inner.init()
+    }
+
+    fn max_read_packet_size(&self) -> usize {
+        self.inner.max_read_packet_size()
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, QTError> {
+        let size = match self.inner.read_frame(buf) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if size > 0 {
+            match self.writer.write_record(DIRECTION_READ, &buf[..size]) {
+                Err(e) => return Err(QTError::Io(e)),
+                _ => {}
+            };
+        }
+
+        Ok(size)
+    }
+
+    fn read_bytes(&mut self) -> Result<PacketBytes, QTError> {
+        let data = match self.inner.read_bytes() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if !data.is_empty() {
+            match self.writer.write_record(DIRECTION_READ, data.as_ref()) {
+                Err(e) => return Err(QTError::Io(e)),
+                _ => {}
+            };
+        }
+
+        Ok(data)
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, QTError> {
+        match self.writer.write_record(DIRECTION_WRITE, buf) {
+            Err(e) => return Err(QTError::Io(e)),
+            _ => {}
+        };
+
+        self.inner.write_frame(buf)
+    }
+
+    fn reconnect(&mut self, term: &AtomicBool) -> Result<bool, QTError> {
+        self.inner.reconnect(term)
+    }
+
+    fn teardown(&mut self) -> Result<(), QTError> {
+        match self.writer.flush() {
+            Err(e) => return Err(QTError::Io(e)),
+            _ => {}
+        };
+
+        self.inner.teardown()
+    }
+}
+
+pub struct ReplayTransport {
+    reader: CaptureReader,
+    max_read_size: usize,
+    first_timestamp: u64,
+    realtime: bool,
+    start: Option<Instant>,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &str, realtime: bool) -> Result<ReplayTransport, Error> {
+        // a first pass for the read buffer size, the records are streamed again on replay
+        let mut scan = match CaptureReader::open(path) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let mut max_read_size = 0;
+        let mut first_timestamp = None;
+        loop {
+            let record = match scan.next_read() {
+                Ok(Some(e)) => e,
+                Ok(None) => break,
+                Err(e) => return Err(e),
+            };
+
+            first_timestamp.get_or_insert(record.timestamp);
+            max_read_size = max_read_size.max(record.data.len());
+        }
+
+        let first_timestamp = match first_timestamp {
+            Some(e) if max_read_size > 0 => e,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "capture has no device packets")),
+        };
+
+        let reader = match CaptureReader::open(path) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(ReplayTransport {
+            reader,
+            max_read_size,
+            first_timestamp,
+            realtime,
+            start: None,
+        })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn init(&mut self) -> Result<(), QTError> {
+        Ok(())
+    }
+
+    fn max_read_packet_size(&self) -> usize {
+        self.max_read_size
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, QTError> {
+        let data = match self.read_bytes() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if buf.len() < data.len() {
+            return Err(QTError::Transport(String::from(
+                "replay buffer smaller than record",
+            )));
+        }
+
+        buf[..data.len()].copy_from_slice(data.as_ref());
+
+        Ok(data.len())
+    }
+
+    // a record is one device read, it goes up as read from the file
+    fn read_bytes(&mut self) -> Result<PacketBytes, QTError> {
+        let record = match self.reader.next_read() {
+            Ok(Some(r)) => r,
+            Ok(None) => return Err(QTError::EndOfStream),
+            Err(e) => return Err(QTError::Io(e)),
+        };
+
+        if self.realtime {
+            let start = *self.start.get_or_insert_with(Instant::now);
+            let due = Duration::from_micros(record.timestamp.saturating_sub(self.first_timestamp));
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                sleep(due - elapsed);
+            }
+        }
+
+        Ok(PacketBytes::from_vec(record.data))
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, QTError> {
+        Ok(buf.len())
+    }
+
+    fn teardown(&mut self) -> Result<(), QTError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("scrmiror-{}-{}.qtcap", name, std::process::id()));
+        String::from(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn records_stream_back_in_order() {
+        let path = temp_path("records");
+        let mut writer = CaptureWriter::create(path.as_str()).unwrap();
+        writer.write_record(DIRECTION_READ, &[1, 2, 3]).unwrap();
+        writer.write_record(DIRECTION_WRITE, &[4]).unwrap();
+        writer.write_record(DIRECTION_READ, &[5, 6]).unwrap();
+        writer.flush().unwrap();
+
+        let mut reader = CaptureReader::open(path.as_str()).unwrap();
+        assert_eq!(reader.next_read().unwrap().unwrap().data(), &[1, 2, 3]);
+        assert_eq!(reader.next_read().unwrap().unwrap().data(), &[5, 6]);
+        assert!(reader.next_read().unwrap().is_none());
+
+        let mut replay = ReplayTransport::open(path.as_str(), false).unwrap();
+        assert_eq!(replay.max_read_packet_size(), 3);
+
+        let mut buf = vec![0; 3];
+        assert_eq!(replay.read_frame(&mut buf).unwrap(), 3);
+        assert_eq!(replay.read_frame(&mut buf).unwrap(), 2);
+        assert!(matches!(replay.read_frame(&mut buf), Err(QTError::EndOfStream)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_record_ends_the_capture() {
+        let path = temp_path("truncated");
+        let mut writer = CaptureWriter::create(path.as_str()).unwrap();
+        writer.write_record(DIRECTION_READ, &[1, 2, 3]).unwrap();
+        writer.write_record(DIRECTION_READ, &[4, 5, 6, 7]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        // cut the last record in the middle of its data, like a capture killed mid write
+        let len = std::fs::metadata(path.as_str()).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path.as_str())
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        let mut replay = ReplayTransport::open(path.as_str(), false).unwrap();
+        let mut buf = vec![0; replay.max_read_packet_size()];
+        assert_eq!(replay.read_frame(&mut buf).unwrap(), 3);
+        assert!(matches!(replay.read_frame(&mut buf), Err(QTError::EndOfStream)));
+
+        // cut inside the next record header
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path.as_str())
+            .unwrap()
+            .set_len(8 + 13 + 3 + 5)
+            .unwrap();
+
+        let mut reader = CaptureReader::open(path.as_str()).unwrap();
+        assert_eq!(reader.next_read().unwrap().unwrap().data(), &[1, 2, 3]);
+        assert!(reader.next_read().unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn empty_capture_is_rejected() {
+        let path = temp_path("empty");
+        let mut writer = CaptureWriter::create(path.as_str()).unwrap();
+        writer.write_record(DIRECTION_WRITE, &[1]).unwrap();
+        writer.flush().unwrap();
+
+        assert!(ReplayTransport::open(path.as_str(), false).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
 use crate::qt_pkt::QTPacket;
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::io::Error;
 
+#[derive(Clone)]
 pub struct AudioStreamDescription {
     sample_rate: f64,
     format_id: u32,
@@ -16,30 +19,29 @@ pub struct AudioStreamDescription {
 
 pub const AUDIO_FORMAT_ID_LPCM: u32 = 0x6C70636D;
 
-impl AudioStreamDescription {
-    pub fn new(
-        sample_rate: f64,
-        format_id: u32,
-        format_flags: u32,
-        bytes_per_packet: u32,
-        frames_per_packet: u32,
-        bytes_per_frame: u32,
-        channels_per_frame: u32,
-        bits_per_channel: u32,
-    ) -> AudioStreamDescription {
+// CoreAudio AudioFormatFlags
+pub const AUDIO_FORMAT_FLAG_IS_FLOAT: u32 = 0x1;
+pub const AUDIO_FORMAT_FLAG_IS_BIG_ENDIAN: u32 = 0x2;
+pub const AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER: u32 = 0x4;
+pub const AUDIO_FORMAT_FLAG_IS_PACKED: u32 = 0x8;
+
+impl Default for AudioStreamDescription {
+    fn default() -> Self {
         AudioStreamDescription {
-            sample_rate,
-            format_flags,
-            format_id,
-            bytes_per_packet,
-            frames_per_packet,
-            bytes_per_frame,
-            channels_per_frame,
-            bits_per_channel,
+            sample_rate: 48000f64,
+            format_flags: 12,
+            format_id: AUDIO_FORMAT_ID_LPCM,
+            bytes_per_packet: 4,
+            frames_per_packet: 1,
+            bytes_per_frame: 4,
+            channels_per_frame: 2,
+            bits_per_channel: 16,
             reserved: 0,
         }
     }
+}
 
+impl AudioStreamDescription {
     pub fn sample_rate(&self) -> f64 {
         self.sample_rate
     }
@@ -131,20 +133,85 @@ impl AudioStreamDescription {
         })
     }
 
-    pub fn default() -> AudioStreamDescription {
+    // packed little endian lpcm, one frame per packet like the device sends it
+    pub fn lpcm(sample_rate: f64, channels: u32, bits: u32, float: bool) -> AudioStreamDescription {
+        let format_flags = match float {
+            true => AUDIO_FORMAT_FLAG_IS_FLOAT | AUDIO_FORMAT_FLAG_IS_PACKED,
+            false => AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER | AUDIO_FORMAT_FLAG_IS_PACKED,
+        };
+        let bytes_per_frame = channels * bits / 8;
+
         AudioStreamDescription {
-            sample_rate: 48000f64,
-            format_flags: 12,
+            sample_rate,
             format_id: AUDIO_FORMAT_ID_LPCM,
-            bytes_per_packet: 4,
+            format_flags,
+            bytes_per_packet: bytes_per_frame,
             frames_per_packet: 1,
-            bytes_per_frame: 4,
-            channels_per_frame: 2,
-            bits_per_channel: 16,
+            bytes_per_frame,
+            channels_per_frame: channels,
+            bits_per_channel: bits,
             reserved: 0,
         }
     }
 
+    pub fn is_float(&self) -> bool {
+        self.format_flags & AUDIO_FORMAT_FLAG_IS_FLOAT != 0
+    }
+
+    pub fn is_big_endian(&self) -> bool {
+        self.format_flags & AUDIO_FORMAT_FLAG_IS_BIG_ENDIAN != 0
+    }
+
+    // what is wrong with the description, None for usable lpcm
+    pub fn lpcm_error(&self) -> Option<String> {
+        if self.format_id != AUDIO_FORMAT_ID_LPCM {
+            return Some(format!("format {:#x} is not lpcm", self.format_id));
+        }
+
+        if self.sample_rate <= 0f64 || self.channels_per_frame == 0 {
+            return Some(format!("{} Hz {} channels", self.sample_rate, self.channels_per_frame));
+        }
+
+        if ![8, 16, 24, 32].contains(&self.bits_per_channel) {
+            return Some(format!("{} bits per channel", self.bits_per_channel));
+        }
+
+        if self.bytes_per_frame != self.channels_per_frame * self.bits_per_channel / 8 {
+            return Some(format!(
+                "{} bytes per frame for {} channels of {} bits",
+                self.bytes_per_frame, self.channels_per_frame, self.bits_per_channel
+            ));
+        }
+
+        None
+    }
+
+    // same sample layout, flags the decoder does not care about are ignored
+    pub fn same_format(&self, other: &AudioStreamDescription) -> bool {
+        self.format_id == other.format_id
+            && self.sample_rate == other.sample_rate
+            && self.channels_per_frame == other.channels_per_frame
+            && self.bits_per_channel == other.bits_per_channel
+            && self.is_float() == other.is_float()
+            && self.is_big_endian() == other.is_big_endian()
+    }
+
+    // ffmpeg raw audio demuxer name for the sample layout
+    pub fn ffmpeg_format(&self) -> Option<&'static str> {
+        if self.format_id != AUDIO_FORMAT_ID_LPCM {
+            return None;
+        }
+
+        match (self.is_float(), self.bits_per_channel, self.is_big_endian()) {
+            (false, 16, false) => Some("s16le"),
+            (false, 16, true) => Some("s16be"),
+            (false, 24, false) => Some("s24le"),
+            (false, 32, false) => Some("s32le"),
+            (true, 32, false) => Some("f32le"),
+            _ => None,
+        }
+    }
+
     pub fn as_buffer(&self) -> Result<Vec<u8>, Error> {
         let mut buffer: Vec<u8> = Vec::new();
 
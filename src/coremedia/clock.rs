@@ -1,23 +1,18 @@
+#![allow(clippy::needless_return)]
+
 use crate::coremedia::time::Time;
-use std::time::SystemTime;
+use std::time::Instant;
 
 const NANO_SECOND_SCALE: u32 = 1_000_000_000;
 
 const KCM_TIME_FLAGS_VALID: u32 = 0x0;
 const KCM_TIME_FLAGS_HAS_BEEN_ROUNDED: u32 = 0x1;
-const KCM_TIME_FLAGS_POSITIVE_INFINITY: u32 = 0x2;
-const KCM_TIME_FLAGS_NEGATIVE_INFINITY: u32 = 0x4;
-const KCM_TIME_FLAGS_INDEFINITE: u32 = 0x8;
-const KCM_TIME_FLAGS_IMPLIED_VALUE_FLAGS_MASK: u32 =
-    KCM_TIME_FLAGS_POSITIVE_INFINITY | KCM_TIME_FLAGS_NEGATIVE_INFINITY | KCM_TIME_FLAGS_INDEFINITE;
-
-const TIME_LENGTH_IN_BYTES: i32 = 24;
 
 pub struct Clock {
     id: u64,
     time_scale: u32,
     factor: f64,
-    t: SystemTime,
+    t: Instant,
 }
 
 impl Clone for Clock {
@@ -37,7 +32,7 @@ impl Clock {
             id,
             time_scale: NANO_SECOND_SCALE,
             factor: 1f64,
-            t: SystemTime::now(),
+            t: Instant::now(),
         }
     }
 
@@ -46,7 +41,7 @@ impl Clock {
             id,
             time_scale: ts,
             factor: ts as f64 / NANO_SECOND_SCALE as f64,
-            t: SystemTime::now(),
+            t: Instant::now(),
         }
     }
 
@@ -61,9 +56,7 @@ impl Clock {
     }
 
     pub fn get_time(&self) -> Time {
-        let since = SystemTime::now()
-            .duration_since(self.t)
-            .expect("get time duration since");
+        let since = self.t.elapsed();
 
         Time::new(
             self.calc_value(since.as_nanos() as u64),
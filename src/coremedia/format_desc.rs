@@ -1,10 +1,14 @@
+#![allow(clippy::needless_return, clippy::question_mark, clippy::single_match)]
+
 use crate::coremedia::audio_desc::AudioStreamDescription;
 use crate::coremedia::sample::{
-    MAGIC_AUDIO_STREAM_DESCRIPTION, MAGIC_CODEC, MAGIC_EXTENSION, MAGIC_MEDIA_TYPE,
-    MAGIC_VIDEO_DIMENSION, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO,
+    CODEC_AVC1, CODEC_HEV1, CODEC_HVC1, MAGIC_AUDIO_STREAM_DESCRIPTION, MAGIC_CODEC, MAGIC_EXTENSION,
+    MAGIC_FORMAT_DESCRIPTOR, MAGIC_MEDIA_TYPE, MAGIC_VIDEO_DIMENSION, MEDIA_TYPE_SOUND,
+    MEDIA_TYPE_VIDEO,
 };
+use crate::error::QTError;
 use crate::qt_pkt::QTPacket;
-use crate::qt_value::QTValue;
+use crate::qt_value::{QTKeyValuePair, QTValue};
 use byteorder::{BigEndian, ReadBytesExt};
 use std::fmt::{Debug, Formatter};
 use std::io;
@@ -21,12 +25,54 @@ pub struct AVC1 {
 }
 
 impl AVC1 {
-    pub fn sps(&self) -> &[u8] {
-        self.sps.as_ref().expect("sps None").as_slice()
+    pub fn new(avc_profile: u8, avc_compatibility: u8, avc_level: u8, sps: &[u8], pps: &[u8]) -> AVC1 {
+        AVC1 {
+            version: 1,
+            avc_profile,
+            avc_compatibility,
+            avc_level,
+            nalu_len: 4,
+            sps: Some(Vec::from(sps)),
+            pps: Some(Vec::from(pps)),
+        }
     }
 
-    pub fn pps(&self) -> &[u8] {
-        self.pps.as_ref().expect("pps None").as_slice()
+    pub fn sps(&self) -> Option<&[u8]> {
+        self.sps.as_deref()
+    }
+
+    pub fn pps(&self) -> Option<&[u8]> {
+        self.pps.as_deref()
+    }
+
+    pub fn as_vec(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec![
+            self.version,
+            self.avc_profile,
+            self.avc_compatibility,
+            self.avc_level,
+            0xFC | ((self.nalu_len - 1) & 0x3),
+        ];
+
+        match &self.sps {
+            Some(sps) => {
+                buffer.push(0xE1);
+                buffer.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+                buffer.extend_from_slice(sps);
+            }
+            None => buffer.push(0xE0),
+        };
+
+        match &self.pps {
+            Some(pps) => {
+                buffer.push(1);
+                buffer.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+                buffer.extend_from_slice(pps);
+            }
+            None => buffer.push(0),
+        };
+
+        buffer
     }
 
     fn from_vec(data: &Vec<u8>) -> Result<AVC1, Error> {
@@ -107,17 +153,232 @@ impl AVC1 {
     }
 }
 
+// hvcC, the record is kept as the device sent it so mp4 can write it back unchanged
+pub struct HVC1 {
+    config: Vec<u8>,
+    nalu_len: u8,
+    vps: Vec<Vec<u8>>,
+    sps: Vec<Vec<u8>>,
+    pps: Vec<Vec<u8>>,
+}
+
+const HEVC_NALU_TYPE_VPS: u8 = 32;
+const HEVC_NALU_TYPE_SPS: u8 = 33;
+const HEVC_NALU_TYPE_PPS: u8 = 34;
+const HVCC_HEADER_LEN: usize = 22;
+
+impl HVC1 {
+    // profile fields are left empty, decoders take them from the sps
+    pub fn new(vps: &[u8], sps: &[u8], pps: &[u8]) -> HVC1 {
+        let mut config: Vec<u8> = vec![0; HVCC_HEADER_LEN];
+        config[0] = 1;
+        config[13] = 0xF0; // min_spatial_segmentation_idc
+        config[15] = 0xFC; // parallelism
+        config[16] = 0xFD; // chroma 4:2:0
+        config[17] = 0xF8; // 8 bit luma
+        config[18] = 0xF8; // 8 bit chroma
+        config[21] = 0x03 | (1 << 3); // one temporal layer, 4 byte nalu length
+
+        config.push(3);
+        for (nalu_type, nalu) in [(HEVC_NALU_TYPE_VPS, vps), (HEVC_NALU_TYPE_SPS, sps), (HEVC_NALU_TYPE_PPS, pps)] {
+            config.push(0x80 | nalu_type);
+            config.extend_from_slice(&1u16.to_be_bytes());
+            config.extend_from_slice(&(nalu.len() as u16).to_be_bytes());
+            config.extend_from_slice(nalu);
+        }
+
+        HVC1 {
+            config,
+            nalu_len: 4,
+            vps: Vec::from([Vec::from(vps)]),
+            sps: Vec::from([Vec::from(sps)]),
+            pps: Vec::from([Vec::from(pps)]),
+        }
+    }
+
+    pub fn vps(&self) -> &Vec<Vec<u8>> {
+        &self.vps
+    }
+
+    pub fn sps(&self) -> &Vec<Vec<u8>> {
+        &self.sps
+    }
+
+    pub fn pps(&self) -> &Vec<Vec<u8>> {
+        &self.pps
+    }
+
+    pub fn as_vec(&self) -> Vec<u8> {
+        self.config.clone()
+    }
+
+    fn from_vec(data: &[u8]) -> Result<HVC1, Error> {
+        if data.len() < HVCC_HEADER_LEN + 1 || data[0] != 1 {
+            return Err(Error::new(ErrorKind::InvalidData, "not a hvcC record"));
+        }
+
+        let nalu_len = (data[21] & 0x3) + 1;
+
+        let mut cur = Cursor::new(&data[HVCC_HEADER_LEN..]);
+        let num_arrays = match cur.read_u8() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let mut vps: Vec<Vec<u8>> = Vec::new();
+        let mut sps: Vec<Vec<u8>> = Vec::new();
+        let mut pps: Vec<Vec<u8>> = Vec::new();
+
+        for _ in 0..num_arrays {
+            let nalu_type = match cur.read_u8() {
+                Ok(e) => e & 0x3F,
+                Err(e) => return Err(e),
+            };
+
+            let num_nalus = match cur.read_u16::<BigEndian>() {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            for _ in 0..num_nalus {
+                let nalu_len = match cur.read_u16::<BigEndian>() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                let mut nalu: Vec<u8> = vec![0; nalu_len as usize];
+                match cur.read_exact(&mut nalu) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+
+                // SEI arrays are allowed in hvcC, they are not needed to start decoding
+                match nalu_type {
+                    HEVC_NALU_TYPE_VPS => vps.push(nalu),
+                    HEVC_NALU_TYPE_SPS => sps.push(nalu),
+                    HEVC_NALU_TYPE_PPS => pps.push(nalu),
+                    _ => {}
+                };
+            }
+        }
+
+        Ok(HVC1 {
+            config: Vec::from(data),
+            nalu_len,
+            vps,
+            sps,
+            pps,
+        })
+    }
+}
+
+pub enum VideoCodec {
+    Avc1(AVC1),
+    Hvc1(HVC1),
+}
+
+impl VideoCodec {
+    pub fn is_hevc(&self) -> bool {
+        matches!(self, VideoCodec::Hvc1(_))
+    }
+
+    // everything a decoder needs before the first frame, in order
+    pub fn parameter_sets(&self) -> Vec<&[u8]> {
+        match self {
+            VideoCodec::Avc1(avc1) => [avc1.sps(), avc1.pps()].into_iter().flatten().collect(),
+            VideoCodec::Hvc1(hvc1) => hvc1
+                .vps
+                .iter()
+                .chain(hvc1.sps.iter())
+                .chain(hvc1.pps.iter())
+                .map(|e| e.as_slice())
+                .collect(),
+        }
+    }
+
+    pub fn nalu_len(&self) -> u8 {
+        match self {
+            VideoCodec::Avc1(avc1) => avc1.nalu_len,
+            VideoCodec::Hvc1(hvc1) => hvc1.nalu_len,
+        }
+    }
+}
+
 pub struct FormatDescriptor {
     media_type: u32,
     video_dimension_width: u32,
     video_dimension_height: u32,
     codec: u32,
     extensions: Option<Vec<QTValue>>,
-    avc1: Option<AVC1>,
+    video_codec: Option<VideoCodec>,
     audio_stream_basic_description: Option<AudioStreamDescription>,
 }
 
 impl FormatDescriptor {
+    pub fn new_audio(asd: AudioStreamDescription) -> FormatDescriptor {
+        FormatDescriptor {
+            media_type: MEDIA_TYPE_SOUND,
+            video_dimension_width: 0,
+            video_dimension_height: 0,
+            codec: 0,
+            extensions: None,
+            video_codec: None,
+            audio_stream_basic_description: Some(asd),
+        }
+    }
+
+    pub fn new_avc1(width: u32, height: u32, avc1: AVC1) -> FormatDescriptor {
+        let avcc: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(105),
+            QTValue::Data(avc1.as_vec()),
+        ))];
+
+        let extensions: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(49),
+            QTValue::Object(avcc),
+        ))];
+
+        FormatDescriptor {
+            media_type: MEDIA_TYPE_VIDEO,
+            video_dimension_width: width,
+            video_dimension_height: height,
+            codec: CODEC_AVC1,
+            extensions: Some(extensions),
+            video_codec: Some(VideoCodec::Avc1(avc1)),
+            audio_stream_basic_description: None,
+        }
+    }
+
+    pub fn new_hvc1(width: u32, height: u32, hvc1: HVC1) -> FormatDescriptor {
+        let hvcc: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(105),
+            QTValue::Data(hvc1.as_vec()),
+        ))];
+
+        let extensions: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(49),
+            QTValue::Object(hvcc),
+        ))];
+
+        FormatDescriptor {
+            media_type: MEDIA_TYPE_VIDEO,
+            video_dimension_width: width,
+            video_dimension_height: height,
+            codec: CODEC_HVC1,
+            extensions: Some(extensions),
+            video_codec: Some(VideoCodec::Hvc1(hvc1)),
+            audio_stream_basic_description: None,
+        }
+    }
+
+    pub fn media_type(&self) -> u32 {
+        self.media_type
+    }
+
+    pub fn codec(&self) -> u32 {
+        self.codec
+    }
+
     pub fn video_dimension_width(&self) -> u32 {
         self.video_dimension_width
     }
@@ -126,17 +387,29 @@ impl FormatDescriptor {
         self.video_dimension_height
     }
 
-    pub fn audio_stream_description(&self) -> &AudioStreamDescription {
-        self.audio_stream_basic_description
-            .as_ref()
-            .expect("audio stream description")
+    pub fn audio_stream_description(&self) -> Option<&AudioStreamDescription> {
+        self.audio_stream_basic_description.as_ref()
+    }
+
+    pub fn video_codec(&self) -> Option<&VideoCodec> {
+        self.video_codec.as_ref()
+    }
+
+    pub fn avc1(&self) -> Option<&AVC1> {
+        match &self.video_codec {
+            Some(VideoCodec::Avc1(e)) => Some(e),
+            _ => None,
+        }
     }
 
-    pub fn avc1(&self) -> &AVC1 {
-        self.avc1.as_ref().expect("avc1")
+    pub fn hvc1(&self) -> Option<&HVC1> {
+        match &self.video_codec {
+            Some(VideoCodec::Hvc1(e)) => Some(e),
+            _ => None,
+        }
     }
 
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<FormatDescriptor, Error> {
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<FormatDescriptor, QTError> {
         let (mut mdia_pkt, _) = match QTPacket::from_qt_packet_with_magic(pkt, MAGIC_MEDIA_TYPE) {
             Ok(e) => e,
             Err(e) => return Err(e),
@@ -144,7 +417,7 @@ impl FormatDescriptor {
 
         let media_type = match mdia_pkt.read_u32() {
             Ok(e) => e,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
         match media_type {
@@ -159,7 +432,7 @@ impl FormatDescriptor {
 
                 let asd = match AudioStreamDescription::from_qt_packet(&mut asdb) {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
                 Ok(FormatDescriptor {
@@ -168,7 +441,7 @@ impl FormatDescriptor {
                     video_dimension_height: 0,
                     codec: 0,
                     extensions: None,
-                    avc1: None,
+                    video_codec: None,
                     audio_stream_basic_description: Some(asd),
                 })
             }
@@ -181,12 +454,12 @@ impl FormatDescriptor {
 
                 let video_width = match video_dimension.read_u32() {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
                 let video_height = match video_dimension.read_u32() {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
                 let (mut codec_pkt, _) = match QTPacket::from_qt_packet_with_magic(pkt, MAGIC_CODEC)
@@ -197,7 +470,7 @@ impl FormatDescriptor {
 
                 let codec = match codec_pkt.read_u32() {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
                 let (mut extension_pkt, _) =
@@ -208,43 +481,36 @@ impl FormatDescriptor {
 
                 let mut extensions: Vec<QTValue> = Vec::new();
 
-                let mut avc1: Option<AVC1> = None;
+                let mut video_codec: Option<VideoCodec> = None;
+
+                let extension_len = match extension_pkt.len() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
 
-                loop {
+                while extension_pkt.pos() < extension_len {
                     let extension = match QTValue::from_qt_packet(&mut extension_pkt) {
                         Ok(e) => e,
-                        Err(e) => match e.kind() {
-                            ErrorKind::UnexpectedEof => break,
-                            _ => return Err(e),
-                        },
+                        Err(e) => return Err(e),
                     };
 
                     match extension.as_pair() {
                         Some(kv) => match kv.key().as_idx() {
-                            Some(idx) => match idx {
-                                49 => {
-                                    let obj = kv.value().as_vec().expect("idx 49 is not object");
-                                    if obj.len() > 0 {
-                                        let obj_kv =
-                                            obj[0].as_pair().expect("obj[0] is not kv pair");
-                                        let obj_k =
-                                            obj_kv.key().as_idx().expect("obj[0].key is not idx");
-                                        if obj_k == 105 {
-                                            // AVCC format in iOS 15.6
-                                            let obj_data = obj_kv
-                                                .value()
-                                                .as_data()
-                                                .expect("obj[0].value is not data");
-
-                                            avc1 = Some(match AVC1::from_vec(obj_data) {
-                                                Ok(e) => e,
-                                                Err(e) => return Err(e),
-                                            });
-                                        }
+                            Some(49) => {
+                                let obj = match kv.value().as_vec() {
+                                    Some(e) => e,
+                                    None => {
+                                        return Err(QTError::MalformedValue(String::from(
+                                            "idx 49 is not object",
+                                        )))
                                     }
-                                }
-                                _ => {}
-                            },
+                                };
+                                match parse_codec_atoms(codec, obj) {
+                                    Ok(Some(e)) => video_codec = Some(e),
+                                    Ok(None) => {}
+                                    Err(e) => return Err(e),
+                                };
+                            }
                             _ => {}
                         },
                         _ => {}
@@ -259,22 +525,28 @@ impl FormatDescriptor {
                     video_dimension_height: video_height,
                     codec,
                     extensions: Some(extensions),
-                    avc1,
+                    video_codec,
                     audio_stream_basic_description: None,
                 })
             }
-            _ => return Err(Error::new(ErrorKind::InvalidData, "media type invalid")),
+            _ => return Err(QTError::UnknownMagic(media_type)),
         }
     }
 
     pub fn as_qt_packet(&self) -> Result<QTPacket, io::Error> {
-        let mut mdia_pkt = QTPacket::new();
-        match mdia_pkt.write_u32(MAGIC_MEDIA_TYPE) {
+        let mut pkt = QTPacket::new_with_magic(MAGIC_FORMAT_DESCRIPTOR);
+
+        let mut mdia_pkt = QTPacket::new_with_magic(MAGIC_MEDIA_TYPE);
+
+        match mdia_pkt.write_u32(self.media_type) {
             Err(e) => return Err(e),
             _ => {}
         };
 
-        match mdia_pkt.write_u32(self.media_type) {
+        match pkt.write(match mdia_pkt.as_bytes() {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        }) {
             Err(e) => return Err(e),
             _ => {}
         };
@@ -283,14 +555,17 @@ impl FormatDescriptor {
             MEDIA_TYPE_SOUND => {
                 let mut asdb = QTPacket::new_with_magic(MAGIC_AUDIO_STREAM_DESCRIPTION);
 
-                let buffer = match self
-                    .audio_stream_basic_description
-                    .as_ref()
-                    .unwrap()
-                    .as_buffer()
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
+                let buffer = match self.audio_stream_description() {
+                    Some(asd) => match asd.as_buffer() {
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    },
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "audio stream description none",
+                        ))
+                    }
                 };
 
                 let asdb_buffer = match asdb.write(buffer.as_slice()) {
@@ -301,7 +576,7 @@ impl FormatDescriptor {
                     },
                 };
 
-                match mdia_pkt.write(asdb_buffer) {
+                match pkt.write(asdb_buffer) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
@@ -319,6 +594,14 @@ impl FormatDescriptor {
                     _ => {}
                 };
 
+                match pkt.write(match vd_pkt.as_bytes() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                }) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+
                 let mut codec_pkt = QTPacket::new_with_magic(MAGIC_CODEC);
 
                 match codec_pkt.write_u32(self.codec) {
@@ -326,20 +609,18 @@ impl FormatDescriptor {
                     _ => {}
                 };
 
-                let codec_buffer = match codec_pkt.as_bytes() {
+                match pkt.write(match codec_pkt.as_bytes() {
                     Ok(e) => e,
                     Err(e) => return Err(e),
-                };
-
-                match vd_pkt.write(codec_buffer) {
+                }) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
 
                 let mut extension_pkt = QTPacket::new_with_magic(MAGIC_EXTENSION);
 
-                if self.extensions.is_some() {
-                    for extension in self.extensions.as_ref().unwrap() {
+                if let Some(extensions) = &self.extensions {
+                    for extension in extensions {
                         let mut ext_val_pkt = match extension.as_qt_packet() {
                             Ok(e) => e,
                             Err(e) => return Err(e),
@@ -355,24 +636,12 @@ impl FormatDescriptor {
                             _ => {}
                         };
                     }
-
-                    let extension_buffer = match extension_pkt.as_bytes() {
-                        Err(e) => return Err(e),
-                        Ok(e) => e,
-                    };
-
-                    match vd_pkt.write(extension_buffer) {
-                        Err(e) => return Err(e),
-                        _ => {}
-                    };
                 }
 
-                let vd_buffer = match vd_pkt.as_bytes() {
-                    Err(e) => return Err(e),
+                match pkt.write(match extension_pkt.as_bytes() {
                     Ok(e) => e,
-                };
-
-                match mdia_pkt.write(vd_buffer) {
+                    Err(e) => return Err(e),
+                }) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
@@ -380,12 +649,101 @@ impl FormatDescriptor {
             _ => return Err(Error::new(ErrorKind::InvalidData, "media type invalid")),
         };
 
-        Ok(mdia_pkt)
+        Ok(pkt)
     }
 }
 
+// idx 49 holds the sample description atoms, avcC is idx 105 in iOS 15.6. the hvcC idx is
+// not documented, so for hevc we take the first data entry that parses as a hvcC record
+fn parse_codec_atoms(codec: u32, obj: &[QTValue]) -> Result<Option<VideoCodec>, QTError> {
+    for value in obj {
+        let kv = match value.as_pair() {
+            Some(e) => e,
+            None => return Err(QTError::MalformedValue(String::from("idx 49 entry is not kv pair"))),
+        };
+
+        let data = match kv.value().as_data() {
+            Some(e) => e,
+            None => continue,
+        };
+
+        match codec {
+            CODEC_HVC1 | CODEC_HEV1 => match HVC1::from_vec(data) {
+                Ok(e) => return Ok(Some(VideoCodec::Hvc1(e))),
+                Err(_) => continue,
+            },
+            _ if kv.key().as_idx() == Some(105) => {
+                return match AVC1::from_vec(data) {
+                    Ok(e) => Ok(Some(VideoCodec::Avc1(e))),
+                    Err(e) => Err(QTError::MalformedValue(format!("avcC {}", e))),
+                }
+            }
+            _ => {}
+        };
+    }
+
+    Ok(None)
+}
+
 impl Debug for FormatDescriptor {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("Format Descriptor")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // main profile hvcC laid out like the device sends it, with an SEI array after the parameter sets
+    const GOLDEN_HVCC: [u8; 89] = [
+        0x01, 0x01, 0x60, 0x00, 0x00, 0x00, 0xB0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5D, 0xF0, 0x00, 0xFC,
+        0xFD, 0xF8, 0xF8, 0x00, 0x00, 0x0F, 0x04,
+        // vps
+        0xA0, 0x00, 0x01, 0x00, 0x18,
+        0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0xB0, 0x00, 0x00, 0x03,
+        0x00, 0x00, 0x03, 0x00, 0x5D, 0x15, 0xC0, 0x90,
+        // sps
+        0xA1, 0x00, 0x01, 0x00, 0x0A,
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0xB0,
+        // pps
+        0xA2, 0x00, 0x01, 0x00, 0x07,
+        0x44, 0x01, 0xC0, 0xF2, 0xC6, 0x8D, 0x03,
+        // prefix sei
+        0xA7, 0x00, 0x01, 0x00, 0x05,
+        0x4E, 0x01, 0x05, 0x1A, 0x80,
+    ];
+
+    #[test]
+    fn hvcc_parameter_sets() {
+        let hvc1 = HVC1::from_vec(&GOLDEN_HVCC).unwrap();
+
+        assert_eq!(hvc1.vps(), &vec![Vec::from(&GOLDEN_HVCC[28..52])]);
+        assert_eq!(hvc1.sps(), &vec![Vec::from(&GOLDEN_HVCC[57..67])]);
+        assert_eq!(hvc1.pps(), &vec![Vec::from(&GOLDEN_HVCC[72..79])]);
+        assert_eq!(hvc1.vps()[0][0] >> 1, HEVC_NALU_TYPE_VPS);
+        assert_eq!(hvc1.sps()[0][0] >> 1, HEVC_NALU_TYPE_SPS);
+        assert_eq!(hvc1.pps()[0][0] >> 1, HEVC_NALU_TYPE_PPS);
+        assert_eq!(hvc1.nalu_len, 4);
+
+        // written back to the mp4 exactly as received
+        assert_eq!(hvc1.as_vec(), Vec::from(GOLDEN_HVCC));
+    }
+
+    #[test]
+    fn hvcc_length_size_and_truncation() {
+        let mut data = Vec::from(GOLDEN_HVCC);
+        data[21] = 0x0D;
+        assert_eq!(HVC1::from_vec(&data).unwrap().nalu_len, 2);
+
+        assert!(HVC1::from_vec(&GOLDEN_HVCC[..70]).is_err());
+        assert!(HVC1::from_vec(&GOLDEN_HVCC[..HVCC_HEADER_LEN]).is_err());
+
+        let built = HVC1::new(&GOLDEN_HVCC[28..52], &GOLDEN_HVCC[57..67], &GOLDEN_HVCC[72..79]);
+        let parsed = HVC1::from_vec(&built.as_vec()).unwrap();
+        assert_eq!(parsed.vps(), built.vps());
+        assert_eq!(parsed.sps(), built.sps());
+        assert_eq!(parsed.pps(), built.pps());
+        assert_eq!(parsed.nalu_len, 4);
+    }
+}
@@ -3,3 +3,4 @@ pub mod clock;
 pub mod format_desc;
 pub mod sample;
 pub mod time;
+pub mod timeline;
@@ -1,9 +1,13 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
 use crate::coremedia::format_desc::FormatDescriptor;
 use crate::coremedia::time::Time;
+use crate::error::QTError;
 use crate::qt_pkt::QTPacket;
 use crate::qt_value::QTValue;
+use log::warn;
 use std::fmt::{Debug, Formatter};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 
 pub const MAGIC_AUDIO_STREAM_DESCRIPTION: u32 = 0x61736264;
 pub const MAGIC_FORMAT_DESCRIPTOR: u32 = 0x66647363;
@@ -14,6 +18,8 @@ pub const MAGIC_CODEC: u32 = 0x636F6463;
 pub const MEDIA_TYPE_VIDEO: u32 = 0x76696465;
 pub const MEDIA_TYPE_SOUND: u32 = 0x736F756E;
 pub const CODEC_AVC1: u32 = 0x61766331;
+pub const CODEC_HVC1: u32 = 0x68766331;
+pub const CODEC_HEV1: u32 = 0x68657631;
 
 pub struct SampleTimingInfo {
     duration: Time,
@@ -22,12 +28,64 @@ pub struct SampleTimingInfo {
 }
 
 impl SampleTimingInfo {
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> SampleTimingInfo {
+    pub fn new(duration: Time, presentation_time_stamp: Time, decode_time_stamp: Time) -> Self {
         SampleTimingInfo {
-            duration: Time::from_qt_packet(pkt),
-            presentation_time_stamp: Time::from_qt_packet(pkt),
-            decode_time_stamp: Time::from_qt_packet(pkt),
+            duration,
+            presentation_time_stamp,
+            decode_time_stamp,
+        }
+    }
+
+    pub fn duration(&self) -> &Time {
+        &self.duration
+    }
+
+    pub fn presentation_time_stamp(&self) -> &Time {
+        &self.presentation_time_stamp
+    }
+
+    pub fn decode_time_stamp(&self) -> &Time {
+        &self.decode_time_stamp
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        for t in [
+            &self.duration,
+            &self.presentation_time_stamp,
+            &self.decode_time_stamp,
+        ] {
+            match t.as_bytes() {
+                Ok(e) => buffer.extend_from_slice(e.as_slice()),
+                Err(e) => return Err(e),
+            };
         }
+
+        Ok(buffer)
+    }
+
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<SampleTimingInfo, QTError> {
+        let duration = match Time::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let presentation_time_stamp = match Time::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let decode_time_stamp = match Time::from_qt_packet(pkt) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(SampleTimingInfo {
+            duration,
+            presentation_time_stamp,
+            decode_time_stamp,
+        })
     }
 }
 
@@ -70,6 +128,9 @@ const SSIZ: u32 = 0x7373697A; //samplesize in bytes, size of what is contained i
 const NSMP: u32 = 0x6E736D70; //numsample so you know how many things are in the arrays
 const FREE: u32 = 0x66726565;
 
+// satt index key of kCMSampleAttachmentKey_NotSync, a non zero value marks a frame that is not an IDR
+pub const SATT_NOT_SYNC: u16 = 29;
+
 impl SampleBuffer {
     pub fn new(media_type: u32) -> SampleBuffer {
         SampleBuffer {
@@ -85,8 +146,37 @@ impl SampleBuffer {
         }
     }
 
-    pub fn sary(&self) -> &Vec<QTValue> {
-        self.sary.as_ref().expect("take sary")
+    pub fn sary(&self) -> Option<&Vec<QTValue>> {
+        self.sary.as_ref()
+    }
+
+    pub fn attachments(&self) -> Option<&Vec<QTValue>> {
+        self.attachments.as_ref()
+    }
+
+    // what the encoder says about the frame, without looking at the NALUs. like CoreMedia a
+    // sample without a NotSync attachment is a sync sample, so audio always is
+    pub fn is_sync(&self) -> bool {
+        let attachments = match &self.attachments {
+            Some(e) => e,
+            None => return true,
+        };
+
+        for kv in attachments.iter().filter_map(|a| a.as_pair()) {
+            if kv.key().as_idx() != Some(SATT_NOT_SYNC) {
+                continue;
+            }
+
+            let not_sync = match kv.value() {
+                QTValue::Boolean(b) => *b,
+                QTValue::UInt32(u) => *u != 0,
+                QTValue::UInt64(u) => *u != 0,
+                _ => false,
+            };
+            return !not_sync;
+        }
+
+        true
     }
 
     pub fn sample_data(&self) -> Option<&[u8]> {
@@ -111,60 +201,291 @@ impl SampleBuffer {
         self.output_presentation_time_stamp.clone()
     }
 
-    pub fn from_qt_packet(pkt: &mut QTPacket, media_type: u32) -> Result<SampleBuffer, Error> {
+    pub fn sample_timing_info_array(&self) -> Option<&Vec<SampleTimingInfo>> {
+        self.sample_timing_info_array.as_ref()
+    }
+
+    pub fn num_samples(&self) -> u32 {
+        self.num_samples
+    }
+
+    pub fn sample_sizes(&self) -> Option<&Vec<u32>> {
+        self.sample_sizes.as_ref()
+    }
+
+    // video sample data is AVCC/HVCC, big endian length prefixed NALUs. the size of the length
+    // comes from the sample's own avcC/hvcC, 4 without one. empty for audio
+    pub fn nal_units(&self) -> Result<Vec<&[u8]>, Error> {
+        let length_size = match self.format_description.as_ref().and_then(|fd| fd.video_codec()) {
+            Some(codec) => codec.nalu_len(),
+            None => 4,
+        };
+
+        self.nal_units_sized(length_size)
+    }
+
+    // for samples that go by a format description sent earlier, length_size as it said
+    pub fn nal_units_sized(&self, length_size: u8) -> Result<Vec<&[u8]>, Error> {
+        let mut units: Vec<&[u8]> = Vec::new();
+        if self.media_type != MEDIA_TYPE_VIDEO {
+            return Ok(units);
+        }
+
+        if !(1..=4).contains(&length_size) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("nalu length size {}", length_size),
+            ));
+        }
+
+        let mut cur = match &self.sample_data {
+            Some(e) => e.as_slice(),
+            None => return Ok(units),
+        };
+
+        let length_size = length_size as usize;
+        while !cur.is_empty() {
+            if cur.len() < length_size {
+                return Err(Error::new(ErrorKind::InvalidData, "truncated nalu length"));
+            }
+
+            let nalu_len = cur[..length_size]
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            if nalu_len > cur.len() - length_size {
+                return Err(Error::new(ErrorKind::InvalidData, "nalu longer than sample"));
+            }
+
+            // an empty NALU carries nothing, it is skipped
+            if nalu_len > 0 {
+                units.push(&cur[length_size..length_size + nalu_len]);
+            }
+            cur = &cur[length_size + nalu_len..];
+        }
+
+        Ok(units)
+    }
+
+    pub fn set_output_presentation_time_stamp(&mut self, t: Time) {
+        self.output_presentation_time_stamp = Some(t);
+    }
+
+    pub fn set_format_description(&mut self, fd: FormatDescriptor) {
+        self.format_description = Some(fd);
+    }
+
+    pub fn set_sample_timing_info_array(&mut self, arr: Vec<SampleTimingInfo>) {
+        self.sample_timing_info_array = Some(arr);
+    }
+
+    pub fn set_attachments(&mut self, attachments: Vec<QTValue>) {
+        self.attachments = Some(attachments);
+    }
+
+    pub fn set_sample_data(&mut self, data: Vec<u8>, sample_sizes: Vec<u32>) {
+        self.num_samples = sample_sizes.len() as u32;
+        self.sample_sizes = Some(sample_sizes);
+        self.sample_data = Some(data);
+    }
+
+    pub fn as_qt_packet(&self) -> Result<QTPacket, Error> {
+        let mut sbuf = QTPacket::new_with_magic(SBUF);
+
+        if let Some(t) = &self.output_presentation_time_stamp {
+            let mut opts = QTPacket::new_with_magic(OPTS);
+            match opts.write(match t.as_bytes() {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            }
+            .as_slice())
+            {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+            match write_child(&mut sbuf, &mut opts) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        if let Some(arr) = &self.sample_timing_info_array {
+            let mut stia = QTPacket::new_with_magic(STIA);
+            for timing in arr {
+                match stia.write(match timing.as_bytes() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                }
+                .as_slice())
+                {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+            }
+            match write_child(&mut sbuf, &mut stia) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        if let Some(data) = &self.sample_data {
+            let mut sdat = QTPacket::new_with_magic(SDAT);
+            match sdat.write(data.as_slice()) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+            match write_child(&mut sbuf, &mut sdat) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        let mut nsmp = QTPacket::new_with_magic(NSMP);
+        match nsmp.write_u32(self.num_samples) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+        match write_child(&mut sbuf, &mut nsmp) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        if let Some(sizes) = &self.sample_sizes {
+            let mut ssiz = QTPacket::new_with_magic(SSIZ);
+            for size in sizes {
+                match ssiz.write_u32(*size) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+            }
+            match write_child(&mut sbuf, &mut ssiz) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        if let Some(fd) = &self.format_description {
+            let mut fdsc = match fd.as_qt_packet() {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+            match write_child(&mut sbuf, &mut fdsc) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        for (magic, values) in [(SATT, &self.attachments), (SARY, &self.sary)] {
+            if let Some(values) = values {
+                let mut child = QTPacket::new_with_magic(magic);
+                for value in values {
+                    let mut val_pkt = match value.as_qt_packet() {
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    };
+                    match write_child(&mut child, &mut val_pkt) {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
+                }
+                match write_child(&mut sbuf, &mut child) {
+                    Err(e) => return Err(e),
+                    _ => {}
+                };
+            }
+        }
+
+        Ok(sbuf)
+    }
+
+    pub fn from_qt_packet(pkt: &mut QTPacket, media_type: u32) -> Result<SampleBuffer, QTError> {
         let mut sample = Self::new(media_type);
 
-        let (mut sbuf, _) =
-            QTPacket::from_qt_packet_with_magic(pkt, SBUF).expect("read sbuf packet");
+        let (mut sbuf, _) = match QTPacket::from_qt_packet_with_magic(pkt, SBUF) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let sbuf_len = match sbuf.len() {
+            Ok(e) => e,
+            Err(e) => return Err(e.into()),
+        };
 
-        while sbuf.pos() < sbuf.len().expect("sbuf length") {
+        while sbuf.pos() < sbuf_len {
             let (mut inner, magic) = match sbuf.read_qt_packet_with_magic() {
                 Ok(e) => e,
                 Err(e) => return Err(e),
             };
 
+            let inner_len = match inner.len() {
+                Ok(e) => e,
+                Err(e) => return Err(e.into()),
+            };
+
             match magic {
                 OPTS => {
-                    sample.output_presentation_time_stamp = Some(Time::from_qt_packet(&mut inner))
+                    sample.output_presentation_time_stamp = match Time::from_qt_packet(&mut inner) {
+                        Ok(e) => Some(e),
+                        Err(e) => return Err(e),
+                    }
                 }
                 STIA => {
                     let mut arr: Vec<SampleTimingInfo> = Vec::new();
-                    while inner.pos() < inner.len().expect("sita length") {
-                        arr.push(SampleTimingInfo::from_qt_packet(&mut inner))
+                    while inner.pos() < inner_len {
+                        match SampleTimingInfo::from_qt_packet(&mut inner) {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.sample_timing_info_array = Some(arr);
                 }
                 SDAT => {
-                    let inner_len = inner.len().expect("inner length");
                     let mut sample_data: Vec<u8> = vec![0; inner_len as usize - 8];
-                    inner.read(&mut sample_data).expect("sdat read sample data");
+                    match inner.read_exact(&mut sample_data) {
+                        Err(e) => return Err(e.into()),
+                        _ => {}
+                    };
                     sample.sample_data = Some(sample_data);
                 }
-                NSMP => sample.num_samples = inner.read_u32().expect("nsmp read sample length"),
+                NSMP => {
+                    sample.num_samples = match inner.read_u32() {
+                        Ok(e) => e,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
                 SSIZ => {
                     let mut arr: Vec<u32> = Vec::new();
-                    while inner.pos() < inner.len().expect("ssiz length") {
-                        arr.push(inner.read_u32().expect("read ssiz"))
+                    while inner.pos() < inner_len {
+                        match inner.read_u32() {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e.into()),
+                        };
                     }
                     sample.sample_sizes = Some(arr);
                 }
                 MAGIC_FORMAT_DESCRIPTOR => {
-                    sample.format_description = Some(
-                        FormatDescriptor::from_qt_packet(&mut inner)
-                            .expect("read format descriptor"),
-                    )
+                    sample.format_description = match FormatDescriptor::from_qt_packet(&mut inner)
+                    {
+                        Ok(e) => Some(e),
+                        Err(e) => return Err(e),
+                    }
                 }
                 SATT => {
                     let mut arr: Vec<QTValue> = Vec::new();
-                    while inner.pos() < inner.len().expect("satt length") {
-                        arr.push(QTValue::from_qt_packet(&mut inner).expect("read satt"))
+                    while inner.pos() < inner_len {
+                        match QTValue::from_qt_packet(&mut inner) {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.attachments = Some(arr);
                 }
                 SARY => {
                     let mut arr: Vec<QTValue> = Vec::new();
-                    while inner.pos() < inner.len().expect("sary length") {
-                        arr.push(QTValue::from_qt_packet(&mut inner).expect("read sary"))
+                    while inner.pos() < inner_len {
+                        match QTValue::from_qt_packet(&mut inner) {
+                            Ok(e) => arr.push(e),
+                            Err(e) => return Err(e),
+                        };
                     }
                     sample.sary = Some(arr);
                 }
@@ -172,10 +493,7 @@ impl SampleBuffer {
                     // free box
                 }
                 _ => {
-                    println!(
-                        "invalid data {}",
-                        format!("sbuf invalid magic {:#x}", magic)
-                    );
+                    warn!("invalid data sbuf invalid magic {:#x}", magic);
                 }
             };
         }
@@ -184,6 +502,18 @@ impl SampleBuffer {
     }
 }
 
+fn write_child(parent: &mut QTPacket, child: &mut QTPacket) -> Result<(), Error> {
+    let buffer = match child.as_bytes() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    match parent.write(buffer) {
+        Err(e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
 impl Debug for SampleBuffer {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str("SampleBuffer:\n").expect("write");
@@ -196,8 +526,8 @@ impl Debug for SampleBuffer {
         }
         f.write_fmt(format_args!("num_samples: {}\n", self.num_samples))
             .expect("write");
-        if self.sample_timing_info_array.is_some() {
-            for timing in self.sample_timing_info_array.as_ref().unwrap() {
+        if let Some(timing_info) = &self.sample_timing_info_array {
+            for timing in timing_info {
                 f.write_fmt(format_args!("sample_timing_info_array:\n {:?}\n", timing))
                     .expect("write");
             }
@@ -209,22 +539,53 @@ impl Debug for SampleBuffer {
         .expect("write");
         f.write_fmt(format_args!("sample_sizes: {:?}\n", self.sample_sizes))
             .expect("write");
-        if self.attachments.is_some() {
-            let mut i = 0;
-            for qtv in self.attachments.as_ref().unwrap() {
+        if let Some(attachments) = &self.attachments {
+            for (i, qtv) in attachments.iter().enumerate() {
                 f.write_fmt(format_args!("attachments.{}:\n{:?}\n", i, qtv))
                     .expect("write");
-                i += 1;
             }
         }
-        if self.sary.is_some() {
-            let mut i = 0;
-            for qtv in self.sary.as_ref().unwrap() {
+        if let Some(sary) = &self.sary {
+            for (i, qtv) in sary.iter().enumerate() {
                 f.write_fmt(format_args!("sary.{}:\n{:?}\n", i, qtv))
                     .expect("write");
-                i += 1;
             }
         }
         f.write_str("-----")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(data: &[u8]) -> SampleBuffer {
+        let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        sample.set_sample_data(Vec::from(data), Vec::from([data.len() as u32]));
+        sample
+    }
+
+    #[test]
+    fn nal_units_follow_the_length_size() {
+        let sample = video(&[0, 0, 0, 2, 0x65, 1, 0, 0, 0, 1, 0x06]);
+        assert_eq!(sample.nal_units().unwrap(), vec![&[0x65, 1][..], &[0x06][..]]);
+
+        let sample = video(&[0, 2, 0x65, 1, 0, 1, 0x06]);
+        assert_eq!(sample.nal_units_sized(2).unwrap(), vec![&[0x65, 1][..], &[0x06][..]]);
+
+        let sample = video(&[2, 0x65, 1, 0, 1, 0x06]);
+        assert_eq!(sample.nal_units_sized(1).unwrap(), vec![&[0x65, 1][..], &[0x06][..]]);
+        assert!(sample.nal_units_sized(0).is_err());
+    }
+
+    #[test]
+    fn nal_units_refuse_malformed_lengths() {
+        // length past the end of the sample
+        assert!(video(&[0, 0, 0, 9, 0x65, 1]).nal_units().is_err());
+        // length cut off
+        assert!(video(&[0, 0, 0, 1, 0x65, 0, 0]).nal_units().is_err());
+        // empty NALUs are skipped
+        assert_eq!(video(&[0, 0, 0, 0, 0, 0, 0, 1, 0x06]).nal_units().unwrap(), vec![&[0x06][..]]);
+        assert!(SampleBuffer::new(MEDIA_TYPE_SOUND).nal_units().unwrap().is_empty());
+    }
+}
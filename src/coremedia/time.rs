@@ -1,8 +1,12 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use crate::error::QTError;
 use crate::qt_pkt::QTPacket;
 use byteorder::{LittleEndian, WriteBytesExt};
 use std::fmt::{Debug, Formatter};
 use std::io::Error;
 
+#[derive(PartialEq)]
 pub struct Time {
     value: u64,
     scale: u32,
@@ -56,30 +60,50 @@ impl Time {
         self.epoch
     }
 
+    // flags 0 is kCMTimeInvalid, the device leaves unused timestamps like that
+    pub fn is_valid(&self) -> bool {
+        self.flags & 0x1 != 0 && self.scale != 0
+    }
+
     pub fn get_time_for_scale(&self, new_scale: &Time) -> f64 {
         let scaling_factor = new_scale.scale as f64 / self.scale as f64;
         self.value as f64 * scaling_factor
     }
 
     pub fn seconds(&self) -> u64 {
-        match self.value {
-            0 => 0,
-            v => v / self.scale as u64,
+        match (self.value, self.scale) {
+            (0, _) | (_, 0) => 0,
+            (v, scale) => v / scale as u64,
         }
     }
 
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> Time {
-        let value = pkt.read_u64().expect("time read value");
-        let scale = pkt.read_u32().expect("time read scale");
-        let flags = pkt.read_u32().expect("time read flags");
-        let epoch = pkt.read_u64().expect("time read epoch");
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<Time, QTError> {
+        let value = match pkt.read_u64() {
+            Ok(e) => e,
+            Err(e) => return Err(e.into()),
+        };
 
-        Time {
+        let scale = match pkt.read_u32() {
+            Ok(e) => e,
+            Err(e) => return Err(e.into()),
+        };
+
+        let flags = match pkt.read_u32() {
+            Ok(e) => e,
+            Err(e) => return Err(e.into()),
+        };
+
+        let epoch = match pkt.read_u64() {
+            Ok(e) => e,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Time {
             value,
             scale,
             flags,
             epoch,
-        }
+        })
     }
 
     pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
@@ -0,0 +1,204 @@
+use crate::coremedia::sample::{SampleBuffer, SampleTimingInfo};
+use crate::coremedia::time::Time;
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+
+fn nanos(t: &Time) -> i128 {
+    t.value() as i128 * NANOS_PER_SECOND / t.scale() as i128
+}
+
+fn shift(t: &Time, offset: i128) -> Time {
+    if !t.is_valid() {
+        return t.clone();
+    }
+
+    let value = t.value() as i128 + offset * t.scale() as i128 / NANOS_PER_SECOND;
+    Time::new(value.max(0) as u64, t.scale(), t.flags(), t.epoch())
+}
+
+// decode time where there is one, presentation times of reordered frames go back and forth
+fn sample_time(sample: &SampleBuffer) -> Option<Time> {
+    let sti = sample
+        .sample_timing_info_array()
+        .and_then(|arr| arr.first());
+
+    match sti {
+        Some(sti) if sti.decode_time_stamp().is_valid() => Some(sti.decode_time_stamp().clone()),
+        Some(sti) if sti.presentation_time_stamp().is_valid() => {
+            Some(sti.presentation_time_stamp().clone())
+        }
+        _ => match sample.output_presentation_time_stamp() {
+            Some(t) if t.is_valid() => Some(t),
+            _ => None,
+        },
+    }
+}
+
+// the device timestamps of one clock as the outputs see them. SRAT and TJMP tell us when the
+// device moves its clock, the samples after a jump are shifted to continue where the last one
+// ended so players and recordings don't see time going backwards. a pause stays a gap, at any
+// other rate the steps between samples are stretched to real time
+pub struct Timeline {
+    rate: f32,
+    jumped: bool,
+    // added to every device timestamp
+    offset: i128,
+    // device time of the last sample
+    last_device: Option<i128>,
+    // output distance between the last two samples, where the next one is expected after a jump
+    last_step: i128,
+    // output time of the last sample
+    last_output: Option<i128>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Timeline::new()
+    }
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline {
+            rate: 1.0,
+            jumped: false,
+            offset: 0,
+            last_device: None,
+            last_step: 0,
+            last_output: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.rate == 0.0
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    // an anchor behind the last sample means the device restarted the clock. false for a rate
+    // that can't be played, negative or not a number, the old one stays
+    pub fn set_rate(&mut self, rate: f32, anchor: &Time) -> bool {
+        if rate.is_nan() || rate < 0.0 {
+            return false;
+        }
+        self.rate = rate;
+
+        if let Some(last) = self.last_device {
+            if anchor.is_valid() && nanos(anchor) < last {
+                self.jumped = true;
+            }
+        }
+
+        true
+    }
+
+    // the next sample continues after the last one, whatever its device time
+    pub fn jump(&mut self) {
+        self.jumped = true;
+    }
+
+    pub fn apply(&mut self, sample: &mut SampleBuffer) {
+        let device = match sample_time(sample) {
+            Some(t) => nanos(&t),
+            None => return,
+        };
+
+        // time going backwards without a TJMP is a jump too
+        let backwards = match self.last_device {
+            Some(last) => device < last,
+            None => false,
+        };
+
+        if self.jumped || backwards {
+            if let Some(output) = self.last_output {
+                self.offset = output + self.last_step - device;
+            }
+            self.jumped = false;
+        } else if let (Some(last), Some(output)) = (self.last_device, self.last_output) {
+            // at 2x a second of device time is half a second for the viewer
+            self.last_step = match self.rate > 0.0 && self.rate != 1.0 {
+                true => ((device - last) as f64 / self.rate as f64) as i128,
+                false => device - last,
+            };
+            self.offset = output + self.last_step - device;
+        }
+
+        self.last_device = Some(device);
+        self.last_output = Some(device + self.offset);
+
+        if self.offset == 0 {
+            return;
+        }
+
+        if let Some(opts) = sample.output_presentation_time_stamp() {
+            sample.set_output_presentation_time_stamp(shift(&opts, self.offset));
+        }
+
+        let stia = match sample.sample_timing_info_array() {
+            Some(arr) => arr
+                .iter()
+                .map(|sti| {
+                    SampleTimingInfo::new(
+                        sti.duration().clone(),
+                        shift(sti.presentation_time_stamp(), self.offset),
+                        shift(sti.decode_time_stamp(), self.offset),
+                    )
+                })
+                .collect(),
+            None => return,
+        };
+        sample.set_sample_timing_info_array(stia);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coremedia::sample::MEDIA_TYPE_VIDEO;
+
+    fn video_sample(nanos: u64) -> SampleBuffer {
+        let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        let time = Time::new(nanos, 1_000_000_000, 1, 0);
+        sample.set_sample_timing_info_array(Vec::from([SampleTimingInfo::new(
+            Time::new(0, 0, 0, 0),
+            time.clone(),
+            time,
+        )]));
+        sample
+    }
+
+    // output decode times of samples at the given device times
+    fn play(timeline: &mut Timeline, device: &[u64]) -> Vec<u64> {
+        device
+            .iter()
+            .map(|t| {
+                let mut sample = video_sample(*t);
+                timeline.apply(&mut sample);
+                sample.sample_timing_info_array().unwrap()[0].decode_time_stamp().value()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rate_stretches_steps() {
+        let mut timeline = Timeline::new();
+        assert_eq!(play(&mut timeline, &[0, 100, 200]), [0, 100, 200]);
+
+        // the device clock runs twice as fast, its steps take half as long
+        assert!(timeline.set_rate(2.0, &Time::new(0, 0, 0, 0)));
+        assert_eq!(play(&mut timeline, &[400, 600]), [300, 400]);
+
+        assert!(timeline.set_rate(1.0, &Time::new(0, 0, 0, 0)));
+        assert_eq!(play(&mut timeline, &[700]), [500]);
+    }
+
+    #[test]
+    fn negative_rate_is_refused() {
+        let mut timeline = Timeline::new();
+        assert!(!timeline.set_rate(-1.0, &Time::new(0, 0, 0, 0)));
+        assert!(!timeline.set_rate(f32::NAN, &Time::new(0, 0, 0, 0)));
+        assert_eq!(timeline.rate(), 1.0);
+    }
+}
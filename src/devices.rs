@@ -0,0 +1,253 @@
+use crate::apple;
+use crate::apple::{AppleDevice, UsbDeviceInfo, UsbMatchError};
+use rusty_libimobiledevice::idevice;
+
+pub struct DeviceInfo {
+    udid: String,
+    name: Option<String>,
+    product_type: Option<String>,
+    product_version: Option<String>,
+    usb: Option<UsbDeviceInfo>,
+    // lockdownd failed, usually not paired or not trusted yet
+    error: Option<String>,
+}
+
+impl DeviceInfo {
+    pub fn udid(&self) -> &str {
+        self.udid.as_str()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn product_type(&self) -> Option<&str> {
+        self.product_type.as_deref()
+    }
+
+    pub fn product_version(&self) -> Option<&str> {
+        self.product_version.as_deref()
+    }
+
+    pub fn usb(&self) -> Option<&UsbDeviceInfo> {
+        self.usb.as_ref()
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+// usb serials are the udid without the dash
+pub fn same_device(udid: &str, serial: &str) -> bool {
+    udid.replace('-', "").eq_ignore_ascii_case(serial)
+}
+
+// the usb side of a device from list_devices, ready to start a session on
+pub fn open_device(udid: &str) -> Result<AppleDevice, UsbMatchError> {
+    apple::get_usb_device(Some(udid), None)
+}
+
+// the first usb device usbmuxd knows about, as a usb serial
+pub fn default_serial() -> Result<String, String> {
+    let devices = match idevice::get_devices() {
+        Ok(d) => d,
+        Err(e) => return Err(format!("get_devices: {:?}", e)),
+    };
+
+    let device = match devices.into_iter().find(|d| !d.get_network()) {
+        Some(d) => d,
+        None => return Err(String::from("get_apple_device: NoDevice")),
+    };
+
+    let lockdownd = match device.new_lockdownd_client("qtstream") {
+        Ok(client) => client,
+        Err(e) => return Err(format!("new_lockdownd_client: {:?}", e)),
+    };
+
+    match lockdownd.get_device_udid() {
+        Ok(sn) => Ok(sn.replace('-', "")),
+        Err(e) => Err(format!("get_device_udid: {:?}", e)),
+    }
+}
+
+// usbmuxd devices first, then apple devices on the bus usbmuxd does not know about
+pub fn list_devices() -> Result<Vec<DeviceInfo>, String> {
+    let mut usb_devices = match apple::list_usb_devices() {
+        Ok(e) => e,
+        Err(e) => return Err(format!("libusb: {:?}", e)),
+    };
+
+    let devices = match idevice::get_devices() {
+        Ok(e) => e,
+        Err(e) => return Err(format!("get_devices: {:?}", e)),
+    };
+
+    let mut list: Vec<DeviceInfo> = Vec::new();
+
+    for device in devices {
+        if device.get_network() {
+            continue;
+        }
+
+        let udid = device.get_udid();
+
+        let usb = usb_devices
+            .iter()
+            .position(|u| u.serial().map(|sn| same_device(udid.as_str(), sn)).unwrap_or(false))
+            .map(|i| usb_devices.remove(i));
+
+        let mut info = DeviceInfo {
+            udid,
+            name: None,
+            product_type: None,
+            product_version: None,
+            usb,
+            error: None,
+        };
+
+        let lockdownd = match device.new_lockdownd_client("qtstream") {
+            Ok(client) => client,
+            Err(e) => {
+                info.error = Some(format!("lockdownd: {:?}", e));
+                list.push(info);
+                continue;
+            }
+        };
+
+        info.name = lockdownd.get_device_name().ok();
+        info.product_type = lockdownd
+            .get_value("ProductType", "")
+            .ok()
+            .and_then(|v| v.get_string_val().ok());
+        info.product_version = lockdownd
+            .get_value("ProductVersion", "")
+            .ok()
+            .and_then(|v| v.get_string_val().ok());
+
+        list.push(info);
+    }
+
+    for usb in usb_devices {
+        // apple keyboards, hubs and the like have no serial we can match
+        let udid = match usb.serial() {
+            Some(sn) if sn.len() >= 24 => String::from(sn),
+            _ => continue,
+        };
+
+        list.push(DeviceInfo {
+            udid,
+            name: None,
+            product_type: None,
+            product_version: None,
+            usb: Some(usb),
+            error: Some(String::from("not known to usbmuxd")),
+        });
+    }
+
+    Ok(list)
+}
+
+// one line per device, for the terminal
+pub fn to_text(devices: &[DeviceInfo]) -> String {
+    if devices.is_empty() {
+        return String::from("no devices");
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    for d in devices {
+        let usb = match &d.usb {
+            Some(u) => match u.location() {
+                Some(l) => format!("usb {:03}:{:03} port {}", u.bus(), u.address(), l),
+                None => format!("usb {:03}:{:03}", u.bus(), u.address()),
+            },
+            None => String::from("usb -"),
+        };
+
+        let qt = match d.usb.as_ref().and_then(|u| u.qt_enabled()) {
+            Some(true) => "qt on",
+            Some(false) => "qt off",
+            None => "qt -",
+        };
+
+        lines.push(format!(
+            "{}  {}  {}  iOS {}  {}  {}{}",
+            d.udid,
+            d.name.as_deref().unwrap_or("-"),
+            d.product_type.as_deref().unwrap_or("-"),
+            d.product_version.as_deref().unwrap_or("-"),
+            usb,
+            qt,
+            match &d.error {
+                Some(e) => format!("  ({})", e),
+                None => String::new(),
+            }
+        ));
+    }
+
+    lines.join("\n")
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option_string(s: Option<&str>) -> String {
+    match s {
+        Some(s) => json_string(s),
+        None => String::from("null"),
+    }
+}
+
+// one object per device, null for what we could not read
+pub fn to_json(devices: &[DeviceInfo]) -> String {
+    let mut items: Vec<String> = Vec::new();
+
+    for d in devices {
+        let (bus, address, location, qt_enabled) = match &d.usb {
+            Some(u) => (
+                u.bus().to_string(),
+                u.address().to_string(),
+                json_option_string(u.location().map(|l| l.to_string()).as_deref()),
+                match u.qt_enabled() {
+                    Some(e) => e.to_string(),
+                    None => String::from("null"),
+                },
+            ),
+            None => (
+                String::from("null"),
+                String::from("null"),
+                String::from("null"),
+                String::from("null"),
+            ),
+        };
+
+        items.push(format!(
+            "{{\"udid\":{},\"name\":{},\"product_type\":{},\"ios_version\":{},\"usb_bus\":{},\"usb_address\":{},\"usb_port\":{},\"qt_enabled\":{},\"error\":{}}}",
+            json_string(d.udid.as_str()),
+            json_option_string(d.name.as_deref()),
+            json_option_string(d.product_type.as_deref()),
+            json_option_string(d.product_version.as_deref()),
+            bus,
+            address,
+            location,
+            qt_enabled,
+            json_option_string(d.error.as_deref()),
+        ));
+    }
+
+    format!("[{}]", items.join(","))
+}
@@ -0,0 +1,80 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+
+#[derive(Debug)]
+pub enum QTError {
+    // libusb failure on the bulk/control endpoints
+    Usb(rusb::Error),
+    // non usb transport failure, capture file, etc.
+    Transport(String),
+    // the transport has nothing more to give, e.g. a replay reached the end of its capture
+    EndOfStream,
+    // packet length does not match the data we have
+    Framing(String),
+    // packet or value magic we don't know
+    UnknownMagic(u32),
+    // packet parsed but a value inside is not what the protocol says
+    MalformedValue(String),
+    // packet arrived before the session state it depends on
+    Protocol(String),
+    // video/audio consumer went away
+    Sink(String),
+    Io(io::Error),
+}
+
+impl QTError {
+    // fatal errors end the session, the others only drop the current packet
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            QTError::Usb(_)
+            | QTError::Transport(_)
+            | QTError::EndOfStream
+            | QTError::Sink(_)
+            | QTError::Io(_) => true,
+            QTError::Framing(_)
+            | QTError::UnknownMagic(_)
+            | QTError::MalformedValue(_)
+            | QTError::Protocol(_) => false,
+        }
+    }
+
+    // the device went away or stopped answering, worth waiting for it to come back
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, QTError::Usb(_))
+    }
+}
+
+impl Display for QTError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QTError::Usb(e) => write!(f, "usb: {}", e),
+            QTError::Transport(s) => write!(f, "transport: {}", s),
+            QTError::EndOfStream => write!(f, "end of stream"),
+            QTError::Framing(s) => write!(f, "framing: {}", s),
+            QTError::UnknownMagic(m) => write!(f, "unknown magic {:#x}", m),
+            QTError::MalformedValue(s) => write!(f, "malformed value: {}", s),
+            QTError::Protocol(s) => write!(f, "protocol: {}", s),
+            QTError::Sink(s) => write!(f, "sink: {}", s),
+            QTError::Io(e) => write!(f, "io: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for QTError {}
+
+impl From<io::Error> for QTError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // every short read on a packet cursor means the packet was truncated
+            ErrorKind::UnexpectedEof => QTError::Framing(e.to_string()),
+            _ => QTError::Io(e),
+        }
+    }
+}
+
+impl From<rusb::Error> for QTError {
+    fn from(e: rusb::Error) -> Self {
+        QTError::Usb(e)
+    }
+}
@@ -0,0 +1,578 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use crate::mp4::{
+    ftyp_box, full_box, lpcm_sample_entry, mdia_box, mp4_box, mvhd_box, rescale,
+    stsd_box, table_box, tkhd_box, video_sample_entry, video_sample_times, AUDIO_TRACK_ID,
+    VIDEO_TIME_SCALE, VIDEO_TRACK_ID,
+};
+use crate::recorder::SampleWriter;
+use log::warn;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+// iOS only sends an IDR when the picture needs one, cap fragments so a static screen still gets flushed
+const MAX_FRAGMENT_DURATION: u64 = 2 * VIDEO_TIME_SCALE as u64;
+// how long we hold video back waiting for the first audio format
+const AUDIO_WAIT: Duration = Duration::from_secs(2);
+
+const SAMPLE_FLAGS_SYNC: u32 = 0x02000000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x01010000;
+
+// tfhd
+const TFHD_DEFAULT_SAMPLE_DURATION: u32 = 0x000008;
+const TFHD_DEFAULT_SAMPLE_SIZE: u32 = 0x000010;
+const TFHD_DEFAULT_SAMPLE_FLAGS: u32 = 0x000020;
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+
+// trun
+const TRUN_DATA_OFFSET: u32 = 0x000001;
+const TRUN_SAMPLE_DURATION: u32 = 0x000100;
+const TRUN_SAMPLE_SIZE: u32 = 0x000200;
+const TRUN_SAMPLE_FLAGS: u32 = 0x000400;
+const TRUN_SAMPLE_COMPOSITION_OFFSET: u32 = 0x000800;
+
+struct FragmentSample {
+    data: Vec<u8>,
+    pts: u64,
+    dts: u64,
+    duration: Option<u64>,
+    key_frame: bool,
+}
+
+struct FragmentVideo {
+    entry: Vec<u8>,
+    width: u32,
+    height: u32,
+    first_arrival: Instant,
+    // dts of the first sample, tfdt counts from here
+    first_dts: Option<u64>,
+    // shifts the track on the movie timeline so audio and video line up
+    start_offset: u64,
+    samples: Vec<FragmentSample>,
+    // avcC/hvcC of the last format description, later changes are sent in band
+    current_entry: Vec<u8>,
+    parameter_sets: Option<Vec<u8>>,
+}
+
+struct FragmentAudio {
+    entry: Vec<u8>,
+    sample_rate: u32,
+    bytes_per_frame: u32,
+    first_arrival: Instant,
+    start_offset: u64,
+    frames_written: u64,
+    data: Vec<u8>,
+}
+
+pub struct FragmentedMp4Writer {
+    writer: BufWriter<File>,
+    expect_audio: bool,
+    video: Option<FragmentVideo>,
+    audio: Option<FragmentAudio>,
+    init_written: bool,
+    sequence: u32,
+    finished: bool,
+}
+
+impl FragmentedMp4Writer {
+    pub fn create(path: &str, expect_audio: bool) -> Result<FragmentedMp4Writer, Error> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(e),
+        };
+
+        Ok(FragmentedMp4Writer {
+            writer: BufWriter::new(file),
+            expect_audio,
+            video: None,
+            audio: None,
+            init_written: false,
+            sequence: 0,
+            finished: false,
+        })
+    }
+
+    fn write_video(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let mut parameter_sets_changed = false;
+
+        if let Some(fd) = sample.format_description() {
+            let entry = match video_sample_entry(fd) {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            // parameter sets in band, length prefixed like the sample data
+            let mut parameter_sets: Vec<u8> = Vec::new();
+            if let Some(codec) = fd.video_codec() {
+                let length_size = codec.nalu_len() as usize;
+                for ps in codec.parameter_sets() {
+                    parameter_sets.extend_from_slice(&(ps.len() as u32).to_be_bytes()[4 - length_size..]);
+                    parameter_sets.extend_from_slice(ps);
+                }
+            }
+
+            match &mut self.video {
+                Some(track) => {
+                    if track.current_entry != entry {
+                        track.current_entry = entry;
+                        track.parameter_sets = Some(parameter_sets);
+                        parameter_sets_changed = true;
+                    }
+                }
+                None => {
+                    self.video = Some(FragmentVideo {
+                        entry: entry.clone(),
+                        width: fd.video_dimension_width(),
+                        height: fd.video_dimension_height(),
+                        first_arrival: Instant::now(),
+                        first_dts: None,
+                        start_offset: 0,
+                        samples: Vec::new(),
+                        current_entry: entry,
+                        parameter_sets: None,
+                    })
+                }
+            };
+        }
+
+        if self.video.is_none() {
+            return Ok(());
+        }
+
+        let (pts, dts, duration) = match video_sample_times(sample) {
+            Some(e) => e,
+            None => return Err(Error::new(ErrorKind::InvalidData, "video sample without timing")),
+        };
+
+        let key_frame = sample.is_sync();
+
+        // a fragment ends right before the next key frame, or once it gets too long
+        let flush = match self.video.as_ref().and_then(|v| v.samples.first()) {
+            Some(first) => key_frame || dts.saturating_sub(first.dts) >= MAX_FRAGMENT_DURATION,
+            None => false,
+        };
+
+        if flush {
+            match self.flush_fragment(Some(dts)) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        let track = self.video.as_mut().unwrap();
+
+        let mut sample_data: Vec<u8> = Vec::new();
+        if parameter_sets_changed {
+            // the init segment is already out, decoders pick up the new sps/pps in band
+            if let Some(ps) = track.parameter_sets.take() {
+                sample_data.extend_from_slice(&ps);
+            }
+        }
+        sample_data.extend_from_slice(data);
+
+        track.samples.push(FragmentSample {
+            data: sample_data,
+            pts,
+            dts,
+            duration,
+            key_frame,
+        });
+
+        Ok(())
+    }
+
+    fn write_audio(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if self.audio.is_none() {
+            // no audio track in the init segment, nowhere to put it
+            if self.init_written {
+                return Ok(());
+            }
+
+            let asd = match sample
+                .format_description()
+                .and_then(|fd| fd.audio_stream_description())
+            {
+                Some(e) => e,
+                None => return Ok(()),
+            };
+
+            let entry = match lpcm_sample_entry(asd) {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            self.audio = Some(FragmentAudio {
+                entry,
+                sample_rate: asd.sample_rate() as u32,
+                bytes_per_frame: asd.bytes_per_frame().max(1),
+                first_arrival: Instant::now(),
+                start_offset: 0,
+                frames_written: 0,
+                data: Vec::new(),
+            });
+        }
+
+        let track = self.audio.as_mut().unwrap();
+        track.data.extend_from_slice(data);
+
+        Ok(())
+    }
+
+    fn init_ready(&self) -> bool {
+        let video = match &self.video {
+            Some(v) => v,
+            None => return false,
+        };
+
+        !self.expect_audio || self.audio.is_some() || video.first_arrival.elapsed() >= AUDIO_WAIT
+    }
+
+    fn write_init(&mut self) -> Result<(), Error> {
+        let video = match &mut self.video {
+            Some(v) => v,
+            None => return Err(Error::new(ErrorKind::InvalidData, "init segment without video")),
+        };
+
+        // place the later track after the earlier one by host arrival
+        if let Some(audio) = &mut self.audio {
+            if audio.first_arrival > video.first_arrival {
+                let delay = audio.first_arrival - video.first_arrival;
+                audio.start_offset = rescale(delay.as_micros() as u64, 1_000_000, audio.sample_rate);
+            } else {
+                let delay = video.first_arrival - audio.first_arrival;
+                video.start_offset = rescale(delay.as_micros() as u64, 1_000_000, VIDEO_TIME_SCALE);
+            }
+        }
+
+        let mut init: Vec<u8> = ftyp_box(b"iso5", &[b"iso5", b"iso6", b"avc1", b"mp41"]);
+
+        let mut moov: Vec<u8> = Vec::new();
+        moov.extend_from_slice(&mvhd_box(0, AUDIO_TRACK_ID + 1));
+
+        let mut trak: Vec<u8> = Vec::new();
+        trak.extend_from_slice(&tkhd_box(VIDEO_TRACK_ID, 0, false, video.width, video.height));
+        trak.extend_from_slice(&mdia_box(VIDEO_TIME_SCALE, 0, false, &empty_stbl(&video.entry)));
+        moov.extend_from_slice(&mp4_box(b"trak", &trak));
+
+        let mut mvex: Vec<u8> = trex_box(VIDEO_TRACK_ID, 0, 0, SAMPLE_FLAGS_NON_SYNC);
+
+        if let Some(audio) = &self.audio {
+            let mut trak: Vec<u8> = Vec::new();
+            trak.extend_from_slice(&tkhd_box(AUDIO_TRACK_ID, 0, true, 0, 0));
+            trak.extend_from_slice(&mdia_box(audio.sample_rate, 0, true, &empty_stbl(&audio.entry)));
+            moov.extend_from_slice(&mp4_box(b"trak", &trak));
+
+            mvex.extend_from_slice(&trex_box(AUDIO_TRACK_ID, 1, audio.bytes_per_frame, SAMPLE_FLAGS_SYNC));
+        }
+
+        moov.extend_from_slice(&mp4_box(b"mvex", &mvex));
+        init.extend_from_slice(&mp4_box(b"moov", &moov));
+
+        match self.writer.write_all(&init) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.init_written = true;
+
+        Ok(())
+    }
+
+    // next_dts is the dts of the sample that starts the next fragment, it gives the last duration
+    fn flush_fragment(&mut self, next_dts: Option<u64>) -> Result<(), Error> {
+        if !self.init_written {
+            if !self.init_ready() {
+                return Ok(());
+            }
+
+            match self.write_init() {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        let video_samples = match &mut self.video {
+            Some(v) => std::mem::take(&mut v.samples),
+            None => Vec::new(),
+        };
+
+        let audio_data = match &mut self.audio {
+            Some(a) => std::mem::take(&mut a.data),
+            None => Vec::new(),
+        };
+
+        if video_samples.is_empty() && audio_data.is_empty() {
+            return Ok(());
+        }
+
+        self.sequence += 1;
+
+        // moof is built twice, the first pass only gives us its size for the trun data offsets
+        let moof_size = self.moof_box(&video_samples, &audio_data, next_dts, 0).len() as u32;
+        let moof = self.moof_box(&video_samples, &audio_data, next_dts, moof_size + 8);
+
+        let mut mdat_len = audio_data.len();
+        for s in &video_samples {
+            mdat_len += s.data.len();
+        }
+
+        match self.writer.write_all(&moof) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_all(&(mdat_len as u32 + 8).to_be_bytes()) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_all(b"mdat") {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        for s in &video_samples {
+            match self.writer.write_all(&s.data) {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        match self.writer.write_all(&audio_data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        // a fragment is only useful after an unplug if it actually hit the disk
+        match self.writer.flush() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        if let Some(v) = &mut self.video {
+            if v.first_dts.is_none() {
+                v.first_dts = video_samples.first().map(|s| s.dts);
+            }
+        }
+
+        if let Some(a) = &mut self.audio {
+            a.frames_written += (audio_data.len() as u32 / a.bytes_per_frame) as u64;
+        }
+
+        Ok(())
+    }
+
+    fn moof_box(
+        &self,
+        video_samples: &[FragmentSample],
+        audio_data: &[u8],
+        next_dts: Option<u64>,
+        data_offset: u32,
+    ) -> Vec<u8> {
+        let mut moof: Vec<u8> = full_box(b"mfhd", 0, 0, &self.sequence.to_be_bytes());
+        let mut offset = data_offset;
+
+        if let (Some(video), Some(first)) = (&self.video, video_samples.first()) {
+            let first_dts = video.first_dts.unwrap_or(first.dts);
+            let base = first.dts.saturating_sub(first_dts) + video.start_offset;
+
+            let mut trun: Vec<u8> = Vec::new();
+            trun.extend_from_slice(&(video_samples.len() as u32).to_be_bytes());
+            trun.extend_from_slice(&offset.to_be_bytes());
+
+            let mut last_duration: u64 = 1;
+            for (i, s) in video_samples.iter().enumerate() {
+                let next = video_samples.get(i + 1).map(|n| n.dts).or(next_dts);
+                let duration = match next {
+                    Some(n) if n > s.dts => n - s.dts,
+                    Some(_) => 1,
+                    None => s.duration.unwrap_or(last_duration),
+                };
+                last_duration = duration;
+
+                let flags = if s.key_frame { SAMPLE_FLAGS_SYNC } else { SAMPLE_FLAGS_NON_SYNC };
+
+                trun.extend_from_slice(&(duration.min(u32::MAX as u64) as u32).to_be_bytes());
+                trun.extend_from_slice(&(s.data.len() as u32).to_be_bytes());
+                trun.extend_from_slice(&flags.to_be_bytes());
+                trun.extend_from_slice(&((s.pts as i64 - s.dts as i64) as i32).to_be_bytes());
+
+                offset += s.data.len() as u32;
+            }
+
+            let mut traf: Vec<u8> = full_box(b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, &VIDEO_TRACK_ID.to_be_bytes());
+            traf.extend_from_slice(&full_box(b"tfdt", 1, 0, &base.to_be_bytes()));
+            traf.extend_from_slice(&full_box(
+                b"trun",
+                1,
+                TRUN_DATA_OFFSET
+                    | TRUN_SAMPLE_DURATION
+                    | TRUN_SAMPLE_SIZE
+                    | TRUN_SAMPLE_FLAGS
+                    | TRUN_SAMPLE_COMPOSITION_OFFSET,
+                &trun,
+            ));
+            moof.extend_from_slice(&mp4_box(b"traf", &traf));
+        }
+
+        if let Some(audio) = &self.audio {
+            if !audio_data.is_empty() {
+                let frames = audio_data.len() as u32 / audio.bytes_per_frame;
+                let base = audio.frames_written + audio.start_offset;
+
+                // every lpcm frame is a sample, sizes and durations come from the tfhd defaults
+                let mut tfhd: Vec<u8> = Vec::new();
+                tfhd.extend_from_slice(&AUDIO_TRACK_ID.to_be_bytes());
+                tfhd.extend_from_slice(&1u32.to_be_bytes());
+                tfhd.extend_from_slice(&audio.bytes_per_frame.to_be_bytes());
+                tfhd.extend_from_slice(&SAMPLE_FLAGS_SYNC.to_be_bytes());
+
+                let mut trun: Vec<u8> = Vec::new();
+                trun.extend_from_slice(&frames.to_be_bytes());
+                trun.extend_from_slice(&offset.to_be_bytes());
+
+                let mut traf: Vec<u8> = full_box(
+                    b"tfhd",
+                    0,
+                    TFHD_DEFAULT_BASE_IS_MOOF
+                        | TFHD_DEFAULT_SAMPLE_DURATION
+                        | TFHD_DEFAULT_SAMPLE_SIZE
+                        | TFHD_DEFAULT_SAMPLE_FLAGS,
+                    &tfhd,
+                );
+                traf.extend_from_slice(&full_box(b"tfdt", 1, 0, &base.to_be_bytes()));
+                traf.extend_from_slice(&full_box(b"trun", 0, TRUN_DATA_OFFSET, &trun));
+                moof.extend_from_slice(&mp4_box(b"traf", &traf));
+            }
+        }
+
+        mp4_box(b"moof", &moof)
+    }
+}
+
+impl SampleWriter for FragmentedMp4Writer {
+    fn write_sample(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        match sample.media_type() {
+            MEDIA_TYPE_VIDEO => self.write_video(sample),
+            MEDIA_TYPE_SOUND => self.write_audio(sample),
+            t => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown media type {:#x}", t),
+            )),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+
+        self.finished = true;
+
+        // short recordings may never have waited long enough for audio
+        if !self.init_written && self.video.is_some() {
+            match self.write_init() {
+                Err(e) => return Err(e),
+                _ => {}
+            };
+        }
+
+        self.flush_fragment(None)
+    }
+}
+
+impl Drop for FragmentedMp4Writer {
+    fn drop(&mut self) {
+        match self.finish() {
+            Err(e) => warn!("finish fragmented mp4: {}", e),
+            _ => {}
+        };
+    }
+}
+
+// sample tables stay empty, every sample lives in a fragment
+fn empty_stbl(entry: &[u8]) -> Vec<u8> {
+    let mut stbl: Vec<u8> = stsd_box(&[Vec::from(entry)]);
+    stbl.extend_from_slice(&table_box(b"stts", &[]));
+    stbl.extend_from_slice(&full_box(b"stsc", 0, 0, &0u32.to_be_bytes()));
+    stbl.extend_from_slice(&full_box(b"stsz", 0, 0, &[0; 8]));
+    stbl.extend_from_slice(&full_box(b"stco", 0, 0, &0u32.to_be_bytes()));
+    stbl
+}
+
+fn trex_box(track_id: u32, duration: u32, size: u32, flags: u32) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&track_id.to_be_bytes());
+    payload.extend_from_slice(&1u32.to_be_bytes()); // sample description index
+    payload.extend_from_slice(&duration.to_be_bytes());
+    payload.extend_from_slice(&size.to_be_bytes());
+    payload.extend_from_slice(&flags.to_be_bytes());
+    full_box(b"trex", 0, 0, &payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::tests::{avc1_format, boxes, find, temp_path, video_sample, words, FRAME};
+
+    #[test]
+    fn fragments_start_at_key_frames() {
+        let path = temp_path("fragments", "mp4");
+        let mut writer = FragmentedMp4Writer::create(path.as_str(), false).unwrap();
+
+        let mut first = video_sample(FRAME, 0, true);
+        first.set_format_description(avc1_format());
+        writer.write_sample(&first).unwrap();
+        writer.write_sample(&video_sample(3 * FRAME, FRAME, false)).unwrap();
+        writer.write_sample(&video_sample(3 * FRAME, 2 * FRAME, true)).unwrap();
+        writer.write_sample(&video_sample(4 * FRAME, 3 * FRAME, false)).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let file = std::fs::read(path.as_str()).unwrap();
+        let top = boxes(&file);
+        let kinds: Vec<&[u8; 4]> = top.iter().map(|(k, _)| k).collect();
+        assert_eq!(kinds, vec![b"ftyp", b"moov", b"moof", b"mdat", b"moof", b"mdat"]);
+
+        for (i, base, reordered) in [(2, 0u64, 2 * FRAME), (4, 2 * FRAME, FRAME)] {
+            let moof = top[i].1;
+            assert_eq!(words(find(moof, &[b"mfhd"])), vec![i as u32 / 2]);
+
+            let tfhd = find(moof, &[b"traf", b"tfhd"]);
+            assert_eq!(u32::from_be_bytes(tfhd[..4].try_into().unwrap()), TFHD_DEFAULT_BASE_IS_MOOF);
+            assert_eq!(words(tfhd), vec![VIDEO_TRACK_ID]);
+
+            let tfdt = find(moof, &[b"traf", b"tfdt"]);
+            assert_eq!(tfdt[0], 1);
+            assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), base);
+
+            // version 1 for signed composition offsets, data offset plus four fields per sample
+            let trun = find(moof, &[b"traf", b"trun"]);
+            assert_eq!(u32::from_be_bytes(trun[..4].try_into().unwrap()), 0x01000F01);
+            let moof_size = moof.len() as u32 + 8;
+            let fields = words(trun);
+            assert_eq!(&fields[..2], &[2, moof_size + 8]);
+            assert_eq!(&fields[2..], &[
+                FRAME as u32, 9, SAMPLE_FLAGS_SYNC, FRAME as u32,
+                FRAME as u32, 9, SAMPLE_FLAGS_NON_SYNC, reordered as u32,
+            ]);
+
+            // the offset points right at the first sample in the mdat
+            assert_eq!(&top[i + 1].1[..5], &[0, 0, 0, 5, 0x65]);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
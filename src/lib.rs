@@ -0,0 +1,30 @@
+pub mod apple;
+#[cfg(feature = "async")]
+pub mod async_mirror;
+mod bulk_reader;
+pub mod capture;
+pub mod coremedia;
+pub mod devices;
+pub mod error;
+pub mod fmp4;
+pub mod mirror;
+pub mod mp4;
+pub mod mpegts;
+mod packet_buffer;
+mod qt;
+mod qt_device;
+mod qt_pkt;
+mod qt_value;
+pub mod recorder;
+pub mod registry;
+pub mod session;
+pub mod sim_device;
+pub mod tcp_server;
+pub mod trace;
+pub mod transport;
+
+#[cfg(feature = "async")]
+pub use crate::async_mirror::{AsyncMirror, SampleStream};
+pub use crate::mirror::{Mirror, MirrorConfig, SampleReceiver, Samples};
+pub use crate::packet_buffer::PacketBytes;
+pub use crate::qt_device::{parse_audio_format, parse_display_size, AudioConfig, DisplayConfig};
@@ -1,49 +1,78 @@
-#![allow(dead_code)]
-
-extern crate core;
-
-mod apple;
-mod coremedia;
-mod qt;
-mod qt_device;
-mod qt_pkt;
-mod qt_value;
-mod tcp_server;
-
-use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
-use crate::qt::QuickTime;
-use crate::tcp_server::TcpServer;
-use rusty_libimobiledevice::error::IdeviceError;
-use rusty_libimobiledevice::idevice;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, SyncSender};
-use std::sync::{Arc, Mutex};
-use std::sync::atomic::AtomicBool;
-use std::{io, thread};
-
-fn get_apple_device() -> Result<idevice::Device, IdeviceError> {
-    let devices = match idevice::get_devices() {
-        Ok(d) => d,
-        Err(e) => return Err(e),
-    };
+#![allow(clippy::single_match)]
+
+use scrmiror::apple;
+use scrmiror::apple::{UsbLocation, UsbMatchError};
+use scrmiror::capture::{CaptureTransport, ReplayTransport};
+use scrmiror::coremedia::sample::{MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use scrmiror::devices;
+use scrmiror::fmp4::FragmentedMp4Writer;
+use scrmiror::mp4::Mp4Writer;
+use scrmiror::mpegts::{TsFileWriter, TsTcpWriter};
+use scrmiror::recorder::{Recorder, SampleWriter};
+use scrmiror::registry::Registry;
+use scrmiror::sim_device::SimulatedDevice;
+use scrmiror::tcp_server::TcpServer;
+use scrmiror::trace::{Trace, TraceFormat};
+use scrmiror::transport::Transport;
+use scrmiror::{parse_audio_format, parse_display_size, AudioConfig, DisplayConfig, Mirror, MirrorConfig};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+// the library reports through log, progress goes to stdout and warnings and the trace to stderr
+struct Console;
 
-    for device in devices {
-        if device.get_network() {
-            continue;
+impl Log for Console {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
         }
 
-        return Ok(device);
+        match record.level() <= Level::Warn || record.target() == "trace" {
+            true => eprintln!("{}", record.args()),
+            false => println!("{}", record.args()),
+        };
     }
 
-    return Err(IdeviceError::NoDevice);
+    fn flush(&self) {}
 }
 
+static CONSOLE: Console = Console;
+
 fn main() {
+    match log::set_logger(&CONSOLE) {
+        Ok(_) => log::set_max_level(LevelFilter::Info),
+        Err(e) => eprintln!("logger: {}", e),
+    };
+
     let args: Vec<String> = std::env::args().collect();
     let mut udid = None;
+    let mut usb_location = None;
     let mut port = Some(12345u16); // Default port
     let mut include_header = false;
     let mut no_audio = false;
+    let mut capture_path = None;
+    let mut replay_path = None;
+    let mut simulate = false;
+    let mut simulate_hevc = false;
+    let mut record_path = None;
+    let mut fragmented = false;
+    let mut mpeg_ts = false;
+    let mut display = DisplayConfig::default();
+    let mut audio_formats = Vec::new();
+    let mut list = false;
+    let mut json = false;
+    let mut all = false;
+    let mut registry_port = None;
+    let mut trace_format = None;
+    let mut trace_hex = false;
 
     // Parse command line arguments
     let mut i = 0;
@@ -53,6 +82,16 @@ fn main() {
                 udid = Some(args[i + 1].clone());
                 i += 1;
             }
+            "-usb" if i + 1 < args.len() => {
+                match UsbLocation::parse(args[i + 1].as_str()) {
+                    Some(l) => usb_location = Some(l),
+                    None => {
+                        println!("invalid usb location {}, expected BUS-PORT[.PORT...]", args[i + 1]);
+                        return;
+                    }
+                };
+                i += 1;
+            }
             "-p" if i + 1 < args.len() => {
                 port = args[i + 1].parse().ok();
                 i += 1;
@@ -63,109 +102,575 @@ fn main() {
             "-na" => {
                 no_audio = true;
             }
+            "-c" if i + 1 < args.len() => {
+                capture_path = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "-r" if i + 1 < args.len() => {
+                replay_path = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "-sim" => {
+                simulate = true;
+            }
+            "-sim-hevc" => {
+                simulate = true;
+                simulate_hevc = true;
+            }
+            "-o" if i + 1 < args.len() => {
+                record_path = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "-frag" => {
+                fragmented = true;
+            }
+            "-ts" => {
+                mpeg_ts = true;
+            }
+            "-size" if i + 1 < args.len() => {
+                match parse_display_size(args[i + 1].as_str()) {
+                    Some((w, h)) => display.set_size(w, h),
+                    None => {
+                        println!("invalid display size {}, expected WIDTHxHEIGHT", args[i + 1]);
+                        return;
+                    }
+                };
+                i += 1;
+            }
+            "-h264" => {
+                display.set_hevc(false);
+            }
+            "-hpd1" if i + 1 < args.len() => {
+                match args[i + 1].split_once('=') {
+                    Some((k, v)) => display.set_option(k, v),
+                    None => {
+                        println!("invalid hpd1 option {}, expected KEY=VALUE", args[i + 1]);
+                        return;
+                    }
+                };
+                i += 1;
+            }
+            "list" => {
+                list = true;
+            }
+            "-all" => {
+                all = true;
+            }
+            "-registry" if i + 1 < args.len() => {
+                registry_port = args[i + 1].parse::<u16>().ok();
+                i += 1;
+            }
+            "-json" => {
+                json = true;
+            }
+            "-trace" => {
+                trace_format = Some(TraceFormat::Text);
+            }
+            "-trace-json" => {
+                trace_format = Some(TraceFormat::Json);
+            }
+            "-trace-hex" => {
+                trace_hex = true;
+            }
+            "-afmt" if i + 1 < args.len() => {
+                match parse_audio_format(args[i + 1].as_str()) {
+                    Some(asd) => audio_formats.push(asd),
+                    None => {
+                        println!("invalid audio format {}, expected RATE:CHANNELS:s16le|s24le|s32le|f32le", args[i + 1]);
+                        return;
+                    }
+                };
+                i += 1;
+            }
             _ => {}
         }
         i += 1;
     }
 
-    let sn = if let Some(u) = udid {
-        u
-    } else {
-        println!("No udid specified, trying to find a device");
-
-        let device = match get_apple_device() {
-            Ok(d) => d,
+    if list {
+        let devices = match devices::list_devices() {
+            Ok(e) => e,
             Err(e) => {
-                println!("get_apple_device: {:?}", e);
+                println!("list devices: {}", e);
                 return;
             }
         };
 
-        let lockdownd = match device.new_lockdownd_client("qtstream") {
-            Ok(client) => client,
+        match json {
+            true => println!("{}", devices::to_json(&devices)),
+            false => println!("{}", devices::to_text(&devices)),
+        };
+        return;
+    }
+
+    // formats are offered in the order given, 48kHz stereo s16le if none
+    let audio = match audio_formats.is_empty() {
+        true => AudioConfig::default(),
+        false => AudioConfig::new(audio_formats),
+    };
+
+    let video_port = port.unwrap_or(12345);
+
+    // -trace-hex alone traces as text
+    let trace = match (trace_format, trace_hex) {
+        (Some(format), hex) => Some(Trace::new(format, hex)),
+        (None, true) => Some(Trace::new(TraceFormat::Text, true)),
+        (None, false) => None,
+    };
+
+    let mut config = MirrorConfig::new(display, audio);
+    config.set_no_audio(no_audio);
+    if let Some(trace) = trace {
+        config.set_trace(trace);
+    }
+
+    // a recording or the mpeg-ts server replace the raw h264/lpcm tcp outputs
+    let make_sink = |udid: Option<&str>, video_port: u16| {
+        create_sink(record_path.as_deref(), udid, fragmented, mpeg_ts, !no_audio, video_port)
+    };
+
+    if all {
+        // every device runs live from usb, there is no single capture, replay or simulation to use
+        if capture_path.is_some() || replay_path.is_some() || simulate {
+            println!("-all can't be combined with -c, -r or -sim");
+            return;
+        }
+
+        run_all(video_port, registry_port, include_header, config, make_sink);
+        return;
+    }
+
+    let sink = match make_sink(None, video_port) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if simulate {
+        run_simulation(simulate_hevc, config, sink);
+        return;
+    }
+
+    if let Some(path) = replay_path {
+        let replay = match ReplayTransport::open(path.as_str(), true) {
+            Ok(r) => r,
             Err(e) => {
-                println!("new_lockdownd_client: {:?}", e);
+                println!("open replay file: {}", e);
                 return;
             }
         };
 
-        match lockdownd.get_device_udid() {
-            Ok(sn) => sn,
+        run_session(replay, video_port, include_header, config, sink);
+        return;
+    }
+
+    // a usb location alone is enough, usbmuxd is only asked when there is nothing to go on
+    let sn = if udid.is_some() {
+        udid
+    } else if usb_location.is_some() {
+        None
+    } else {
+        println!("No udid specified, trying to find a device");
+
+        match devices::default_serial() {
+            Ok(sn) => Some(sn),
             Err(e) => {
-                println!("get_device_udid: {:?}", e);
+                println!("{}", e);
                 return;
             }
         }
     };
 
-    let usb_device = match apple::get_usb_device(sn.replace("-", "").as_str()) {
+    let usb_device = match apple::get_usb_device(sn.as_deref(), usb_location.as_ref()) {
         Ok(d) => d,
+        // not plugged in yet, or not usable yet, start the session once it shows up
+        Err(e @ UsbMatchError::NotFound(_)) if sn.is_some() => {
+            println!("{}", e);
+            match wait_usb_device(sn.as_deref().unwrap()) {
+                Some(d) => d,
+                None => return,
+            }
+        }
         Err(e) => {
-            println!("libusb: {:?}", e);
+            println!("{}", e);
             return;
         }
     };
 
-    let video_port = port.unwrap_or(12345);
-    let audio_port = video_port + 1;
+    match capture_path {
+        Some(path) => {
+            let capture = match CaptureTransport::new(usb_device, path.as_str()) {
+                Ok(c) => c,
+                Err(e) => {
+                    println!("create capture file: {}", e);
+                    return;
+                }
+            };
 
-    let video_addr = format!("0.0.0.0:{}", video_port);
-    let audio_addr = format!("0.0.0.0:{}", audio_port);
+            run_session(capture, video_port, include_header, config, sink);
+        }
+        None => run_session(usb_device, video_port, include_header, config, sink),
+    };
+}
 
-    let audio_connected = Arc::new(AtomicBool::new(false));
-    let audio_connected_clone = audio_connected.clone();
+// udid goes into the file name when several devices record at once
+fn create_sink(
+    record_path: Option<&str>,
+    udid: Option<&str>,
+    fragmented: bool,
+    mpeg_ts: bool,
+    audio: bool,
+    video_port: u16,
+) -> Result<Option<Box<dyn SampleWriter + Send>>, String> {
+    if let Some(path) = record_path {
+        let path = match udid {
+            Some(udid) => device_path(path, udid),
+            None => String::from(path),
+        };
 
-    let (video_tx, video_rx): (
-        SyncSender<Result<SampleBuffer, io::Error>>,
-        Receiver<Result<SampleBuffer, io::Error>>,
-    ) = mpsc::sync_channel(256);
+        return match create_recording(path.as_str(), fragmented, audio) {
+            Ok(w) => {
+                println!("recording to {}", path);
+                Ok(Some(w))
+            }
+            Err(e) => Err(format!("create recording {}: {}", path, e)),
+        };
+    }
 
-    let (audio_tx, audio_rx): (
-        SyncSender<Result<SampleBuffer, io::Error>>,
-        Receiver<Result<SampleBuffer, io::Error>>,
-    ) = mpsc::sync_channel(256);
+    if mpeg_ts {
+        return match TsTcpWriter::bind(format!("0.0.0.0:{}", video_port).as_str()) {
+            Ok(w) => Ok(Some(Box::new(w))),
+            Err(e) => Err(format!("mpeg-ts server: {}", e)),
+        };
+    }
 
-    let mut qt = QuickTime::new(usb_device, video_tx, audio_tx, no_audio, audio_connected.clone());
+    Ok(None)
+}
 
-    let video_server = TcpServer::new(video_addr, video_rx, MEDIA_TYPE_VIDEO, Some(include_header), None);
-    let audio_server = TcpServer::new(audio_addr, audio_rx, MEDIA_TYPE_SOUND, None, Some(audio_connected_clone));
+// record.mp4 -> record-<udid>.mp4
+fn device_path(path: &str, udid: &str) -> String {
+    let name_start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match path[name_start..].rfind('.') {
+        Some(i) => format!("{}-{}{}", &path[..name_start + i], udid, &path[name_start + i..]),
+        None => format!("{}-{}", path, udid),
+    }
+}
 
-    match qt.init() {
+// one session per attached device, each on its own port pair counting up from video_port
+fn run_all<F>(
+    video_port: u16,
+    registry_port: Option<u16>,
+    include_header: bool,
+    config: MirrorConfig,
+    make_sink: F,
+) where
+    F: Fn(Option<&str>, u16) -> Result<Option<Box<dyn SampleWriter + Send>>, String>,
+{
+    let mut devices = match devices::list_devices() {
+        Ok(e) => e,
+        Err(e) => {
+            println!("list devices: {}", e);
+            return;
+        }
+    };
+
+    // stable port assignment across runs
+    devices.sort_by(|a, b| a.udid().cmp(b.udid()));
+
+    let registry = Arc::new(Registry::new());
+
+    let term = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        match signal_hook::flag::register(sig, term.clone()) {
+            Err(e) => {
+                println!("register signal {} failed {}", sig, e);
+                return;
+            }
+            _ => {}
+        };
+    }
+
+    let server = match registry_port {
+        Some(port) => match Registry::serve(registry.clone(), format!("0.0.0.0:{}", port), term.clone()) {
+            Ok(e) => Some(e),
+            Err(e) => {
+                println!("registry server: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut sessions: Vec<thread::JoinHandle<()>> = Vec::new();
+
+    for (i, d) in devices.iter().filter(|d| d.usb().is_some()).enumerate() {
+        let udid = String::from(d.udid());
+
+        // video and audio port of the device, both have to fit in u16
+        let ports = u16::try_from(2 * i)
+            .ok()
+            .and_then(|offset| video_port.checked_add(offset))
+            .and_then(|video| video.checked_add(1).map(|audio| (video, audio)));
+        let (device_video_port, device_audio_port) = match ports {
+            Some(e) => e,
+            None => {
+                println!("{}: no free port pair above {}, skipped", udid, video_port);
+                continue;
+            }
+        };
+
+        registry.add(udid.as_str(), d.name(), device_video_port, device_audio_port);
+
+        let sink = match make_sink(Some(udid.as_str()), device_video_port) {
+            Ok(e) => e,
+            Err(e) => {
+                registry.set_state(udid.as_str(), format!("failed: {}", e).as_str());
+                continue;
+            }
+        };
+
+        let usb_device = match devices::open_device(udid.as_str()) {
+            Ok(e) => e,
+            Err(e) => {
+                registry.set_state(udid.as_str(), format!("failed: {}", e).as_str());
+                continue;
+            }
+        };
+
+        let registry = registry.clone();
+        let config = config.clone();
+
+        registry.set_state(udid.as_str(), "running");
+
+        sessions.push(thread::spawn(move || {
+            run_session(usb_device, device_video_port, include_header, config, sink);
+            registry.set_state(udid.as_str(), "stopped");
+        }));
+    }
+
+    println!("{}", registry.to_text());
+
+    if sessions.is_empty() {
+        println!("no device to stream");
+    }
+
+    for session in sessions {
+        session.join().expect("session thread term");
+    }
+
+    term.store(true, Ordering::SeqCst);
+
+    if let Some(server) = server {
+        server.join().expect("registry thread term");
+    }
+}
+
+fn create_recording(
+    path: &str,
+    fragmented: bool,
+    audio: bool,
+) -> Result<Box<dyn SampleWriter + Send>, io::Error> {
+    if path.ends_with(".ts") {
+        return match TsFileWriter::create(path) {
+            Ok(w) => Ok(Box::new(w)),
+            Err(e) => Err(e),
+        };
+    }
+
+    if fragmented {
+        return match FragmentedMp4Writer::create(path, audio) {
+            Ok(w) => Ok(Box::new(w)),
+            Err(e) => Err(e),
+        };
+    }
+
+    match Mp4Writer::create(path) {
+        Ok(w) => Ok(Box::new(w)),
+        Err(e) => Err(e),
+    }
+}
+
+fn wait_usb_device(sn: &str) -> Option<apple::AppleDevice> {
+    let term = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        match signal_hook::flag::register(sig, term.clone()) {
+            Err(e) => {
+                println!("register signal {} failed {}", sig, e);
+                return None;
+            }
+            _ => {}
+        };
+    }
+
+    println!("waiting for {}", sn);
+
+    match apple::wait_for_device(sn, &term) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("libusb: {:?}", e);
+            None
+        }
+    }
+}
+
+fn run_session<T: Transport + Send + 'static>(
+    device: T,
+    video_port: u16,
+    include_header: bool,
+    mut config: MirrorConfig,
+    sink: Option<Box<dyn SampleWriter + Send>>,
+) {
+    // SIGINT/SIGTERM only flip term, qt sends HPA0/HPD0 once its loop sees it
+    let term = Arc::new(AtomicBool::new(false));
+    for sig in [SIGINT, SIGTERM] {
+        match signal_hook::flag::register(sig, term.clone()) {
+            Err(e) => {
+                println!("register signal {} failed {}", sig, e);
+                return;
+            }
+            _ => {}
+        };
+    }
+
+    config.set_term(term.clone());
+    // a sink takes audio from the start, tcp only once a client connects
+    config.set_audio_on_demand(sink.is_none());
+
+    // a recording is written here as the samples come in, tcp clients are served from threads
+    let writer = match sink {
+        Some(writer) => writer,
+        None => return serve_tcp(device, config, video_port, include_header, term),
+    };
+
+    let mut mirror = match Mirror::start(device, config) {
+        Ok(e) => e,
         Err(e) => {
             println!("init qt failed {}", e);
             return;
         }
+    };
+
+    Recorder::new(writer).run(mirror.samples());
+
+    match mirror.wait() {
+        Err(e) => println!("qt loop exit: {}", e),
         _ => {}
-    }
+    };
+}
+
+fn serve_tcp<T: Transport + Send + 'static>(
+    device: T,
+    config: MirrorConfig,
+    video_port: u16,
+    include_header: bool,
+    term: Arc<AtomicBool>,
+) {
+    let no_audio = config.no_audio();
+    let video_addr = format!("0.0.0.0:{}", video_port);
+    let audio_addr = format!("0.0.0.0:{}", video_port + 1);
+
+    let (mut mirror, video_rx, audio_rx) = match Mirror::start_split(device, config) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("init qt failed {}", e);
+            return;
+        }
+    };
+
+    let video_server = TcpServer::new(video_addr, video_rx, MEDIA_TYPE_VIDEO, Some(include_header), None, term.clone());
+    let audio_server = TcpServer::new(audio_addr, audio_rx, MEDIA_TYPE_SOUND, None, Some(mirror.audio_connected()), term.clone());
+
+    let mut servers: Vec<thread::JoinHandle<()>> = Vec::new();
 
-    let vt = thread::spawn(move || {
+    servers.push(thread::spawn(move || {
         video_server.run();
-    });
+    }));
 
-    let at = thread::spawn(move || {
+    servers.push(thread::spawn(move || {
         if !no_audio {
             audio_server.run();
         }
-    });
+    }));
 
-    let qt = Arc::new(Mutex::new(qt));
-    let qt_clone = Arc::clone(&qt);
+    match mirror.wait() {
+        Err(e) => println!("qt loop exit: {}", e),
+        _ => {}
+    };
 
-    let qtt = thread::spawn(move || {
-        let mut qt = qt.lock().unwrap();
-        match qt.run() {
-            Err(e) => {
-                println!("qt loop exit: {}", e)
-            }
-            _ => {}
+    // qt may have exited on its own, make sure the servers stop too
+    term.store(true, Ordering::SeqCst);
+
+    for server in servers {
+        server.join().expect("server thread term");
+    }
+}
+
+fn run_simulation(hevc: bool, mut config: MirrorConfig, sink: Option<Box<dyn SampleWriter + Send>>) {
+    let device = match SimulatedDevice::new(30, !config.no_audio(), hevc) {
+        Ok(d) => d,
+        Err(e) => {
+            println!("simulated device: {}", e);
+            return;
         }
-    });
+    };
 
-    at.join().expect("audio thread term");
+    let report = device.report();
 
-    vt.join().expect("video thread term");
+    config.set_term(device.term());
 
-    qtt.join().expect("qt thread term");
+    match sink {
+        Some(writer) => {
+            let mut mirror = match Mirror::start(device, config) {
+                Ok(e) => e,
+                Err(e) => {
+                    println!("init qt failed {}", e);
+                    return;
+                }
+            };
 
-    drop(qt_clone.lock().unwrap());
+            Recorder::new(writer).run(mirror.samples());
+
+            match mirror.wait() {
+                Err(e) => println!("qt loop exit: {}", e),
+                _ => {}
+            };
+        }
+        None => {
+            let mut mirror = match Mirror::start(device, config) {
+                Ok(e) => e,
+                Err(e) => {
+                    println!("init qt failed {}", e);
+                    return;
+                }
+            };
+
+            let mut video = 0;
+            let mut audio = 0;
+            for sample in mirror.samples() {
+                match sample.media_type() {
+                    MEDIA_TYPE_VIDEO => video += 1,
+                    _ => audio += 1,
+                };
+            }
+
+            match mirror.wait() {
+                Err(e) => println!("qt loop exit: {}", e),
+                _ => {}
+            };
+
+            println!("simulation received {} video and {} audio samples", video, audio);
+        }
+    };
+
+    let report = report.lock().unwrap();
+    for e in report.errors() {
+        println!("simulation error: {}", e);
+    }
+
+    match report.passed() {
+        true => println!("simulation passed, {} host packets", report.received().len()),
+        false => println!("simulation failed"),
+    };
 }
@@ -0,0 +1,275 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use crate::coremedia::sample::SampleBuffer;
+use crate::error::QTError;
+use crate::qt::{QuickTime, SampleSender};
+use crate::qt_device::{AudioConfig, DisplayConfig};
+use crate::session::{Phase, SessionWatch};
+use crate::trace::Trace;
+use crate::transport::Transport;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+// samples queued before the usb thread waits for the consumer
+pub const SAMPLE_QUEUE: usize = 256;
+
+pub type SampleReceiver = Receiver<Result<SampleBuffer, QTError>>;
+
+#[derive(Clone, Default)]
+pub struct MirrorConfig {
+    no_audio: bool,
+    display: DisplayConfig,
+    audio: AudioConfig,
+    // audio is dropped until audio_connected is set, for outputs that only want it with a client
+    audio_on_demand: bool,
+    trace: Option<Trace>,
+    // shared with the caller, e.g. set from a signal handler
+    term: Option<Arc<AtomicBool>>,
+}
+
+impl MirrorConfig {
+    pub fn new(display: DisplayConfig, audio: AudioConfig) -> MirrorConfig {
+        MirrorConfig {
+            display,
+            audio,
+            ..Default::default()
+        }
+    }
+
+    pub fn no_audio(&self) -> bool {
+        self.no_audio
+    }
+
+    pub fn display(&self) -> &DisplayConfig {
+        &self.display
+    }
+
+    pub fn audio(&self) -> &AudioConfig {
+        &self.audio
+    }
+
+    pub fn audio_on_demand(&self) -> bool {
+        self.audio_on_demand
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    pub fn term(&self) -> Option<&Arc<AtomicBool>> {
+        self.term.as_ref()
+    }
+
+    pub fn set_no_audio(&mut self, no_audio: bool) {
+        self.no_audio = no_audio;
+    }
+
+    pub fn set_display(&mut self, display: DisplayConfig) {
+        self.display = display;
+    }
+
+    pub fn set_audio(&mut self, audio: AudioConfig) {
+        self.audio = audio;
+    }
+
+    pub fn set_audio_on_demand(&mut self, on_demand: bool) {
+        self.audio_on_demand = on_demand;
+    }
+
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    pub fn set_term(&mut self, term: Arc<AtomicBool>) {
+        self.term = Some(term);
+    }
+}
+
+// a running screen capture. the protocol runs on its own thread until stop, or until the device
+// ends the session, and comes back by itself after an unplug
+pub struct Mirror {
+    term: Arc<AtomicBool>,
+    audio_connected: Arc<AtomicBool>,
+    watch: SessionWatch,
+    // video and audio in the order the device sent them, None when started split
+    samples: Option<SampleReceiver>,
+    qt: Option<thread::JoinHandle<Result<(), QTError>>>,
+}
+
+pub struct Samples<'a> {
+    mirror: &'a Mirror,
+}
+
+impl<'a> Iterator for Samples<'a> {
+    type Item = SampleBuffer;
+
+    fn next(&mut self) -> Option<SampleBuffer> {
+        self.mirror.recv()
+    }
+}
+
+impl Mirror {
+    // video and audio come out of samples() or recv()
+    pub fn start<T: Transport + Send + 'static>(device: T, config: MirrorConfig) -> Result<Mirror, QTError> {
+        let (tx, rx) = mpsc::sync_channel(SAMPLE_QUEUE);
+
+        Mirror::spawn(device, config, tx.clone(), tx, Some(rx))
+    }
+
+    // video and audio on their own receivers, for outputs that handle them apart
+    pub fn start_split<T: Transport + Send + 'static>(
+        device: T,
+        config: MirrorConfig,
+    ) -> Result<(Mirror, SampleReceiver, SampleReceiver), QTError> {
+        let (video_tx, video_rx) = mpsc::sync_channel(SAMPLE_QUEUE);
+        let (audio_tx, audio_rx) = mpsc::sync_channel(SAMPLE_QUEUE);
+
+        match Mirror::spawn(device, config, video_tx, audio_tx, None) {
+            Ok(e) => Ok((e, video_rx, audio_rx)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn spawn<T: Transport + Send + 'static>(
+        device: T,
+        config: MirrorConfig,
+        video_tx: SyncSender<Result<SampleBuffer, QTError>>,
+        audio_tx: SyncSender<Result<SampleBuffer, QTError>>,
+        samples: Option<SampleReceiver>,
+    ) -> Result<Mirror, QTError> {
+        let qt = match init_qt(device, config, Box::new(video_tx), Box::new(audio_tx)) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(Mirror {
+            term: qt.term().clone(),
+            audio_connected: qt.audio_connected().clone(),
+            watch: qt.session_watch(),
+            samples,
+            qt: Some(thread::spawn(move || run_qt(qt))),
+        })
+    }
+
+    pub fn term(&self) -> Arc<AtomicBool> {
+        self.term.clone()
+    }
+
+    pub fn audio_connected(&self) -> Arc<AtomicBool> {
+        self.audio_connected.clone()
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.watch.phase()
+    }
+
+    // blocks for the next sample, None once the session is over
+    pub fn recv(&self) -> Option<SampleBuffer> {
+        let rx = match &self.samples {
+            Some(e) => e,
+            None => return None,
+        };
+
+        loop {
+            match rx.recv() {
+                Ok(Ok(sample)) => return Some(sample),
+                Ok(Err(e)) => warn!("sample error: {}", e),
+                Err(_) => return None,
+            };
+        }
+    }
+
+    pub fn samples(&self) -> Samples<'_> {
+        Samples { mirror: self }
+    }
+
+    // waits for the session to end on its own, the error that ended it if any
+    pub fn wait(&mut self) -> Result<(), QTError> {
+        let qt = match self.qt.take() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        match qt.join() {
+            Ok(e) => e,
+            Err(_) => Err(QTError::Transport(String::from("qt thread panicked"))),
+        }
+    }
+
+    // sends HPA0/HPD0 and waits for the device to let go
+    pub fn stop(mut self) -> Result<(), QTError> {
+        self.term.store(true, Ordering::SeqCst);
+
+        // qt may be waiting for room in the queue, keep it moving until it drops the senders
+        if let Some(rx) = &self.samples {
+            for _ in rx.iter() {}
+        }
+
+        self.wait()
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        if self.qt.is_none() {
+            return;
+        }
+
+        self.term.store(true, Ordering::SeqCst);
+
+        // the receivers go first so a qt blocked on a full queue fails its send and ends
+        self.samples = None;
+
+        match self.wait() {
+            Err(e) => warn!("qt loop exit: {}", e),
+            _ => {}
+        };
+    }
+}
+
+// the device is ready to start the protocol once this returns
+pub(crate) fn init_qt<T: Transport>(
+    device: T,
+    config: MirrorConfig,
+    video_tx: Box<dyn SampleSender>,
+    audio_tx: Box<dyn SampleSender>,
+) -> Result<QuickTime<T>, QTError> {
+    let mut qt = QuickTime::new(device, video_tx, audio_tx, &config);
+
+    match qt.init() {
+        Err(e) => return Err(e),
+        _ => {}
+    }
+
+    Ok(qt)
+}
+
+// runs on its own thread until the session is over
+pub(crate) fn run_qt<T: Transport>(mut qt: QuickTime<T>) -> Result<(), QTError> {
+    let result = loop {
+        let e = match qt.run() {
+            Ok(_) => break Ok(()),
+            Err(e) => e,
+        };
+
+        // unplugged or rebooted, the outputs stay up and viewers only see a gap
+        if !e.is_disconnect() {
+            break Err(e);
+        }
+
+        warn!("qt loop exit: {}", e);
+
+        if !qt.reconnect() {
+            break Err(e);
+        }
+
+        info!("device is back, new session");
+    };
+
+    qt.stop();
+    result
+}
@@ -0,0 +1,841 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use crate::coremedia::audio_desc::{AudioStreamDescription, AUDIO_FORMAT_ID_LPCM};
+use crate::coremedia::format_desc::{FormatDescriptor, VideoCodec};
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use crate::recorder::SampleWriter;
+use log::warn;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Seek, SeekFrom, Write};
+
+pub const MOVIE_TIME_SCALE: u32 = 1000;
+pub const VIDEO_TIME_SCALE: u32 = 90000;
+pub const VIDEO_TRACK_ID: u32 = 1;
+pub const AUDIO_TRACK_ID: u32 = 2;
+
+// 64 bit mdat header, size is patched in finish
+const MDAT_HEADER_SIZE: u64 = 16;
+
+pub fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() + 8);
+    buffer.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    buffer.extend_from_slice(kind);
+    buffer.extend_from_slice(payload);
+    buffer
+}
+
+pub fn full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() + 4);
+    buffer.extend_from_slice(&(((version as u32) << 24) | (flags & 0xFFFFFF)).to_be_bytes());
+    buffer.extend_from_slice(payload);
+    mp4_box(kind, &buffer)
+}
+
+pub fn ftyp_box(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(major);
+    payload.extend_from_slice(&0x200u32.to_be_bytes());
+    for brand in compatible {
+        payload.extend_from_slice(*brand);
+    }
+    mp4_box(b"ftyp", &payload)
+}
+
+pub fn rescale(value: u64, scale: u32, new_scale: u32) -> u64 {
+    if scale == 0 {
+        return 0;
+    }
+    (value as u128 * new_scale as u128 / scale as u128) as u64
+}
+
+// pts, dts and duration in VIDEO_TIME_SCALE, dts falls back to pts when the device leaves it invalid
+pub fn video_sample_times(sample: &SampleBuffer) -> Option<(u64, u64, Option<u64>)> {
+    let sti = sample
+        .sample_timing_info_array()
+        .and_then(|arr| arr.first());
+
+    let pts = match sti {
+        Some(sti) if sti.presentation_time_stamp().is_valid() => sti.presentation_time_stamp().clone(),
+        _ => match sample.output_presentation_time_stamp() {
+            Some(t) if t.is_valid() => t,
+            _ => return None,
+        },
+    };
+
+    let pts_value = rescale(pts.value(), pts.scale(), VIDEO_TIME_SCALE);
+
+    let dts_value = match sti {
+        Some(sti) if sti.decode_time_stamp().is_valid() => {
+            let dts = sti.decode_time_stamp();
+            rescale(dts.value(), dts.scale(), VIDEO_TIME_SCALE)
+        }
+        _ => pts_value,
+    };
+
+    let duration = match sti {
+        Some(sti) if sti.duration().is_valid() => {
+            let d = sti.duration();
+            Some(rescale(d.value(), d.scale(), VIDEO_TIME_SCALE))
+        }
+        _ => None,
+    };
+
+    Some((pts_value, dts_value, duration))
+}
+
+// LPCM sample entry fourcc, mp4 has no generic lpcm so we use the QuickTime ones ffmpeg reads
+pub fn lpcm_fourcc(asd: &AudioStreamDescription) -> Result<&'static [u8; 4], Error> {
+    if asd.format_id() != AUDIO_FORMAT_ID_LPCM {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported audio format {:#x}", asd.format_id()),
+        ));
+    }
+
+    match (asd.is_float(), asd.bits_per_channel(), asd.is_big_endian()) {
+        (false, 16, false) => Ok(b"sowt"),
+        (false, 16, true) => Ok(b"twos"),
+        (false, 24, false) => Ok(b"in24"),
+        (false, 32, false) => Ok(b"in32"),
+        (true, 32, false) => Ok(b"fl32"),
+        (_, bits, _) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported lpcm {} bits flags {:#x}", bits, asd.format_flags()),
+        )),
+    }
+}
+
+// avc1/avcC or hvc1/hvcC, whichever the device streams
+pub fn video_sample_entry(fd: &FormatDescriptor) -> Result<Vec<u8>, Error> {
+    let (kind, config_kind, config) = match fd.video_codec() {
+        Some(VideoCodec::Avc1(avc1)) => (b"avc1", b"avcC", avc1.as_vec()),
+        Some(VideoCodec::Hvc1(hvc1)) => (b"hvc1", b"hvcC", hvc1.as_vec()),
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported video codec {:#x}", fd.codec()),
+            ))
+        }
+    };
+
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&[0; 6]);
+    payload.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+    payload.extend_from_slice(&[0; 16]);
+    payload.extend_from_slice(&(fd.video_dimension_width() as u16).to_be_bytes());
+    payload.extend_from_slice(&(fd.video_dimension_height() as u16).to_be_bytes());
+    payload.extend_from_slice(&0x00480000u32.to_be_bytes()); // 72 dpi
+    payload.extend_from_slice(&0x00480000u32.to_be_bytes());
+    payload.extend_from_slice(&0u32.to_be_bytes());
+    payload.extend_from_slice(&1u16.to_be_bytes()); // frame count
+    payload.extend_from_slice(&[0; 32]); // compressor name
+    payload.extend_from_slice(&0x18u16.to_be_bytes());
+    payload.extend_from_slice(&0xFFFFu16.to_be_bytes());
+    payload.extend_from_slice(&mp4_box(config_kind, &config));
+
+    Ok(mp4_box(kind, &payload))
+}
+
+pub fn lpcm_sample_entry(asd: &AudioStreamDescription) -> Result<Vec<u8>, Error> {
+    let fourcc = match lpcm_fourcc(asd) {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    // QuickTime sound description version 0
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&[0; 6]);
+    payload.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+    payload.extend_from_slice(&[0; 8]);
+    payload.extend_from_slice(&(asd.channels_per_frame() as u16).to_be_bytes());
+    payload.extend_from_slice(&(asd.bits_per_channel() as u16).to_be_bytes());
+    payload.extend_from_slice(&[0; 4]);
+    payload.extend_from_slice(&((asd.sample_rate() as u32) << 16).to_be_bytes());
+
+    Ok(mp4_box(fourcc, &payload))
+}
+
+pub fn tkhd_box(track_id: u32, duration: u64, audio: bool, width: u32, height: u32) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&0u64.to_be_bytes()); // creation time
+    payload.extend_from_slice(&0u64.to_be_bytes()); // modification time
+    payload.extend_from_slice(&track_id.to_be_bytes());
+    payload.extend_from_slice(&0u32.to_be_bytes());
+    payload.extend_from_slice(&duration.to_be_bytes());
+    payload.extend_from_slice(&[0; 8]);
+    payload.extend_from_slice(&0u16.to_be_bytes()); // layer
+    payload.extend_from_slice(&0u16.to_be_bytes()); // alternate group
+    payload.extend_from_slice(&(if audio { 0x0100u16 } else { 0 }).to_be_bytes());
+    payload.extend_from_slice(&0u16.to_be_bytes());
+    payload.extend_from_slice(&unity_matrix());
+    payload.extend_from_slice(&(width << 16).to_be_bytes());
+    payload.extend_from_slice(&(height << 16).to_be_bytes());
+
+    // enabled | in movie
+    full_box(b"tkhd", 1, 0x3, &payload)
+}
+
+pub fn mvhd_box(duration: u64, next_track_id: u32) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&0u64.to_be_bytes());
+    payload.extend_from_slice(&0u64.to_be_bytes());
+    payload.extend_from_slice(&MOVIE_TIME_SCALE.to_be_bytes());
+    payload.extend_from_slice(&duration.to_be_bytes());
+    payload.extend_from_slice(&0x00010000u32.to_be_bytes()); // rate 1.0
+    payload.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+    payload.extend_from_slice(&[0; 10]);
+    payload.extend_from_slice(&unity_matrix());
+    payload.extend_from_slice(&[0; 24]);
+    payload.extend_from_slice(&next_track_id.to_be_bytes());
+
+    full_box(b"mvhd", 1, 0, &payload)
+}
+
+pub fn mdia_box(time_scale: u32, duration: u64, audio: bool, stbl: &[u8]) -> Vec<u8> {
+    let mut mdhd: Vec<u8> = Vec::new();
+    mdhd.extend_from_slice(&0u64.to_be_bytes());
+    mdhd.extend_from_slice(&0u64.to_be_bytes());
+    mdhd.extend_from_slice(&time_scale.to_be_bytes());
+    mdhd.extend_from_slice(&duration.to_be_bytes());
+    mdhd.extend_from_slice(&0x55C4u16.to_be_bytes()); // und
+    mdhd.extend_from_slice(&0u16.to_be_bytes());
+
+    let mut hdlr: Vec<u8> = Vec::new();
+    hdlr.extend_from_slice(&0u32.to_be_bytes());
+    hdlr.extend_from_slice(if audio { b"soun" } else { b"vide" });
+    hdlr.extend_from_slice(&[0; 12]);
+    hdlr.extend_from_slice(if audio { b"SoundHandler\0" } else { b"VideoHandler\0" });
+
+    let media_header = if audio {
+        full_box(b"smhd", 0, 0, &[0; 4])
+    } else {
+        full_box(b"vmhd", 0, 1, &[0; 8])
+    };
+
+    let mut dref: Vec<u8> = Vec::new();
+    dref.extend_from_slice(&1u32.to_be_bytes());
+    // self contained
+    dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+
+    let mut minf: Vec<u8> = Vec::new();
+    minf.extend_from_slice(&media_header);
+    minf.extend_from_slice(&mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref)));
+    minf.extend_from_slice(&mp4_box(b"stbl", stbl));
+
+    let mut mdia: Vec<u8> = Vec::new();
+    mdia.extend_from_slice(&full_box(b"mdhd", 1, 0, &mdhd));
+    mdia.extend_from_slice(&full_box(b"hdlr", 0, 0, &hdlr));
+    mdia.extend_from_slice(&mp4_box(b"minf", &minf));
+
+    mp4_box(b"mdia", &mdia)
+}
+
+pub fn stsd_box(entries: &[Vec<u8>]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        payload.extend_from_slice(entry);
+    }
+    full_box(b"stsd", 0, 0, &payload)
+}
+
+fn unity_matrix() -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::new();
+    for v in [0x00010000u32, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000] {
+        buffer.extend_from_slice(&v.to_be_bytes());
+    }
+    buffer
+}
+
+// run length encode (count, value) pairs, used by stts, ctts and stsc
+fn run_length(values: &[u32]) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for v in values {
+        match runs.last_mut() {
+            Some((count, last)) if last == v => *count += 1,
+            _ => runs.push((1, *v)),
+        }
+    }
+    runs
+}
+
+pub fn table_box(kind: &[u8; 4], rows: &[(u32, u32)]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&(rows.len() as u32).to_be_bytes());
+    for (a, b) in rows {
+        payload.extend_from_slice(&a.to_be_bytes());
+        payload.extend_from_slice(&b.to_be_bytes());
+    }
+    full_box(kind, 0, 0, &payload)
+}
+
+// one empty edit so the track starts `delay` movie ticks after the movie
+fn edts_box(delay: u64, duration: u64, media_time: u64) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    let entries: u32 = if delay > 0 { 2 } else { 1 };
+    payload.extend_from_slice(&entries.to_be_bytes());
+
+    if delay > 0 {
+        payload.extend_from_slice(&delay.to_be_bytes());
+        payload.extend_from_slice(&(-1i64).to_be_bytes());
+        payload.extend_from_slice(&0x00010000u32.to_be_bytes());
+    }
+
+    payload.extend_from_slice(&duration.to_be_bytes());
+    payload.extend_from_slice(&media_time.to_be_bytes());
+    payload.extend_from_slice(&0x00010000u32.to_be_bytes());
+
+    mp4_box(b"edts", &full_box(b"elst", 1, 0, &payload))
+}
+
+struct VideoSample {
+    offset: u64,
+    size: u32,
+    pts: u64,
+    dts: u64,
+    duration: Option<u64>,
+    key_frame: bool,
+    // 1 based stsd index
+    description: u32,
+}
+
+struct VideoTrack {
+    entries: Vec<Vec<u8>>,
+    width: u32,
+    height: u32,
+    samples: Vec<VideoSample>,
+}
+
+struct AudioChunk {
+    offset: u64,
+    frames: u32,
+}
+
+struct AudioTrack {
+    entry: Vec<u8>,
+    sample_rate: u32,
+    bytes_per_frame: u32,
+    chunks: Vec<AudioChunk>,
+    // pts of the first EAT! sample in VIDEO_TIME_SCALE, on the device clock like the video pts
+    first_pts: u64,
+}
+
+pub struct Mp4Writer {
+    writer: BufWriter<File>,
+    pos: u64,
+    mdat_start: u64,
+    video: Option<VideoTrack>,
+    audio: Option<AudioTrack>,
+    finished: bool,
+}
+
+impl Mp4Writer {
+    pub fn create(path: &str) -> Result<Mp4Writer, Error> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(e),
+        };
+
+        let mut writer = BufWriter::new(file);
+
+        let ftyp = ftyp_box(b"isom", &[b"isom", b"iso2", b"avc1", b"mp41"]);
+        match writer.write_all(&ftyp) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let mdat_start = ftyp.len() as u64;
+
+        // size 1 means the real size is the u64 after the type
+        let mut mdat: Vec<u8> = Vec::new();
+        mdat.extend_from_slice(&1u32.to_be_bytes());
+        mdat.extend_from_slice(b"mdat");
+        mdat.extend_from_slice(&MDAT_HEADER_SIZE.to_be_bytes());
+        match writer.write_all(&mdat) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        Ok(Mp4Writer {
+            writer,
+            pos: mdat_start + MDAT_HEADER_SIZE,
+            mdat_start,
+            video: None,
+            audio: None,
+            finished: false,
+        })
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<u64, Error> {
+        let offset = self.pos;
+
+        match self.writer.write_all(data) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.pos += data.len() as u64;
+
+        Ok(offset)
+    }
+
+    fn write_video(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if let Some(fd) = sample.format_description() {
+            let entry = match video_sample_entry(fd) {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            match &mut self.video {
+                Some(track) => {
+                    // rotation and resolution changes add a new sample description
+                    if track.entries.last() != Some(&entry) {
+                        track.entries.push(entry);
+                    }
+                }
+                None => {
+                    self.video = Some(VideoTrack {
+                        entries: Vec::from([entry]),
+                        width: fd.video_dimension_width(),
+                        height: fd.video_dimension_height(),
+                        samples: Vec::new(),
+                    })
+                }
+            };
+        }
+
+        // nothing is decodable before the first SPS/PPS
+        if self.video.is_none() {
+            return Ok(());
+        }
+
+        let (pts, dts, duration) = match video_sample_times(sample) {
+            Some(e) => e,
+            None => return Err(Error::new(ErrorKind::InvalidData, "video sample without timing")),
+        };
+
+        let offset = match self.write_data(data) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let track = self.video.as_mut().unwrap();
+        let description = track.entries.len() as u32;
+        let key_frame = sample.is_sync();
+        track.samples.push(VideoSample {
+            offset,
+            size: data.len() as u32,
+            pts,
+            dts,
+            duration,
+            key_frame,
+            description,
+        });
+
+        Ok(())
+    }
+
+    fn write_audio(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        let data = match sample.sample_data() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if self.audio.is_none() {
+            let first_pts = match sample.output_presentation_time_stamp() {
+                Some(t) if t.is_valid() => rescale(t.value(), t.scale(), VIDEO_TIME_SCALE),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "audio sample without timing")),
+            };
+
+            let asd = match sample
+                .format_description()
+                .and_then(|fd| fd.audio_stream_description())
+            {
+                Some(e) => e,
+                None => return Ok(()),
+            };
+
+            let entry = match lpcm_sample_entry(asd) {
+                Ok(e) => e,
+                Err(e) => return Err(e),
+            };
+
+            self.audio = Some(AudioTrack {
+                entry,
+                sample_rate: asd.sample_rate() as u32,
+                bytes_per_frame: asd.bytes_per_frame().max(1),
+                chunks: Vec::new(),
+                first_pts,
+            });
+        }
+
+        let offset = match self.write_data(data) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        // every lpcm frame is one mp4 sample, the whole buffer is one chunk
+        let track = self.audio.as_mut().unwrap();
+        track.chunks.push(AudioChunk {
+            offset,
+            frames: data.len() as u32 / track.bytes_per_frame,
+        });
+
+        Ok(())
+    }
+
+    fn moov_box(&self) -> Vec<u8> {
+        // tracks are placed on the movie timeline by their first device pts, so audio and video
+        // keep the offset the device gave them
+        let video_start = self.video.as_ref().and_then(|v| v.samples.first()).map(|s| s.pts);
+        let audio_start = self
+            .audio
+            .as_ref()
+            .filter(|a| !a.chunks.is_empty())
+            .map(|a| a.first_pts);
+        let start = video_start.into_iter().chain(audio_start).min();
+
+        let mut traks: Vec<u8> = Vec::new();
+        let mut movie_duration: u64 = 0;
+
+        if let (Some(track), Some(pts), Some(start)) = (&self.video, video_start, start) {
+            let delay = rescale(pts - start, VIDEO_TIME_SCALE, MOVIE_TIME_SCALE);
+            let (trak, duration) = video_trak(track, delay);
+            traks.extend_from_slice(&trak);
+            movie_duration = movie_duration.max(delay + duration);
+        }
+
+        if let (Some(track), Some(pts), Some(start)) = (&self.audio, audio_start, start) {
+            let delay = rescale(pts - start, VIDEO_TIME_SCALE, MOVIE_TIME_SCALE);
+            let (trak, duration) = audio_trak(track, delay);
+            traks.extend_from_slice(&trak);
+            movie_duration = movie_duration.max(delay + duration);
+        }
+
+        let mut moov: Vec<u8> = Vec::new();
+        moov.extend_from_slice(&mvhd_box(movie_duration, AUDIO_TRACK_ID + 1));
+        moov.extend_from_slice(&traks);
+
+        mp4_box(b"moov", &moov)
+    }
+}
+
+impl SampleWriter for Mp4Writer {
+    fn write_sample(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        match sample.media_type() {
+            MEDIA_TYPE_VIDEO => self.write_video(sample),
+            MEDIA_TYPE_SOUND => self.write_audio(sample),
+            t => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown media type {:#x}", t),
+            )),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+
+        self.finished = true;
+
+        match self.writer.flush() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let mdat_size = self.pos - self.mdat_start;
+
+        match self.writer.seek(SeekFrom::Start(self.mdat_start + 8)) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.write_all(&mdat_size.to_be_bytes()) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        match self.writer.seek(SeekFrom::Start(self.pos)) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let moov = self.moov_box();
+        match self.writer.write_all(&moov) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        self.writer.flush()
+    }
+}
+
+impl Drop for Mp4Writer {
+    fn drop(&mut self) {
+        match self.finish() {
+            Err(e) => warn!("finish mp4: {}", e),
+            _ => {}
+        };
+    }
+}
+
+fn chunk_offsets_box(offsets: &[u64]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend_from_slice(&(offsets.len() as u32).to_be_bytes());
+    for offset in offsets {
+        payload.extend_from_slice(&offset.to_be_bytes());
+    }
+    full_box(b"co64", 0, 0, &payload)
+}
+
+// stsc with one chunk per entry in `samples_per_chunk`
+fn stsc_box(chunks: &[(u32, u32)]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    let mut rows: Vec<(u32, u32, u32)> = Vec::new();
+
+    for (i, (samples, description)) in chunks.iter().enumerate() {
+        match rows.last() {
+            Some((_, s, d)) if s == samples && d == description => {}
+            _ => rows.push((i as u32 + 1, *samples, *description)),
+        }
+    }
+
+    payload.extend_from_slice(&(rows.len() as u32).to_be_bytes());
+    for (first_chunk, samples, description) in rows {
+        payload.extend_from_slice(&first_chunk.to_be_bytes());
+        payload.extend_from_slice(&samples.to_be_bytes());
+        payload.extend_from_slice(&description.to_be_bytes());
+    }
+
+    full_box(b"stsc", 0, 0, &payload)
+}
+
+fn video_trak(track: &VideoTrack, delay: u64) -> (Vec<u8>, u64) {
+    let first_dts = track.samples[0].dts;
+
+    // durations from the dts deltas, the last one from its own timing info
+    let mut durations: Vec<u32> = Vec::new();
+    for (i, s) in track.samples.iter().enumerate() {
+        let duration = match track.samples.get(i + 1) {
+            Some(next) if next.dts > s.dts => next.dts - s.dts,
+            Some(_) => 1,
+            None => s.duration.unwrap_or(durations.last().copied().unwrap_or(1) as u64),
+        };
+        durations.push(duration.min(u32::MAX as u64) as u32);
+    }
+
+    let media_duration: u64 = durations.iter().map(|d| *d as u64).sum();
+
+    let offsets: Vec<u32> = track
+        .samples
+        .iter()
+        .map(|s| s.pts.saturating_sub(s.dts) as u32)
+        .collect();
+
+    let mut stbl: Vec<u8> = Vec::new();
+    stbl.extend_from_slice(&stsd_box(&track.entries));
+    stbl.extend_from_slice(&table_box(b"stts", &run_length(&durations)));
+
+    if offsets.iter().any(|o| *o != 0) {
+        let runs = run_length(&offsets);
+        stbl.extend_from_slice(&table_box(b"ctts", &runs));
+    }
+
+    if track.samples.iter().any(|s| !s.key_frame) {
+        let mut stss: Vec<u8> = Vec::new();
+        let key_frames: Vec<u32> = track
+            .samples
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.key_frame)
+            .map(|(i, _)| i as u32 + 1)
+            .collect();
+        stss.extend_from_slice(&(key_frames.len() as u32).to_be_bytes());
+        for k in key_frames {
+            stss.extend_from_slice(&k.to_be_bytes());
+        }
+        stbl.extend_from_slice(&full_box(b"stss", 0, 0, &stss));
+    }
+
+    let chunks: Vec<(u32, u32)> = track.samples.iter().map(|s| (1, s.description)).collect();
+    stbl.extend_from_slice(&stsc_box(&chunks));
+
+    let mut stsz: Vec<u8> = Vec::new();
+    stsz.extend_from_slice(&0u32.to_be_bytes());
+    stsz.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+    for s in &track.samples {
+        stsz.extend_from_slice(&s.size.to_be_bytes());
+    }
+    stbl.extend_from_slice(&full_box(b"stsz", 0, 0, &stsz));
+
+    let chunk_offsets: Vec<u64> = track.samples.iter().map(|s| s.offset).collect();
+    stbl.extend_from_slice(&chunk_offsets_box(&chunk_offsets));
+
+    let duration = rescale(media_duration, VIDEO_TIME_SCALE, MOVIE_TIME_SCALE);
+    // start presenting at the first pts
+    let media_time = track.samples[0].pts.saturating_sub(first_dts);
+
+    let mut trak: Vec<u8> = Vec::new();
+    trak.extend_from_slice(&tkhd_box(VIDEO_TRACK_ID, delay + duration, false, track.width, track.height));
+    trak.extend_from_slice(&edts_box(delay, duration, media_time));
+    trak.extend_from_slice(&mdia_box(VIDEO_TIME_SCALE, media_duration, false, &stbl));
+
+    (mp4_box(b"trak", &trak), duration)
+}
+
+fn audio_trak(track: &AudioTrack, delay: u64) -> (Vec<u8>, u64) {
+    let frames: u64 = track.chunks.iter().map(|c| c.frames as u64).sum();
+
+    let mut stbl: Vec<u8> = Vec::new();
+    stbl.extend_from_slice(&stsd_box(std::slice::from_ref(&track.entry)));
+    stbl.extend_from_slice(&table_box(b"stts", &[(frames as u32, 1)]));
+
+    let chunks: Vec<(u32, u32)> = track.chunks.iter().map(|c| (c.frames, 1)).collect();
+    stbl.extend_from_slice(&stsc_box(&chunks));
+
+    let mut stsz: Vec<u8> = Vec::new();
+    stsz.extend_from_slice(&track.bytes_per_frame.to_be_bytes());
+    stsz.extend_from_slice(&(frames as u32).to_be_bytes());
+    stbl.extend_from_slice(&full_box(b"stsz", 0, 0, &stsz));
+
+    let chunk_offsets: Vec<u64> = track.chunks.iter().map(|c| c.offset).collect();
+    stbl.extend_from_slice(&chunk_offsets_box(&chunk_offsets));
+
+    let duration = rescale(frames, track.sample_rate, MOVIE_TIME_SCALE);
+
+    let mut trak: Vec<u8> = Vec::new();
+    trak.extend_from_slice(&tkhd_box(AUDIO_TRACK_ID, delay + duration, true, 0, 0));
+    trak.extend_from_slice(&edts_box(delay, duration, 0));
+    trak.extend_from_slice(&mdia_box(track.sample_rate, frames, true, &stbl));
+
+    (mp4_box(b"trak", &trak), duration)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::coremedia::format_desc::AVC1;
+    use crate::coremedia::sample::{SampleTimingInfo, SATT_NOT_SYNC};
+    use crate::coremedia::time::Time;
+    use crate::qt_value::{QTKeyValuePair, QTValue};
+
+    // 30 fps at VIDEO_TIME_SCALE
+    pub(crate) const FRAME: u64 = 3000;
+
+    pub(crate) fn temp_path(name: &str, extension: &str) -> String {
+        let path = std::env::temp_dir().join(format!("scrmiror-{}-{}.{}", name, std::process::id(), extension));
+        String::from(path.to_str().unwrap())
+    }
+
+    pub(crate) fn avc1_format() -> FormatDescriptor {
+        let avc1 = AVC1::new(0x64, 0x00, 0x28, &[0x67, 0x64, 0x00, 0x28], &[0x68, 0xEB, 0xEF, 0x2C]);
+        FormatDescriptor::new_avc1(1920, 1080, avc1)
+    }
+
+    // one IDR or P slice, sync samples are told apart by the satt attachment like the device does
+    pub(crate) fn video_sample(pts: u64, dts: u64, sync: bool) -> SampleBuffer {
+        let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+        sample.set_sample_timing_info_array(Vec::from([SampleTimingInfo::new(
+            Time::new(FRAME, VIDEO_TIME_SCALE, 1, 0),
+            Time::new(pts, VIDEO_TIME_SCALE, 1, 0),
+            Time::new(dts, VIDEO_TIME_SCALE, 1, 0),
+        )]));
+
+        let nalu: &[u8] = match sync {
+            true => &[0x65, 0x88, 0x84, 0x00, 0x10],
+            false => &[0x41, 0x9A, 0x02, 0x00, 0x10],
+        };
+        let mut data: Vec<u8> = Vec::from((nalu.len() as u32).to_be_bytes());
+        data.extend_from_slice(nalu);
+        let data_len = data.len() as u32;
+        sample.set_sample_data(data, Vec::from([data_len]));
+
+        if !sync {
+            sample.set_attachments(Vec::from([QTValue::KeyValuePair(QTKeyValuePair::new(
+                QTValue::IdxKey(SATT_NOT_SYNC),
+                QTValue::UInt32(1),
+            ))]));
+        }
+
+        sample
+    }
+
+    // the boxes in data as (type, payload)
+    pub(crate) fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out: Vec<([u8; 4], &[u8])> = Vec::new();
+        let mut cur = data;
+        while cur.len() >= 8 {
+            let kind: [u8; 4] = cur[4..8].try_into().unwrap();
+            let (header, size) = match u32::from_be_bytes(cur[..4].try_into().unwrap()) {
+                1 => (16, u64::from_be_bytes(cur[8..16].try_into().unwrap()) as usize),
+                size => (8, size as usize),
+            };
+            out.push((kind, &cur[header..size]));
+            cur = &cur[size..];
+        }
+        out
+    }
+
+    pub(crate) fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        path.iter().fold(data, |data, kind| {
+            match boxes(data).into_iter().find(|(k, _)| k == *kind) {
+                Some((_, payload)) => payload,
+                None => panic!("no {} box", String::from_utf8_lossy(*kind)),
+            }
+        })
+    }
+
+    // u32 fields of a full box payload, after version and flags
+    pub(crate) fn words(full_box: &[u8]) -> Vec<u32> {
+        full_box[4..]
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn sample_tables_follow_the_device_timing() {
+        let path = temp_path("tables", "mp4");
+        let mut writer = Mp4Writer::create(path.as_str()).unwrap();
+
+        // IDR, then a frame shown after the next one, like a B frame reorder
+        let mut first = video_sample(FRAME, 0, true);
+        first.set_format_description(avc1_format());
+        writer.write_sample(&first).unwrap();
+        writer.write_sample(&video_sample(3 * FRAME, FRAME, false)).unwrap();
+        writer.write_sample(&video_sample(2 * FRAME, 2 * FRAME, false)).unwrap();
+        writer.write_sample(&video_sample(4 * FRAME, 3 * FRAME, true)).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let file = std::fs::read(path.as_str()).unwrap();
+        let stbl = find(&file, &[b"moov", b"trak", b"mdia", b"minf", b"stbl"]);
+
+        // four frames one FRAME apart, the last one from its own duration
+        assert_eq!(words(find(stbl, &[b"stts"])), vec![1, 4, FRAME as u32]);
+        assert_eq!(words(find(stbl, &[b"stss"])), vec![2, 1, 4]);
+        assert_eq!(
+            words(find(stbl, &[b"ctts"])),
+            vec![4, 1, FRAME as u32, 1, 2 * FRAME as u32, 1, 0, 1, FRAME as u32]
+        );
+
+        // samples follow each other in the mdat, right after its 16 byte header
+        let mdat_start = boxes(&file)[0].1.len() as u64 + 8 + MDAT_HEADER_SIZE;
+        let co64 = find(stbl, &[b"co64"]);
+        let offsets: Vec<u64> = co64[8..]
+            .chunks(8)
+            .map(|o| u64::from_be_bytes(o.try_into().unwrap()))
+            .collect();
+        assert_eq!(u32::from_be_bytes(co64[4..8].try_into().unwrap()), 4);
+        assert_eq!(offsets, vec![mdat_start, mdat_start + 9, mdat_start + 18, mdat_start + 27]);
+        assert_eq!(&file[offsets[3] as usize..offsets[3] as usize + 5], &[0, 0, 0, 5, 0x65]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
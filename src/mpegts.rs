@@ -0,0 +1,696 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use crate::mp4::{rescale, video_sample_times, VIDEO_TIME_SCALE};
+use crate::recorder::SampleWriter;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use crate::tcp_server::Client;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub const TS_PACKET_SIZE: usize = 188;
+
+const PID_PAT: u16 = 0x0000;
+const PID_PMT: u16 = 0x1000;
+const PID_VIDEO: u16 = 0x0100;
+const PID_AUDIO: u16 = 0x0101;
+const PROGRAM_NUMBER: u16 = 1;
+
+const STREAM_TYPE_H264: u8 = 0x1B;
+const STREAM_TYPE_HEVC: u8 = 0x24;
+// SMPTE 302M, PES private data with a BSSD registration descriptor
+const STREAM_TYPE_PRIVATE_PES: u8 = 0x06;
+const STREAM_ID_VIDEO: u8 = 0xE0;
+const STREAM_ID_PRIVATE_1: u8 = 0xBD;
+
+// pts runs ahead of pcr so players have time to buffer, same as ffmpeg's default muxdelay
+const TS_DELAY: u64 = 63000;
+// re-send PAT/PMT at least this often, in 90kHz
+const TABLE_INTERVAL: u64 = 45000;
+const PTS_MASK: u64 = (1 << 33) - 1;
+
+const AUD_NALU: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xF0];
+const HEVC_AUD_NALU: [u8; 7] = [0x00, 0x00, 0x00, 0x01, 0x46, 0x01, 0x50];
+const START_CODE: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
+
+// crc32/mpeg2 for PSI sections
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFFFFFF;
+    for b in data {
+        crc ^= (*b as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn write_timestamp(buffer: &mut Vec<u8>, marker: u8, ts: u64) {
+    let ts = ts & PTS_MASK;
+    buffer.push((marker << 4) | (((ts >> 30) as u8 & 0x07) << 1) | 1);
+    buffer.extend_from_slice(&((((ts >> 15) & 0x7FFF) << 1 | 1) as u16).to_be_bytes());
+    buffer.extend_from_slice(&(((ts & 0x7FFF) << 1 | 1) as u16).to_be_bytes());
+}
+
+fn pes_header(stream_id: u8, pts: u64, dts: Option<u64>, payload_len: usize) -> Vec<u8> {
+    let header_data_len: u8 = if dts.is_some() { 10 } else { 5 };
+
+    // 0 is only allowed for video, audio PES are always far below 64k
+    let pes_len = match stream_id {
+        STREAM_ID_VIDEO => 0,
+        _ => (payload_len + 3 + header_data_len as usize).min(0xFFFF) as u16,
+    };
+
+    let mut buffer: Vec<u8> = Vec::from([0x00, 0x00, 0x01, stream_id]);
+    buffer.extend_from_slice(&pes_len.to_be_bytes());
+    buffer.push(0x80);
+
+    match dts {
+        Some(dts) => {
+            buffer.push(0xC0);
+            buffer.push(header_data_len);
+            write_timestamp(&mut buffer, 0x3, pts);
+            write_timestamp(&mut buffer, 0x1, dts);
+        }
+        None => {
+            buffer.push(0x80);
+            buffer.push(header_data_len);
+            write_timestamp(&mut buffer, 0x2, pts);
+        }
+    };
+
+    buffer
+}
+
+// AVCC length prefixed NALUs to Annex B start codes
+fn annex_b(nal_units: &[&[u8]], out: &mut Vec<u8>) {
+    for nalu in nal_units {
+        out.extend_from_slice(&START_CODE);
+        out.extend_from_slice(nalu);
+    }
+}
+
+// SMPTE 302M AES3 framing of little endian lpcm, ffmpeg's s302m layout
+fn s302m_payload(asd: &AudioStreamDescription, data: &[u8], frame_index: &mut u32) -> Result<Vec<u8>, Error> {
+    let channels = asd.channels_per_frame() as usize;
+    if asd.sample_rate() as u32 != 48000 || asd.bits_per_channel() != 16 || ![2, 4, 6, 8].contains(&channels) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "s302m needs 48kHz 16 bit and 2-8 channels, got {} Hz {} bits {} channels",
+                asd.sample_rate(),
+                asd.bits_per_channel(),
+                channels
+            ),
+        ));
+    }
+
+    let frames = data.len() / (channels * 2);
+    let payload_len = frames * channels / 2 * 5;
+
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload_len + 4);
+    // audio_packet_size:16 number_channels:2 channel_identification:8 bits_per_sample:2 alignment_bits:4
+    let header: u32 = ((payload_len as u32) << 16) | ((((channels - 2) / 2) as u32) << 14);
+    buffer.extend_from_slice(&header.to_be_bytes());
+
+    for frame in data.chunks_exact(channels * 2).take(frames) {
+        // start of an AES3 block every 192 frames
+        let vucf: u8 = if *frame_index == 0 { 0x10 } else { 0 };
+
+        for pair in frame.chunks_exact(4) {
+            let l = u16::from_le_bytes([pair[0], pair[1]]);
+            let r = u16::from_le_bytes([pair[2], pair[3]]);
+
+            buffer.push((l as u8).reverse_bits());
+            buffer.push(((l >> 8) as u8).reverse_bits());
+            buffer.push((((r & 0x0F) << 4) as u8).reverse_bits() | vucf);
+            buffer.push((((r & 0x0FF0) >> 4) as u8).reverse_bits());
+            buffer.push((((r & 0xF000) >> 12) as u8).reverse_bits());
+        }
+
+        *frame_index = (*frame_index + 1) % 192;
+    }
+
+    Ok(buffer)
+}
+
+struct TrackClock {
+    // first device timestamp of the track in 90kHz
+    base: u64,
+    // when the first sample arrived relative to the first sample of the session, in 90kHz
+    offset: u64,
+}
+
+pub struct TsMuxer {
+    start: Option<Instant>,
+    video_clock: Option<TrackClock>,
+    audio_clock: Option<TrackClock>,
+    continuity: [u8; 4],
+    parameter_sets: Option<Vec<u8>>,
+    hevc: bool,
+    // size of the NALU lengths in the sample data, from the last avcC/hvcC
+    nalu_len: u8,
+    audio_description: Option<AudioStreamDescription>,
+    audio_frame_index: u32,
+    // the device sent a format s302m can't carry, the program goes on without audio
+    audio_dropped: bool,
+    pmt_version: u8,
+    last_tables: Option<u64>,
+}
+
+impl Default for TsMuxer {
+    fn default() -> Self {
+        TsMuxer::new()
+    }
+}
+
+impl TsMuxer {
+    pub fn new() -> TsMuxer {
+        TsMuxer {
+            start: None,
+            video_clock: None,
+            audio_clock: None,
+            continuity: [0; 4],
+            parameter_sets: None,
+            hevc: false,
+            nalu_len: 4,
+            audio_description: None,
+            audio_frame_index: 0,
+            audio_dropped: false,
+            pmt_version: 0,
+            last_tables: None,
+        }
+    }
+
+    pub fn hevc(&self) -> bool {
+        self.hevc
+    }
+
+    // TS packets for one FEED or EAT! sample buffer, empty until the first format description.
+    // true for video key frames, those start with PAT/PMT and are a clean point to join the stream
+    pub fn mux_sample(&mut self, sample: &SampleBuffer) -> Result<(Vec<u8>, bool), Error> {
+        match sample.media_type() {
+            MEDIA_TYPE_VIDEO => self.mux_video(sample),
+            MEDIA_TYPE_SOUND => self.mux_audio(sample).map(|out| (out, false)),
+            t => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown media type {:#x}", t),
+            )),
+        }
+    }
+
+    fn session_time(&mut self, device_ts: u64, audio: bool) -> u64 {
+        let start = *self.start.get_or_insert_with(Instant::now);
+
+        let clock = if audio { &mut self.audio_clock } else { &mut self.video_clock };
+        let clock = clock.get_or_insert_with(|| TrackClock {
+            base: device_ts,
+            offset: rescale(start.elapsed().as_micros() as u64, 1_000_000, VIDEO_TIME_SCALE),
+        });
+
+        device_ts.saturating_sub(clock.base) + clock.offset + TS_DELAY
+    }
+
+    fn mux_video(&mut self, sample: &SampleBuffer) -> Result<(Vec<u8>, bool), Error> {
+        if sample.sample_data().is_none() {
+            return Ok((Vec::new(), false));
+        }
+
+        if let Some(codec) = sample.format_description().and_then(|fd| fd.video_codec()) {
+            let mut ps: Vec<u8> = Vec::new();
+            for nalu in codec.parameter_sets() {
+                ps.extend_from_slice(&START_CODE);
+                ps.extend_from_slice(nalu);
+            }
+
+            // the stream type changes, players pick it up from the next PMT
+            if self.parameter_sets.is_some() && codec.is_hevc() != self.hevc {
+                self.pmt_version = (self.pmt_version + 1) & 0x1F;
+            }
+
+            self.parameter_sets = Some(ps);
+            self.hevc = codec.is_hevc();
+            self.nalu_len = codec.nalu_len();
+        }
+
+        let parameter_sets = match &self.parameter_sets {
+            Some(e) => e.clone(),
+            None => return Ok((Vec::new(), false)),
+        };
+
+        let (pts, dts, _) = match video_sample_times(sample) {
+            Some(e) => e,
+            None => return Err(Error::new(ErrorKind::InvalidData, "video sample without timing")),
+        };
+
+        let key_frame = sample.is_sync();
+
+        let mut es: Vec<u8> = match self.hevc {
+            true => Vec::from(HEVC_AUD_NALU),
+            false => Vec::from(AUD_NALU),
+        };
+        if key_frame {
+            es.extend_from_slice(&parameter_sets);
+        }
+
+        let nal_units = match sample.nal_units_sized(self.nalu_len) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+        annex_b(&nal_units, &mut es);
+
+        let composition_offset = pts.saturating_sub(dts);
+        let dts = self.session_time(dts, false);
+        let pts = dts + composition_offset;
+
+        let mut out: Vec<u8> = Vec::new();
+
+        let tables_due = match self.last_tables {
+            Some(last) => dts.saturating_sub(last) >= TABLE_INTERVAL,
+            None => true,
+        };
+
+        if key_frame || tables_due {
+            self.write_tables(&mut out);
+            self.last_tables = Some(dts);
+        }
+
+        let mut pes = pes_header(STREAM_ID_VIDEO, pts, Some(dts), es.len());
+        pes.extend_from_slice(&es);
+
+        // pcr on the video pid, behind dts by the mux delay
+        self.write_pes(&mut out, PID_VIDEO, &pes, Some(dts - TS_DELAY), key_frame);
+
+        Ok((out, key_frame))
+    }
+
+    fn mux_audio(&mut self, sample: &SampleBuffer) -> Result<Vec<u8>, Error> {
+        let data = match sample.sample_data() {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+
+        if self.audio_dropped {
+            return Ok(Vec::new());
+        }
+
+        if let Some(asd) = sample
+            .format_description()
+            .and_then(|fd| fd.audio_stream_description())
+        {
+            if self.audio_description.is_none() {
+                // audio joins the program, players pick it up from the next PMT
+                self.pmt_version = (self.pmt_version + 1) & 0x1F;
+                self.last_tables = None;
+            }
+
+            self.audio_description = Some(asd.clone());
+        }
+
+        // audio before the first video frame has nothing to sync against
+        if self.video_clock.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let asd = match &self.audio_description {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+
+        let opts = match sample.output_presentation_time_stamp() {
+            Some(t) => t,
+            None => return Err(Error::new(ErrorKind::InvalidData, "audio sample without timing")),
+        };
+
+        let payload = match s302m_payload(asd, data, &mut self.audio_frame_index) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("mpeg-ts audio dropped: {}", e);
+
+                // audio leaves the program again, the video goes on
+                self.audio_dropped = true;
+                self.audio_description = None;
+                self.pmt_version = (self.pmt_version + 1) & 0x1F;
+                self.last_tables = None;
+                return Ok(Vec::new());
+            }
+        };
+
+        let pts = self.session_time(rescale(opts.value(), opts.scale(), VIDEO_TIME_SCALE), true);
+
+        let mut out: Vec<u8> = Vec::new();
+
+        if self.last_tables.is_none() {
+            self.write_tables(&mut out);
+            self.last_tables = Some(pts);
+        }
+
+        let mut pes = pes_header(STREAM_ID_PRIVATE_1, pts, None, payload.len());
+        pes.extend_from_slice(&payload);
+
+        self.write_pes(&mut out, PID_AUDIO, &pes, None, false);
+
+        Ok(out)
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        let idx = match pid {
+            PID_PAT => 0,
+            PID_PMT => 1,
+            PID_VIDEO => 2,
+            _ => 3,
+        };
+        let cc = self.continuity[idx];
+        self.continuity[idx] = (cc + 1) & 0x0F;
+        cc
+    }
+
+    fn write_section(&mut self, out: &mut Vec<u8>, pid: u16, section: &[u8]) {
+        let mut payload: Vec<u8> = Vec::from([0x00]); // pointer field
+        payload.extend_from_slice(section);
+        payload.extend_from_slice(&crc32(section).to_be_bytes());
+        payload.resize(TS_PACKET_SIZE - 4, 0xFF);
+
+        let cc = self.next_continuity(pid);
+        out.push(0x47);
+        out.push(0x40 | ((pid >> 8) as u8 & 0x1F));
+        out.push(pid as u8);
+        out.push(0x10 | cc);
+        out.extend_from_slice(&payload);
+    }
+
+    fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let mut pat: Vec<u8> = Vec::from([0x00, 0xB0, 0x0D]);
+        pat.extend_from_slice(&1u16.to_be_bytes()); // transport stream id
+        pat.extend_from_slice(&[0xC1, 0x00, 0x00]);
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xE000 | PID_PMT).to_be_bytes());
+        self.write_section(out, PID_PAT, &pat);
+
+        let mut streams: Vec<u8> = Vec::new();
+        streams.push(if self.hevc { STREAM_TYPE_HEVC } else { STREAM_TYPE_H264 });
+        streams.extend_from_slice(&(0xE000 | PID_VIDEO).to_be_bytes());
+        streams.extend_from_slice(&0xF000u16.to_be_bytes());
+
+        if self.audio_description.is_some() {
+            streams.push(STREAM_TYPE_PRIVATE_PES);
+            streams.extend_from_slice(&(0xE000 | PID_AUDIO).to_be_bytes());
+            // registration descriptor BSSD
+            streams.extend_from_slice(&0xF006u16.to_be_bytes());
+            streams.extend_from_slice(&[0x05, 0x04]);
+            streams.extend_from_slice(b"BSSD");
+        }
+
+        let section_len = 9 + streams.len() + 4;
+        let mut pmt: Vec<u8> = Vec::from([0x02]);
+        pmt.extend_from_slice(&(0xB000 | section_len as u16).to_be_bytes());
+        pmt.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pmt.push(0xC1 | (self.pmt_version << 1));
+        pmt.extend_from_slice(&[0x00, 0x00]);
+        pmt.extend_from_slice(&(0xE000 | PID_VIDEO).to_be_bytes()); // pcr pid
+        pmt.extend_from_slice(&0xF000u16.to_be_bytes());
+        pmt.extend_from_slice(&streams);
+        self.write_section(out, PID_PMT, &pmt);
+    }
+
+    fn write_pes(&mut self, out: &mut Vec<u8>, pid: u16, pes: &[u8], pcr: Option<u64>, random_access: bool) {
+        let mut cur = pes;
+        let mut first = true;
+
+        while !cur.is_empty() {
+            // adaptation field content after its length byte
+            let mut adaptation: Option<Vec<u8>> = None;
+
+            if first && (pcr.is_some() || random_access) {
+                let mut field: Vec<u8> = Vec::from([if random_access { 0x40 } else { 0x00 }]);
+
+                if let Some(pcr) = pcr {
+                    field[0] |= 0x10;
+                    let base = pcr & PTS_MASK;
+                    field.extend_from_slice(&((base >> 1) as u32).to_be_bytes());
+                    field.push((((base & 1) as u8) << 7) | 0x7E);
+                    field.push(0x00);
+                }
+
+                adaptation = Some(field);
+            }
+
+            let header_len = 4 + adaptation.as_ref().map(|a| a.len() + 1).unwrap_or(0);
+
+            // stuff the last packet through the adaptation field
+            if cur.len() < TS_PACKET_SIZE - header_len {
+                let field = adaptation.get_or_insert_with(Vec::new);
+                let field_len = TS_PACKET_SIZE - 5 - cur.len();
+                if field.is_empty() && field_len > 0 {
+                    field.push(0x00);
+                }
+                field.resize(field_len, 0xFF);
+            }
+
+            let header_len = 4 + adaptation.as_ref().map(|a| a.len() + 1).unwrap_or(0);
+            let payload_len = (TS_PACKET_SIZE - header_len).min(cur.len());
+
+            let cc = self.next_continuity(pid);
+            out.push(0x47);
+            out.push(if first { 0x40 } else { 0x00 } | ((pid >> 8) as u8 & 0x1F));
+            out.push(pid as u8);
+
+            match &adaptation {
+                Some(field) => {
+                    out.push(0x30 | cc);
+                    out.push(field.len() as u8);
+                    out.extend_from_slice(field);
+                }
+                None => out.push(0x10 | cc),
+            };
+
+            out.extend_from_slice(&cur[..payload_len]);
+            cur = &cur[payload_len..];
+            first = false;
+        }
+    }
+}
+
+pub struct TsFileWriter {
+    muxer: TsMuxer,
+    writer: BufWriter<File>,
+}
+
+impl TsFileWriter {
+    pub fn create(path: &str) -> Result<TsFileWriter, Error> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return Err(e),
+        };
+
+        Ok(TsFileWriter {
+            muxer: TsMuxer::new(),
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl SampleWriter for TsFileWriter {
+    fn write_sample(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        let (packets, _) = match self.muxer.mux_sample(sample) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        self.writer.write_all(&packets)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.writer.flush()
+    }
+}
+
+// one muxed stream served to every tcp client, a new client joins at the next key frame.
+// each client has its own bounded queue and writer thread, a slow one is skipped and then dropped
+pub struct TsTcpWriter {
+    muxer: TsMuxer,
+    listener: TcpListener,
+    clients: Vec<Client>,
+}
+
+impl TsTcpWriter {
+    pub fn bind(address: &str) -> Result<TsTcpWriter, Error> {
+        let listener = match TcpListener::bind(address) {
+            Ok(l) => l,
+            Err(e) => return Err(e),
+        };
+
+        match listener.set_nonblocking(true) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        info!("mpeg-ts server started on {}", address);
+
+        Ok(TsTcpWriter {
+            muxer: TsMuxer::new(),
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("New mpeg-ts connection: {}", addr);
+
+                    match Client::connect(stream, addr, true) {
+                        Ok(mut client) => {
+                            client.wait_for_sync();
+                            self.clients.push(client);
+                        }
+                        Err(e) => warn!("mpeg-ts connection error: {}", e),
+                    };
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("mpeg-ts connection error: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl SampleWriter for TsTcpWriter {
+    fn write_sample(&mut self, sample: &SampleBuffer) -> Result<(), Error> {
+        self.accept();
+
+        let (packets, key_frame) = match self.muxer.mux_sample(sample) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if packets.is_empty() {
+            return Ok(());
+        }
+
+        let data = Arc::new(packets);
+        let sync_data = match key_frame {
+            true => Some(&data),
+            false => None,
+        };
+
+        self.clients.retain_mut(|client| client.send(&data, sync_data, "mpeg-ts"));
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        // dropping the senders ends the client threads
+        self.clients.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4::tests::{avc1_format, video_sample, FRAME};
+
+    fn pid(packet: &[u8]) -> u16 {
+        u16::from_be_bytes([packet[1] & 0x1F, packet[2]])
+    }
+
+    // payload after the header and the adaptation field
+    fn payload(packet: &[u8]) -> &[u8] {
+        match packet[3] & 0x20 {
+            0 => &packet[4..],
+            _ => &packet[5 + packet[4] as usize..],
+        }
+    }
+
+    fn read_timestamp(b: &[u8]) -> u64 {
+        ((b[0] as u64 >> 1) & 0x07) << 30
+            | (u16::from_be_bytes([b[1], b[2]]) as u64 >> 1) << 15
+            | u16::from_be_bytes([b[3], b[4]]) as u64 >> 1
+    }
+
+    // (pts, dts) from the PES header starting the packet
+    fn pes_times(packet: &[u8]) -> (u64, u64) {
+        let pes = payload(packet);
+        assert_eq!(&pes[..4], &[0x00, 0x00, 0x01, STREAM_ID_VIDEO]);
+        assert_eq!(pes[7], 0xC0);
+        assert_eq!(pes[9] >> 4, 0x3);
+        assert_eq!(pes[14] >> 4, 0x1);
+        (read_timestamp(&pes[9..14]), read_timestamp(&pes[14..19]))
+    }
+
+    #[test]
+    fn key_frames_carry_the_tables_and_timestamps() {
+        let mut muxer = TsMuxer::new();
+
+        let mut first = video_sample(FRAME, 0, true);
+        first.set_format_description(avc1_format());
+        let samples = [
+            first,
+            video_sample(3 * FRAME, FRAME, false),
+            video_sample(2 * FRAME, 2 * FRAME, false),
+            video_sample(4 * FRAME, 3 * FRAME, true),
+        ];
+
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+        for (i, sample) in samples.iter().enumerate() {
+            let (out, key_frame) = muxer.mux_sample(sample).unwrap();
+            assert_eq!(key_frame, i == 0 || i == 3);
+            assert_eq!(out.len() % TS_PACKET_SIZE, 0);
+            packets.extend(out.chunks(TS_PACKET_SIZE).map(Vec::from));
+        }
+
+        let pids: Vec<u16> = packets.iter().map(|p| pid(p)).collect();
+        assert_eq!(
+            pids,
+            vec![PID_PAT, PID_PMT, PID_VIDEO, PID_VIDEO, PID_VIDEO, PID_PAT, PID_PMT, PID_VIDEO]
+        );
+        for packet in &packets {
+            assert_eq!(packet[0], 0x47);
+            assert_eq!(packet[1] & 0x40, 0x40);
+        }
+
+        // every pid counts on its own
+        let counters: Vec<u8> = packets.iter().map(|p| p[3] & 0x0F).collect();
+        assert_eq!(counters, vec![0, 0, 0, 1, 2, 1, 1, 3]);
+
+        // PAT points program 1 at the PMT, a section with its crc appended checks to 0
+        let pat = &payload(&packets[0])[1..];
+        assert_eq!(pat[0], 0x00);
+        assert_eq!(&pat[8..12], &[0x00, 0x01, 0xF0, 0x00]);
+        assert_eq!(crc32(&pat[..16]), 0);
+
+        // PMT with the pcr and a single h264 stream on the video pid
+        let pmt = &payload(&packets[1])[1..];
+        assert_eq!(pmt[0], 0x02);
+        assert_eq!(u16::from_be_bytes([pmt[8] & 0x1F, pmt[9]]), PID_VIDEO);
+        assert_eq!(&pmt[12..17], &[STREAM_TYPE_H264, 0xE1, 0x00, 0xF0, 0x00]);
+        assert_eq!(crc32(&pmt[..21]), 0);
+
+        // only key frames are flagged as random access
+        let video: Vec<&Vec<u8>> = packets.iter().filter(|p| pid(p) == PID_VIDEO).collect();
+        let random_access: Vec<bool> = video.iter().map(|p| p[3] & 0x20 != 0 && p[5] & 0x40 != 0).collect();
+        assert_eq!(random_access, vec![true, false, false, true]);
+
+        // dts keeps the device spacing behind the mux delay, pts keeps the reorder offsets
+        let times: Vec<(u64, u64)> = video.iter().map(|p| pes_times(p)).collect();
+        let start = times[0].1;
+        assert!(start >= TS_DELAY);
+        assert_eq!(
+            times,
+            vec![
+                (start + FRAME, start),
+                (start + 3 * FRAME, start + FRAME),
+                (start + 2 * FRAME, start + 2 * FRAME),
+                (start + 4 * FRAME, start + 3 * FRAME),
+            ]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use crate::error::QTError;
+use std::sync::Arc;
+
+// part of a read buffer, cloning and slicing only bump the refcount
+#[derive(Clone)]
+pub struct PacketBytes {
+    data: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBytes {
+    pub fn from_vec(data: Vec<u8>) -> PacketBytes {
+        let end = data.len();
+        PacketBytes {
+            data: Arc::new(data),
+            start: 0,
+            end,
+        }
+    }
+
+    // the first len bytes of a buffer someone else may still hold on to
+    pub fn from_shared(data: Arc<Vec<u8>>, len: usize) -> PacketBytes {
+        assert!(len <= data.len(), "{} bytes of {}", len, data.len());
+
+        PacketBytes {
+            data,
+            start: 0,
+            end: len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    // start and end are relative to this slice
+    pub fn slice(&self, start: usize, end: usize) -> PacketBytes {
+        assert!(start <= end && end <= self.len(), "slice {}..{} of {}", start, end, self.len());
+
+        PacketBytes {
+            data: self.data.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+}
+
+impl AsRef<[u8]> for PacketBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+}
+
+// equal when the bytes are, wherever they live
+impl PartialEq for PacketBytes {
+    fn eq(&self, other: &PacketBytes) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+
+fn packet_len(header: &[u8]) -> usize {
+    u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize
+}
+
+// frames the length prefixed packets out of the transport reads. packets inside one read are
+// handed out as slices of it, only a packet that spans two reads is copied together
+pub struct PacketBuffer {
+    // the last read, dropped once all of it was handed out so its buffer can be reused
+    chunk: PacketBytes,
+    // start of the next packet in chunk
+    pos: usize,
+    // packet that started in an earlier read
+    partial: Vec<u8>,
+}
+
+impl PacketBuffer {
+    pub fn new() -> PacketBuffer {
+        PacketBuffer {
+            chunk: PacketBytes::from_vec(Vec::new()),
+            pos: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    // call once next_packet returned None
+    pub fn push(&mut self, chunk: PacketBytes) {
+        // the start of a packet that did not fit into the last read
+        if self.pos < self.chunk.len() {
+            self.partial.extend_from_slice(&self.chunk.as_ref()[self.pos..]);
+        }
+        self.chunk = chunk;
+        self.pos = 0;
+
+        if !self.partial.is_empty() {
+            self.fill_partial();
+        }
+    }
+
+    // moves as much of the new read into partial as the packet still needs
+    fn fill_partial(&mut self) {
+        let chunk = self.chunk.as_ref();
+
+        if self.partial.len() < 4 {
+            let take = (4 - self.partial.len()).min(chunk.len() - self.pos);
+            self.partial.extend_from_slice(&chunk[self.pos..self.pos + take]);
+            self.pos += take;
+
+            if self.partial.len() < 4 {
+                return;
+            }
+        }
+
+        let pkt_len = packet_len(&self.partial);
+        if pkt_len <= self.partial.len() {
+            return;
+        }
+
+        let take = (pkt_len - self.partial.len()).min(chunk.len() - self.pos);
+        self.partial.extend_from_slice(&chunk[self.pos..self.pos + take]);
+        self.pos += take;
+    }
+
+    // can't find the next packet boundary, drop what we have
+    fn drop_buffered(&mut self, pkt_len: usize) -> QTError {
+        self.partial = Vec::new();
+        self.pos = self.chunk.len();
+        QTError::Framing(format!("packet length {}", pkt_len))
+    }
+
+    // the next complete packet, several can come out of one read
+    pub fn next_packet(&mut self) -> Result<Option<PacketBytes>, QTError> {
+        if !self.partial.is_empty() {
+            if self.partial.len() < 4 {
+                return Ok(None);
+            }
+
+            let pkt_len = packet_len(&self.partial);
+            if pkt_len < 8 {
+                return Err(self.drop_buffered(pkt_len));
+            }
+
+            if self.partial.len() < pkt_len {
+                return Ok(None);
+            }
+
+            let data = std::mem::take(&mut self.partial);
+            return Ok(Some(PacketBytes::from_vec(data)));
+        }
+
+        let available = self.chunk.len() - self.pos;
+        if available == 0 && !self.chunk.is_empty() {
+            self.chunk = PacketBytes::from_vec(Vec::new());
+            self.pos = 0;
+        }
+        if available < 4 {
+            return Ok(None);
+        }
+
+        let pkt_len = packet_len(&self.chunk.as_ref()[self.pos..]);
+        if pkt_len < 8 {
+            return Err(self.drop_buffered(pkt_len));
+        }
+
+        if available < pkt_len {
+            return Ok(None);
+        }
+
+        let pkt = self.chunk.slice(self.pos, self.pos + pkt_len);
+        self.pos += pkt_len;
+
+        Ok(Some(pkt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a packet of len bytes, the length header and then fill
+    fn packet(len: usize, fill: u8) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::from((len as u32).to_le_bytes());
+        data.resize(len, fill);
+        data
+    }
+
+    // pushes every read and takes all complete packets after each
+    fn frame(reads: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, QTError> {
+        let mut buffer = PacketBuffer::new();
+        let mut packets: Vec<Vec<u8>> = Vec::new();
+
+        for read in reads {
+            buffer.push(PacketBytes::from_vec(read));
+            loop {
+                match buffer.next_packet() {
+                    Ok(Some(pkt)) => packets.push(Vec::from(pkt.as_ref())),
+                    Ok(None) => break,
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+
+        Ok(packets)
+    }
+
+    #[test]
+    fn several_packets_in_one_read() {
+        let expected = Vec::from([packet(8, 1), packet(20, 2), packet(12, 3)]);
+
+        let packets = frame(Vec::from([expected.concat()])).unwrap();
+        assert_eq!(packets, expected);
+    }
+
+    #[test]
+    fn length_header_split_across_reads() {
+        let expected = Vec::from([packet(16, 1), packet(24, 2)]);
+        let data = expected.concat();
+
+        // the second header is cut after two of its four bytes
+        let packets = frame(Vec::from([data[..18].to_vec(), data[18..].to_vec()])).unwrap();
+        assert_eq!(packets, expected);
+    }
+
+    #[test]
+    fn one_byte_reads() {
+        let expected = Vec::from([packet(8, 1), packet(13, 2), packet(9, 3)]);
+        let reads: Vec<Vec<u8>> = expected.concat().iter().map(|b| Vec::from([*b])).collect();
+
+        let packets = frame(reads).unwrap();
+        assert_eq!(packets, expected);
+    }
+
+    #[test]
+    fn packet_fills_the_read() {
+        let expected = Vec::from([packet(64, 1), packet(64, 2)]);
+
+        let packets = frame(expected.clone()).unwrap();
+        assert_eq!(packets, expected);
+    }
+
+    #[test]
+    fn short_length_is_an_error() {
+        for len in 0..8 {
+            let mut read: Vec<u8> = Vec::from((len as u32).to_le_bytes());
+            read.resize(16, 0);
+
+            assert!(frame(Vec::from([read])).is_err(), "length {}", len);
+        }
+
+        // behind a good packet, in one read and with the header split so the partial path sees it
+        let header = 4u32.to_le_bytes();
+        let reads = Vec::from([packet(8, 1), Vec::from(&header[..3]), Vec::from(&header[3..])]);
+        assert!(frame(Vec::from([reads.concat()])).is_err());
+        assert!(frame(reads).is_err());
+    }
+}
@@ -1,21 +1,36 @@
-use crate::apple::AppleDevice;
+#![allow(clippy::needless_return, clippy::question_mark, clippy::single_match)]
+
 use crate::coremedia::clock::Clock;
 use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
 use crate::coremedia::time::Time;
-use crate::qt_device::{qt_hpa1_device_info, qt_hpd1_device_info};
+use crate::coremedia::timeline::Timeline;
+use crate::error::QTError;
+use crate::mirror::MirrorConfig;
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::format_desc::FormatDescriptor;
+use crate::qt_device::{qt_hpa1_device_info, qt_hpd1_device_info, AudioConfig, DisplayConfig};
+use crate::packet_buffer::PacketBuffer;
 use crate::qt_pkt;
-use crate::qt_pkt::{
-    QTPacket, QTPacketAFMT, QTPacketASYN, QTPacketCLOCK, QTPacketSKEW, QTPacketSTOP, QTPacketTIME,
-};
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{BufRead, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use crate::qt_pkt::{value_payload, AsynMessage, Message, QTPacket, SyncMessage};
+use crate::qt_value::{QTKeyValuePair, QTValue};
+use crate::session::{Phase, Session, SessionWatch};
+use crate::trace::Trace;
+use crate::transport::Transport;
+use log::{info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::SyncSender;
 use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-pub struct QuickTime {
-    device: AppleDevice,
+pub struct QuickTime<T: Transport> {
+    device: T,
     no_audio: bool,
+    display: DisplayConfig,
+    audio: AudioConfig,
+    // what the device proposed in AFMT and we accepted
+    audio_format: Option<AudioStreamDescription>,
+    audio_format_sent: bool,
     term: Arc<AtomicBool>,
     clock: Option<Clock>,
     need_clock_ref: Option<u64>,
@@ -25,37 +40,71 @@ pub struct QuickTime {
     last_eat_frame_received_local_audio_clock: Option<Time>,
     start_time_device_audio_clock: Option<Time>,
     last_eat_frame_received_device_audio_clock: Option<Time>,
-    packet_pool: Cursor<Vec<u8>>,
-    video_tx: SyncSender<Result<SampleBuffer, Error>>,
-    audio_tx: SyncSender<Result<SampleBuffer, Error>>,
+    packets: PacketBuffer,
+    video_timeline: Timeline,
+    audio_timeline: Timeline,
+    video_tx: Box<dyn SampleSender>,
+    audio_tx: Box<dyn SampleSender>,
     audio_connected: Arc<AtomicBool>,
+    session: Session,
+    rels_received: u32,
+    stopped: bool,
+    trace: Option<Trace>,
+}
+
+// where decoded samples go, blocks while the consumer is behind and fails once it is gone
+pub trait SampleSender: Send {
+    fn send_sample(&self, sample: SampleBuffer) -> Result<(), String>;
+}
+
+impl SampleSender for SyncSender<Result<SampleBuffer, QTError>> {
+    fn send_sample(&self, sample: SampleBuffer) -> Result<(), String> {
+        match self.send(Ok(sample)) {
+            Err(e) => Err(e.to_string()),
+            _ => Ok(()),
+        }
+    }
 }
 
-const HPD1: u32 = 0x68706431;
-const HPA1: u32 = 0x68706131;
-const HPD0: u32 = 0x68706430;
-const HPA0: u32 = 0x68706130;
-const NEED: u32 = 0x6E656564;
-const EMPTY_CF_TYPE: u64 = 1;
+pub const HPD1: u32 = qt_pkt::ASYN_PACKET_MAGIC_HPD1;
+pub const HPA1: u32 = qt_pkt::ASYN_PACKET_MAGIC_HPA1;
+pub const HPD0: u32 = qt_pkt::ASYN_PACKET_MAGIC_HPD0;
+pub const HPA0: u32 = qt_pkt::ASYN_PACKET_MAGIC_HPA0;
+pub const NEED: u32 = qt_pkt::ASYN_PACKET_MAGIC_NEED;
+pub const EMPTY_CF_TYPE: u64 = 1;
+
+// how long we wait for STOP/RELS after sending HPA0/HPD0
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
-impl AsRef<QuickTime> for QuickTime {
-    fn as_ref(&self) -> &QuickTime {
+// device is back but init failed, e.g. it is still booting
+const RECONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+impl<T: Transport> AsRef<QuickTime<T>> for QuickTime<T> {
+    fn as_ref(&self) -> &QuickTime<T> {
         self
     }
 }
 
-impl QuickTime {
+impl<T: Transport> QuickTime<T> {
     pub fn new(
-        device: AppleDevice,
-        video_tx: SyncSender<Result<SampleBuffer, Error>>,
-        audio_tx: SyncSender<Result<SampleBuffer, Error>>,
-        no_audio: bool,
-        audio_connected: Arc<AtomicBool>,
-    ) -> QuickTime {
+        device: T,
+        video_tx: Box<dyn SampleSender>,
+        audio_tx: Box<dyn SampleSender>,
+        config: &MirrorConfig,
+    ) -> QuickTime<T> {
+        let term = match config.term() {
+            Some(e) => e.clone(),
+            None => Arc::new(AtomicBool::new(false)),
+        };
+
         return QuickTime {
             device,
-            no_audio,
-            term: Arc::new(AtomicBool::new(false)),
+            no_audio: config.no_audio(),
+            display: config.display().clone(),
+            audio: config.audio().clone(),
+            audio_format: None,
+            audio_format_sent: false,
+            term,
             clock: None,
             need_clock_ref: None,
             local_audio_clock: None,
@@ -64,10 +113,16 @@ impl QuickTime {
             last_eat_frame_received_local_audio_clock: None,
             start_time_device_audio_clock: None,
             last_eat_frame_received_device_audio_clock: None,
-            packet_pool: Cursor::new(Vec::new()),
+            packets: PacketBuffer::new(),
+            video_timeline: Timeline::new(),
+            audio_timeline: Timeline::new(),
             video_tx,
             audio_tx,
-            audio_connected,
+            audio_connected: Arc::new(AtomicBool::new(!config.audio_on_demand())),
+            session: Session::new(),
+            rels_received: 0,
+            stopped: false,
+            trace: config.trace().cloned(),
         };
     }
 
@@ -75,361 +130,341 @@ impl QuickTime {
         return &self.term;
     }
 
-    pub fn init(&mut self) -> Result<(), Error> {
-        self.device.set_qt_enabled(true).expect("set qt enabled");
-
-        match self.device.claim_interface() {
-            Some(_) => return Err(Error::new(ErrorKind::Other, "claim interface")),
-            _ => {}
-        };
-
-        match self.device.init_bulk_endpoint() {
-            Some(_) => return Err(Error::new(ErrorKind::Other, "init bulk endpoint")),
-            _ => {}
-        };
+    pub fn audio_connected(&self) -> &Arc<AtomicBool> {
+        &self.audio_connected
+    }
 
-        match self.device.clear_feature() {
-            Some(_) => return Err(Error::new(ErrorKind::Other, "clear feature")),
-            _ => {}
-        };
+    pub fn session_watch(&self) -> SessionWatch {
+        self.session.watch()
+    }
 
-        Ok(())
+    pub fn init(&mut self) -> Result<(), QTError> {
+        self.device.init()
     }
 
-    fn read(&mut self) -> Result<Option<QTPacket>, Error> {
-        let mut buffer: Vec<u8> = vec![0; self.device.max_read_packet_size() as usize];
-        let buffer_size = match self.device.read_bulk(&mut buffer) {
-            Ok(e) => e,
-            Err(e) => {
-                return Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    format!("read bulk {}", e),
-                ))
-            }
-        };
+    // waits for the device after a disconnect and starts over with a fresh protocol session,
+    // the senders stay the same so the outputs keep their clients
+    pub fn reconnect(&mut self) -> bool {
+        while !self.term.load(Ordering::SeqCst) {
+            match self.device.reconnect(&self.term) {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(e) => {
+                    warn!("reconnect failed {}", e);
+                    return false;
+                }
+            };
 
-        if buffer_size <= 0 {
-            return Ok(None);
+            self.reset();
+
+            match self.device.init() {
+                Ok(_) => return true,
+                Err(e) if e.is_disconnect() => {
+                    warn!("init after reconnect failed {}", e);
+                    sleep(RECONNECT_RETRY_DELAY);
+                }
+                Err(e) => {
+                    warn!("init after reconnect failed {}", e);
+                    return false;
+                }
+            };
         }
 
-        self.packet_pool
-            .seek(SeekFrom::End(0))
-            .expect("packet pool seek to end");
+        false
+    }
+
+    fn reset(&mut self) {
+        self.audio_format = None;
+        self.audio_format_sent = false;
+        self.clock = None;
+        self.need_clock_ref = None;
+        self.local_audio_clock = None;
+        self.device_audio_clock_ref = None;
+        self.start_time_local_audio_clock = None;
+        self.last_eat_frame_received_local_audio_clock = None;
+        self.start_time_device_audio_clock = None;
+        self.last_eat_frame_received_device_audio_clock = None;
+        self.packets = PacketBuffer::new();
+        // the new session starts its clocks over, the outputs carry on where they were
+        self.video_timeline.jump();
+        self.audio_timeline.jump();
+        self.session.reset();
+        self.rels_received = 0;
+        self.stopped = false;
+    }
 
-        match self.packet_pool.write(&buffer[..buffer_size]) {
+    fn read(&mut self) -> Result<Option<Message>, QTError> {
+        // one usb transfer can carry several packets, hand those out before reading again
+        match self.next_message() {
+            Ok(Some(msg)) => return Ok(Some(msg)),
+            Ok(None) => {}
             Err(e) => return Err(e),
-            _ => {}
         };
 
-        self.packet_pool
-            .seek(SeekFrom::Start(0))
-            .expect("packet pool seek to start");
-
-        let pkt_len = match self.packet_pool.read_u32::<LittleEndian>() {
+        let chunk = match self.device.read_bytes() {
             Ok(e) => e,
             Err(e) => return Err(e),
         };
 
-        let pool_len = self
-            .packet_pool
-            .seek(SeekFrom::End(0))
-            .expect("packet pool seek to end");
-
-        if pool_len >= pkt_len as u64 {
-            self.packet_pool
-                .seek(SeekFrom::Start(0))
-                .expect("packet pool seek to start");
-
-            let mut pkt_buffer: Vec<u8> = vec![0; pkt_len as usize];
-            self.packet_pool
-                .read_exact(&mut pkt_buffer)
-                .expect("packet pool read");
-
-            let pkt = QTPacket::from_bytes(&pkt_buffer).expect("qt packet from bytes");
+        if chunk.is_empty() {
+            return Ok(None);
+        }
 
-            let remain = self.packet_pool.fill_buf().expect("remain");
+        self.packets.push(chunk);
+        self.next_message()
+    }
 
-            self.packet_pool = Cursor::new(Vec::from(remain));
+    fn next_message(&mut self) -> Result<Option<Message>, QTError> {
+        match self.packets.next_packet() {
+            Ok(Some(data)) => match Message::decode_shared(data.clone()) {
+                Ok(msg) => {
+                    if let Some(trace) = &self.trace {
+                        trace.message(true, &msg);
+                    }
+                    Ok(Some(msg))
+                }
+                Err(e) => {
+                    if let Some(trace) = &self.trace {
+                        trace.undecoded(true, data.as_ref(), &e);
+                    }
+                    Err(e)
+                }
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
-            return Ok(Some(pkt));
+    fn send(&mut self, msg: &Message) -> Result<usize, QTError> {
+        if let Some(trace) = &self.trace {
+            trace.message(false, msg);
         }
 
-        Ok(None)
+        self.device.write_frame(&msg.encode())
     }
 
-    fn write(&self, data: &mut QTPacket) -> Result<usize, Error> {
-        let buf = match data.as_bytes() {
-            Ok(d) => d,
-            Err(_) => return Err(Error::new(ErrorKind::InvalidData, "packet as_bytes")),
-        };
-
-        match self.device.write_bulk(buf) {
-            Ok(e) => Ok(e),
-            Err(e) => Err(Error::new(
-                ErrorKind::BrokenPipe,
-                format!("write bulk {}", e),
-            )),
-        }
+    fn send_asyn(&mut self, clock_ref: u64, msg: AsynMessage) -> Result<usize, QTError> {
+        self.send(&Message::asyn(clock_ref, msg))
     }
 
-    fn handle_pkt(&mut self, pkt: &mut QTPacket, sync: bool) -> Result<(), Error> {
-        let clock_ref = match pkt.read_u64() {
+    fn handle_message(&mut self, msg: Message) -> Result<(), QTError> {
+        let magic = msg.magic();
+
+        match self.session.check(magic) {
             Err(e) => return Err(e),
-            Ok(e) => e,
+            _ => {}
         };
 
-        let magic = match pkt.read_u32() {
-            Ok(e) => e,
-            Err(e) => return Err(e),
+        let res = match msg {
+            // ping request, sent back as it came
+            Message::Ping { .. } => match self.send(&msg) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            },
+            Message::Sync {
+                clock_ref,
+                correlation_id,
+                message,
+            } => self.handle_sync_pkt(message, clock_ref, correlation_id),
+            Message::Asyn { clock_ref, message } => self.handle_asyn_pkt(message, clock_ref),
+            Message::Reply { .. } => Err(QTError::UnknownMagic(magic)),
         };
 
-        match sync {
-            true => {
-                let correlation_id = match pkt.read_u64() {
-                    Ok(m) => m,
-                    Err(e) => return Err(e),
-                };
-                self.handle_sync_pkt(pkt, clock_ref, magic, correlation_id)
-            }
-            false => self.handle_asyn_pkt(pkt, clock_ref, magic),
+        if res.is_ok() {
+            self.session.advance(magic);
         }
+
+        res
     }
 
     fn handle_sync_pkt(
         &mut self,
-        pkt: &mut QTPacket,
+        msg: SyncMessage,
         clock_ref: u64,
-        magic: u32,
         correlation_id: u64,
-    ) -> Result<(), Error> {
-        match magic {
-            qt_pkt::SYNC_PACKET_MAGIC_CWPA => {
-                let cwpa_pkt = match qt_pkt::QTPacketCWPA::from_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                let device_clock_ref = cwpa_pkt.device_clock_ref() + 1000;
+    ) -> Result<(), QTError> {
+        match msg {
+            SyncMessage::Cwpa { device_clock_ref } => {
+                let host_clock_ref = device_clock_ref + 1000;
 
-                self.local_audio_clock = Some(Clock::new_with_host_time(device_clock_ref));
+                self.local_audio_clock = Some(Clock::new_with_host_time(host_clock_ref));
 
-                self.device_audio_clock_ref = Some(cwpa_pkt.device_clock_ref());
+                self.device_audio_clock_ref = Some(device_clock_ref);
 
-                let mut reply_packet = match cwpa_pkt.reply_packet(correlation_id, device_clock_ref)
-                {
+                let display_device_info = match value_payload(&qt_hpd1_device_info(&self.display)) {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
-                let display_device_info = qt_hpd1_device_info();
-
-                let mut display_pkt =
-                    match QTPacketASYN::new(Some(display_device_info), HPD1, EMPTY_CF_TYPE)
-                        .as_qt_packet()
-                    {
-                        Ok(e) => e,
-                        Err(e) => return Err(e),
-                    };
-
-                match self.write(&mut display_pkt) {
+                match self.send_asyn(EMPTY_CF_TYPE, AsynMessage::Hpd1 { payload: display_device_info }) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
 
-                match self.write(&mut reply_packet) {
+                match self.send(&Message::reply_clock_ref(correlation_id, host_clock_ref)) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
 
                 if !self.no_audio {
-                    let audio_device_info = qt_hpa1_device_info();
-
-                    let mut audio_pkt = match QTPacketASYN::new(
-                        Some(audio_device_info),
-                        HPA1,
-                        cwpa_pkt.device_clock_ref(),
-                    )
-                    .as_qt_packet()
-                    {
+                    let audio_device_info = match value_payload(&qt_hpa1_device_info(&self.audio)) {
                         Ok(e) => e,
-                        Err(e) => return Err(e),
+                        Err(e) => return Err(e.into()),
                     };
 
-                    match self.write(&mut audio_pkt) {
+                    match self.send_asyn(device_clock_ref, AsynMessage::Hpa1 { payload: audio_device_info }) {
                         Err(e) => return Err(e),
                         _ => {}
                     }
                 }
             }
-            qt_pkt::SYNC_PACKET_MAGIC_AFMT => {
-                let afmt_pkt = match QTPacketAFMT::from_packet(pkt) {
+            SyncMessage::Afmt { payload } => {
+                let asd = match AudioStreamDescription::from_qt_packet(&mut QTPacket::from_payload(payload)) {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
+                };
+
+                // kAudioFormatUnsupportedDataFormatError, 'fmt?'. without an accepted format no
+                // audio goes to the outputs
+                let error = match asd.lpcm_error() {
+                    Some(e) => {
+                        warn!("AFMT rejected: {}", e);
+                        0x666D743F
+                    }
+                    None if !self.audio.is_offered(&asd) => {
+                        warn!(
+                            "AFMT rejected: {} Hz {} channels was not offered",
+                            asd.sample_rate(),
+                            asd.channels_per_frame()
+                        );
+                        0x666D743F
+                    }
+                    None => {
+                        info!(
+                            "audio format {} Hz {} channels {}",
+                            asd.sample_rate(),
+                            asd.channels_per_frame(),
+                            asd.ffmpeg_format().unwrap_or("lpcm")
+                        );
+                        self.audio_format = Some(asd.clone());
+                        0
+                    }
                 };
 
-                let mut reply_packet = match afmt_pkt.reply_packet(correlation_id) {
+                // error 0 accepts the proposed format
+                let arr: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+                    QTValue::StringKey(String::from("Error")),
+                    QTValue::UInt32(error),
+                ))];
+
+                let reply = match Message::reply_value(correlation_id, &QTValue::Object(arr)) {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
-                match self.write(&mut reply_packet) {
+                match self.send(&reply) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
             }
-            qt_pkt::SYNC_PACKET_MAGIC_CVRP => {
-                let cvrp_pkt = match qt_pkt::QTPacketCVRP::from_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                self.need_clock_ref = Some(cvrp_pkt.device_clock_ref());
+            SyncMessage::Cvrp {
+                device_clock_ref, ..
+            } => {
+                self.need_clock_ref = Some(device_clock_ref);
 
-                let mut need_pkt = match QTPacketASYN::new(None, NEED, cvrp_pkt.device_clock_ref())
-                    .as_qt_packet()
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                match self.write(&mut need_pkt) {
+                match self.send_asyn(device_clock_ref, AsynMessage::Need) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
 
-                let device_clock_ref = cvrp_pkt.device_clock_ref() + 0x1000AF;
+                let host_clock_ref = device_clock_ref + 0x1000AF;
 
-                let mut reply_packet = match cvrp_pkt.reply_packet(correlation_id, device_clock_ref)
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                match self.write(&mut reply_packet) {
+                match self.send(&Message::reply_clock_ref(correlation_id, host_clock_ref)) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
             }
-            qt_pkt::SYNC_PACKET_MAGIC_CLOK => {
+            SyncMessage::Clok => {
                 let host_time = clock_ref + 0x10000;
 
                 self.clock = Some(Clock::new_with_host_time(host_time));
 
-                let mut reply_packet =
-                    match QTPacketCLOCK::new().reply_packet(correlation_id, host_time) {
-                        Err(e) => return Err(e),
-                        Ok(e) => e,
-                    };
-
-                match self.write(&mut reply_packet) {
+                match self.send(&Message::reply_clock_ref(correlation_id, host_time)) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
             }
-            qt_pkt::SYNC_PACKET_MAGIC_TIME => {
-                let mut reply_packet = match QTPacketTIME::new().reply_packet(
-                    correlation_id,
-                    self.clock.as_ref().expect("clock none").get_time(),
-                ) {
-                    Err(e) => return Err(e),
+            SyncMessage::Time => {
+                let clock = match self.clock.as_ref() {
+                    Some(e) => e,
+                    None => return Err(QTError::Protocol(String::from("TIME before CLOK"))),
+                };
+
+                let reply = match Message::reply_time(correlation_id, &clock.get_time()) {
+                    Err(e) => return Err(e.into()),
                     Ok(e) => e,
                 };
 
-                match self.write(&mut reply_packet) {
+                match self.send(&reply) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
             }
-            qt_pkt::SYNC_PACKET_MAGIC_SKEW => {
-                let stlac = self
-                    .start_time_local_audio_clock
-                    .as_ref()
-                    .expect("start_time_local_audio_clock None");
-
-                let stdac = self
-                    .start_time_device_audio_clock
-                    .as_ref()
-                    .expect("start_time_device_audio_clock None");
-
-                let lefrlac = self
-                    .last_eat_frame_received_local_audio_clock
-                    .as_ref()
-                    .expect("last_eat_frame_received_local_audio_clock None");
-
-                let lefrdac = self
-                    .last_eat_frame_received_device_audio_clock
-                    .as_ref()
-                    .expect("last_eat_frame_received_device_audio_clock None");
+            SyncMessage::Skew => {
+                let (stlac, stdac, lefrlac, lefrdac) = match (
+                    self.start_time_local_audio_clock.as_ref(),
+                    self.start_time_device_audio_clock.as_ref(),
+                    self.last_eat_frame_received_local_audio_clock.as_ref(),
+                    self.last_eat_frame_received_device_audio_clock.as_ref(),
+                ) {
+                    (Some(stlac), Some(stdac), Some(lefrlac), Some(lefrdac)) => {
+                        (stlac, stdac, lefrlac, lefrdac)
+                    }
+                    _ => return Err(QTError::Protocol(String::from("SKEW before EAT!"))),
+                };
 
                 let skew = Clock::calculate_skew(stlac, lefrlac, stdac, lefrdac);
 
-                let mut pkt = match QTPacketSKEW::new().reply_packet(correlation_id, skew) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                match self.write(&mut pkt) {
+                match self.send(&Message::reply_skew(correlation_id, skew)) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
             }
-            qt_pkt::SYNC_PACKET_MAGIC_OG => {
-                let og_pkt = match qt_pkt::QTPacketOG::from_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                let mut reply_packet = match og_pkt.reply_packet(correlation_id) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                match self.write(&mut reply_packet) {
+            SyncMessage::Og { .. } => {
+                match self.send(&Message::reply_u32(correlation_id, 0)) {
                     Err(e) => return Err(e),
                     _ => {}
                 }
             }
-            qt_pkt::SYNC_PACKET_MAGIC_STOP => {
-                let mut pkt = match QTPacketSTOP::new().reply_packet(correlation_id) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                };
-
-                match self.write(&mut pkt) {
+            SyncMessage::Stop => {
+                match self.send(&Message::reply_u32(correlation_id, 0)) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
             }
-            _ => {
-                println!("SYNC_UNKNOWN_MAGIC - {}", magic);
-            }
+            SyncMessage::Unknown { magic, .. } => return Err(QTError::UnknownMagic(magic)),
         };
 
         Ok(())
     }
 
-    fn handle_asyn_pkt(
-        &mut self,
-        pkt: &mut QTPacket,
-        _clock_ref: u64,
-        magic: u32,
-    ) -> Result<(), Error> {
-        match magic {
-            qt_pkt::ASYN_PACKET_MAGIC_EAT => {
-                let sample_buffer = match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_SOUND) {
+    fn handle_asyn_pkt(&mut self, msg: AsynMessage, clock_ref: u64) -> Result<(), QTError> {
+        match msg {
+            AsynMessage::Eat { payload } => {
+                let mut sample_buffer = match SampleBuffer::from_qt_packet(&mut QTPacket::from_payload(payload), MEDIA_TYPE_SOUND) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 };
 
+                let local_time = match self.local_audio_clock.as_ref() {
+                    Some(e) => e.get_time(),
+                    None => return Err(QTError::Protocol(String::from("EAT! before CWPA"))),
+                };
+
                 if self.last_eat_frame_received_device_audio_clock.is_none() {
                     self.start_time_device_audio_clock =
                         sample_buffer.output_presentation_time_stamp();
-                    self.start_time_local_audio_clock = Some(
-                        self.local_audio_clock
-                            .as_ref()
-                            .expect("local audio clock")
-                            .get_time(),
-                    );
+                    self.start_time_local_audio_clock = Some(local_time);
                     self.last_eat_frame_received_device_audio_clock =
                         sample_buffer.output_presentation_time_stamp();
                     self.last_eat_frame_received_local_audio_clock =
@@ -437,74 +472,143 @@ impl QuickTime {
                 } else {
                     self.last_eat_frame_received_device_audio_clock =
                         sample_buffer.output_presentation_time_stamp();
-                    self.last_eat_frame_received_local_audio_clock = Some(
-                        self.local_audio_clock
-                            .as_ref()
-                            .expect("invalid lac")
-                            .get_time(),
-                    );
+                    self.last_eat_frame_received_local_audio_clock = Some(local_time);
                 }
 
+                // skew is worked out on the device clock, the outputs get the continuous one
+                self.audio_timeline.apply(&mut sample_buffer);
+
                 self.handle_audio_sample(sample_buffer)?;
             }
-            qt_pkt::ASYN_PACKET_MAGIC_FEED => {
-                let sample_buffer = match SampleBuffer::from_qt_packet(pkt, MEDIA_TYPE_VIDEO) {
+            AsynMessage::Feed { payload } => {
+                let mut sample_buffer = match SampleBuffer::from_qt_packet(&mut QTPacket::from_payload(payload), MEDIA_TYPE_VIDEO) {
                     Ok(e) => e,
                     Err(e) => return Err(e),
                 };
 
-                let mut pkt = match QTPacketASYN::new(
-                    None,
-                    NEED,
-                    self.need_clock_ref.expect("need clock ref"),
-                )
-                .as_qt_packet()
-                {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
+                // HPD0 already sent, the device is flushing
+                if self.session.is_closing() {
+                    return Ok(());
+                }
+
+                let need_clock_ref = match self.need_clock_ref {
+                    Some(e) => e,
+                    None => return Err(QTError::Protocol(String::from("FEED before CVRP"))),
                 };
 
-                match self.write(&mut pkt) {
+                match self.send_asyn(need_clock_ref, AsynMessage::Need) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
 
-                match self.video_tx.send(Ok(sample_buffer)) {
-                    Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+                let empty = match sample_buffer.sample_data() {
+                    Some(data) => data.is_empty(),
+                    None => true,
+                };
+                if empty && self.session.skip_empty_media() {
+                    return Ok(());
+                }
+
+                self.video_timeline.apply(&mut sample_buffer);
+
+                match self.video_tx.send_sample(sample_buffer) {
+                    Err(e) => return Err(QTError::Sink(format!("video {}", e))),
                     _ => {}
                 };
             }
-            qt_pkt::ASYN_PACKET_MAGIC_SPRP => {}
-            qt_pkt::ASYN_PACKET_MAGIC_SRAT => {}
-            qt_pkt::ASYN_PACKET_MAGIC_TBAS => {}
-            qt_pkt::ASYN_PACKET_MAGIC_TJMP => {}
-            qt_pkt::ASYN_PACKET_MAGIC_RELS => {}
-            qt_pkt::ASYN_PACKET_MAGIC_HPA0 => {}
-            qt_pkt::ASYN_PACKET_MAGIC_HPD0 => {}
+            AsynMessage::Sprp { payload } => {
+                let value = match QTValue::from_qt_packet(&mut QTPacket::from_payload(payload)) {
+                    Ok(e) => e,
+                    Err(e) => return Err(e),
+                };
+
+                let property = match value.as_pair() {
+                    Some(e) => e,
+                    None => return Err(QTError::MalformedValue(String::from("SPRP without key value pair"))),
+                };
+
+                let key = match property.key().as_string() {
+                    Some(e) => e,
+                    None => return Err(QTError::MalformedValue(String::from("SPRP key"))),
+                };
+
+                if !self.session.set_property(&key, property.value()) {
+                    warn!("ignored property {}", property.value().to_str(format!("{} ", key)));
+                }
+            }
+            AsynMessage::Srat { rate1, time, .. } => {
+                for timeline in self.timelines(clock_ref) {
+                    let paused = timeline.is_paused();
+                    if !timeline.set_rate(rate1, &time) {
+                        warn!("clock {:x} rate {} ignored", clock_ref, rate1);
+                        continue;
+                    }
+
+                    if paused != timeline.is_paused() {
+                        match paused {
+                            true => info!("clock {:x} resumed", clock_ref),
+                            false => info!("clock {:x} paused", clock_ref),
+                        };
+                    }
+                }
+            }
+            AsynMessage::Tbas { time_base_ref } => {
+                info!("clock {:x} time base {:x}", clock_ref, time_base_ref);
+            }
+            AsynMessage::Tjmp { payload } => {
+                info!("clock {:x} jumped {}", clock_ref, hex::encode_upper(payload.as_ref()));
+
+                for timeline in self.timelines(clock_ref) {
+                    timeline.jump();
+                }
+            }
+            AsynMessage::Rels => {
+                self.rels_received += 1;
+
+                // the device let go of a clock without being asked, it stopped sharing. answer
+                // like we would on ctrl-c, run then waits for the rest of the teardown
+                if !self.session.is_closing() {
+                    info!("device released clock {:x}, closing session", clock_ref);
+                    match self.close_session() {
+                        Err(e) => return Err(e),
+                        _ => {}
+                    };
+                }
+            }
             _ => {}
         }
         Ok(())
     }
 
-    fn close_session(&mut self) -> Result<(), Error> {
-        match self.device_audio_clock_ref {
-            Some(clock) => {
-                let mut off_audio = match QTPacketASYN::new(None, HPA0, clock).as_qt_packet() {
-                    Err(e) => return Err(e),
-                    Ok(e) => e,
-                };
+    // SRAT and TJMP name the clock they move, the audio clock or the video one. anything else
+    // moves both
+    fn timelines(&mut self, clock_ref: u64) -> Vec<&mut Timeline> {
+        if Some(clock_ref) == self.device_audio_clock_ref {
+            return Vec::from([&mut self.audio_timeline]);
+        }
 
-                let mut off_display = match QTPacketASYN::new(None, HPD0, 1).as_qt_packet() {
-                    Err(e) => return Err(e),
-                    Ok(e) => e,
-                };
+        if Some(clock_ref) == self.need_clock_ref {
+            return Vec::from([&mut self.video_timeline]);
+        }
+
+        Vec::from([&mut self.video_timeline, &mut self.audio_timeline])
+    }
+
+    fn close_session(&mut self) -> Result<(), QTError> {
+        if self.session.is_closing() {
+            return Ok(());
+        }
+
+        self.session.enter(Phase::Closing);
 
-                match self.write(&mut off_audio) {
+        match self.device_audio_clock_ref {
+            Some(clock) => {
+                match self.send_asyn(clock, AsynMessage::Hpa0) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
 
-                match self.write(&mut off_display) {
+                match self.send_asyn(EMPTY_CF_TYPE, AsynMessage::Hpd0) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
@@ -515,76 +619,194 @@ impl QuickTime {
         Ok(())
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        while !self.term.load(Ordering::Relaxed) {
-            // ping request
-            let o_pkt = match self.read() {
-                Ok(e) => e,
-                Err(e) => return Err(e),
+    pub fn run(&mut self) -> Result<(), QTError> {
+        // ends on ctrl-c, or when the device closes the session from its side
+        while !self.term.load(Ordering::Relaxed) && !self.session.is_closing() {
+            match self.run_once() {
+                // a replay ran out, there is no device left to close the session with
+                Err(QTError::EndOfStream) => return Ok(()),
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => warn!("drop packet: {}", e),
+                _ => {}
             };
+        }
 
-            if o_pkt.is_none() {
-                continue;
-            }
-
-            let mut pkt = o_pkt.unwrap();
+        self.shutdown()
+    }
 
-            let magic = match pkt.read_u32() {
-                Ok(m) => m,
-                Err(_) => return Err(Error::new(ErrorKind::InvalidData, "read magic failed")),
-            };
+    fn shutdown(&mut self) -> Result<(), QTError> {
+        match self.close_session() {
+            Err(e) => return Err(e),
+            _ => {}
+        };
 
-            match magic {
-                qt_pkt::PACKET_MAGIC_PING => {
-                    pkt.borrow_mut().seek(SeekFrom::Start(0)).expect("seek");
-                    self.write(&mut pkt).expect("write ping");
-                }
-                qt_pkt::PACKET_MAGIC_SYNC => {
-                    self.handle_pkt(&mut pkt, true).expect("sync");
-                }
-                qt_pkt::PACKET_MAGIC_ASYN => {
-                    self.handle_pkt(&mut pkt, false).expect("asyn");
-                }
-                _ => {
-                    println!("magic: PACKET_MAGIC_UNKNOWN {:#2X?}", magic);
-                }
+        // device answers HPD0 with a STOP sync and RELS for the video and clok clocks
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while !(self.session.phase() == Phase::Stopped && self.rels_received >= 2) && Instant::now() < deadline {
+            match self.run_once() {
+                Err(QTError::EndOfStream) => return Ok(()),
+                Err(e) if e.is_fatal() => return Err(e),
+                Err(e) => warn!("drop packet: {}", e),
+                _ => {}
             };
         }
 
         Ok(())
     }
 
+    fn run_once(&mut self) -> Result<(), QTError> {
+        match self.read() {
+            Ok(Some(msg)) => self.handle_message(msg),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn stop(&mut self) {
-        println!("stop qt");
-        self.close_session().expect("close session failed");
-
-        match self.device.is_qt_enabled() {
-            Ok(enabled) => {
-                if enabled {
-                    match self.device.set_qt_enabled(!enabled) {
-                        Err(e) => {
-                            println!("set_qt_disabled failed {}", e);
-                        }
-                        _ => {}
-                    }
-                }
+        if self.stopped {
+            return;
+        }
+
+        self.stopped = true;
+
+        info!("stop qt");
+        match self.close_session() {
+            Err(e) => {
+                warn!("close session failed {}", e);
             }
+            _ => {}
+        };
+
+        match self.device.teardown() {
             Err(e) => {
-                println!("dispose failed {}", e);
+                warn!("teardown failed {}", e);
             }
+            _ => {}
         };
     }
 
-    fn handle_audio_sample(&self, sample: SampleBuffer) -> Result<(), Error> {
-        if self.audio_connected.load(Ordering::SeqCst) {
-            self.audio_tx.send(Ok(sample)).map_err(|_| Error::new(ErrorKind::BrokenPipe, "send error"))?;
+    fn handle_audio_sample(&mut self, mut sample: SampleBuffer) -> Result<(), QTError> {
+        // AFMT was rejected, the device may still send EAT! but nobody agreed on what it holds
+        if self.audio_format.is_none() {
+            return Ok(());
+        }
+
+        if !self.session.is_closing() && self.audio_connected.load(Ordering::SeqCst) {
+            // the first sample the outputs see carries the negotiated format
+            if !self.audio_format_sent {
+                if sample.format_description().is_none() {
+                    if let Some(asd) = &self.audio_format {
+                        sample.set_format_description(FormatDescriptor::new_audio(asd.clone()));
+                    }
+                }
+                self.audio_format_sent = sample.format_description().is_some();
+            }
+
+            match self.audio_tx.send_sample(sample) {
+                Err(e) => return Err(QTError::Sink(format!("audio {}", e))),
+                _ => {}
+            };
         }
         Ok(())
     }
 }
 
-impl Drop for QuickTime {
+impl<T: Transport> Drop for QuickTime<T> {
     fn drop(&mut self) {
         self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_buffer::PacketBytes;
+    use crate::qt_device::DisplayConfig;
+    use std::sync::mpsc::sync_channel;
+
+    const CORRELATION_ID: u64 = 0x113229D80;
+
+    // keeps what the host writes, nothing ever arrives from the device
+    struct RecordingTransport {
+        written: Vec<Vec<u8>>,
+    }
+
+    impl Transport for RecordingTransport {
+        fn init(&mut self) -> Result<(), QTError> {
+            Ok(())
+        }
+
+        fn max_read_packet_size(&self) -> usize {
+            0
+        }
+
+        fn read_frame(&mut self, _buf: &mut [u8]) -> Result<usize, QTError> {
+            Ok(0)
+        }
+
+        fn write_frame(&mut self, buf: &[u8]) -> Result<usize, QTError> {
+            self.written.push(Vec::from(buf));
+            Ok(buf.len())
+        }
+
+        fn teardown(&mut self) -> Result<(), QTError> {
+            Ok(())
+        }
+    }
+
+    fn quicktime(audio: AudioConfig) -> QuickTime<RecordingTransport> {
+        let (video_tx, _) = sync_channel::<Result<SampleBuffer, QTError>>(1);
+        let (audio_tx, _) = sync_channel::<Result<SampleBuffer, QTError>>(1);
+
+        QuickTime::new(
+            RecordingTransport { written: Vec::new() },
+            Box::new(video_tx),
+            Box::new(audio_tx),
+            &MirrorConfig::new(DisplayConfig::default(), audio),
+        )
+    }
+
+    // sends the proposal and returns the encoded reply
+    fn propose(qt: &mut QuickTime<RecordingTransport>, asd: &AudioStreamDescription) -> Vec<u8> {
+        let payload = PacketBytes::from_vec(asd.as_buffer().unwrap());
+        qt.handle_sync_pkt(SyncMessage::Afmt { payload }, 0x7FA66CE20CB0, CORRELATION_ID)
+            .unwrap();
+
+        assert_eq!(qt.device.written.len(), 1);
+        qt.device.written.remove(0)
+    }
+
+    fn error_reply(error: u32) -> Vec<u8> {
+        let arr: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(String::from("Error")),
+            QTValue::UInt32(error),
+        ))];
+        Message::reply_value(CORRELATION_ID, &QTValue::Object(arr)).unwrap().encode()
+    }
+
+    #[test]
+    fn afmt_accepts_an_offered_format() {
+        let offered = AudioStreamDescription::lpcm(44100f64, 2, 16, false);
+        let mut qt = quicktime(AudioConfig::new(Vec::from([
+            AudioStreamDescription::default(),
+            offered.clone(),
+        ])));
+
+        assert_eq!(propose(&mut qt, &offered), error_reply(0));
+        assert!(qt.audio_format.as_ref().unwrap().same_format(&offered));
+    }
+
+    #[test]
+    fn afmt_refuses_a_format_that_was_not_offered() {
+        let mut qt = quicktime(AudioConfig::default());
+
+        let reply = propose(&mut qt, &AudioStreamDescription::lpcm(44100f64, 2, 16, false));
+        assert_eq!(reply, error_reply(0x666D743F));
+        assert!(qt.audio_format.is_none());
+
+        // valid lpcm the device could have picked is still refused when it is not ours
+        let reply = propose(&mut qt, &AudioStreamDescription::lpcm(48000f64, 2, 32, true));
+        assert_eq!(reply, error_reply(0x666D743F));
+        assert!(qt.audio_format.is_none());
+    }
+}
@@ -1,7 +1,154 @@
+#![allow(clippy::question_mark)]
+
 use crate::coremedia::audio_desc::AudioStreamDescription;
 use crate::qt_value::{QTKeyValuePair, QTValue};
 
-pub fn qt_hpd1_device_info() -> QTValue {
+// what the host announces in HPD1, the device picks its stream size and codec from it
+#[derive(Clone)]
+pub struct DisplayConfig {
+    width: u32,
+    height: u32,
+    hevc: bool,
+    // any other HPD1 key, the value is sent as a bool, number or string
+    options: Vec<(String, String)>,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            width: 1920,
+            height: 1200,
+            hevc: true,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl DisplayConfig {
+    pub fn new(width: u32, height: u32, hevc: bool) -> DisplayConfig {
+        DisplayConfig {
+            width,
+            height,
+            hevc,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn hevc(&self) -> bool {
+        self.hevc
+    }
+
+    pub fn set_size(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn set_hevc(&mut self, hevc: bool) {
+        self.hevc = hevc;
+    }
+
+    // replaces the value if the key was set before
+    pub fn set_option(&mut self, key: &str, value: &str) {
+        self.options.retain(|(k, _)| k != key);
+        self.options.push((String::from(key), String::from(value)));
+    }
+
+    pub fn options(&self) -> &Vec<(String, String)> {
+        &self.options
+    }
+}
+
+// parses "1280x720"
+pub fn parse_display_size(s: &str) -> Option<(u32, u32)> {
+    let (w, h) = match s.split_once('x') {
+        Some(e) => e,
+        None => return None,
+    };
+
+    match (w.parse::<u32>(), h.parse::<u32>()) {
+        (Ok(w), Ok(h)) if w > 0 && h > 0 => Some((w, h)),
+        _ => None,
+    }
+}
+
+fn option_value(value: &str) -> QTValue {
+    match value {
+        "true" => return QTValue::Boolean(true),
+        "false" => return QTValue::Boolean(false),
+        _ => {}
+    };
+
+    match value.parse::<f64>() {
+        Ok(f) => QTValue::Float(f),
+        Err(_) => QTValue::StringValue(String::from(value)),
+    }
+}
+
+// lpcm formats offered to the device in HPA1, it picks one and proposes it back in AFMT
+#[derive(Clone)]
+pub struct AudioConfig {
+    formats: Vec<AudioStreamDescription>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            formats: Vec::from([AudioStreamDescription::default()]),
+        }
+    }
+}
+
+impl AudioConfig {
+    pub fn new(formats: Vec<AudioStreamDescription>) -> AudioConfig {
+        AudioConfig { formats }
+    }
+
+    pub fn formats(&self) -> &Vec<AudioStreamDescription> {
+        &self.formats
+    }
+
+    pub fn is_offered(&self, asd: &AudioStreamDescription) -> bool {
+        self.formats.iter().any(|f| f.same_format(asd))
+    }
+}
+
+// parses "48000:2:s16le", rate:channels:ffmpeg sample format
+pub fn parse_audio_format(s: &str) -> Option<AudioStreamDescription> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let sample_rate = match parts[0].parse::<u32>() {
+        Ok(e) if e > 0 => e,
+        _ => return None,
+    };
+
+    let channels = match parts[1].parse::<u32>() {
+        Ok(e) if e > 0 && e <= 8 => e,
+        _ => return None,
+    };
+
+    let (bits, float) = match parts[2] {
+        "s16le" => (16, false),
+        "s24le" => (24, false),
+        "s32le" => (32, false),
+        "f32le" => (32, true),
+        _ => return None,
+    };
+
+    Some(AudioStreamDescription::lpcm(sample_rate as f64, channels, bits, float))
+}
+
+pub fn qt_hpd1_device_info(config: &DisplayConfig) -> QTValue {
     let mut arr: Vec<QTValue> = Vec::new();
     let mut display_arr: Vec<QTValue> = Vec::new();
 
@@ -12,17 +159,17 @@ pub fn qt_hpd1_device_info() -> QTValue {
 
     arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
         QTValue::StringKey(String::from("HEVCDecoderSupports444")),
-        QTValue::Boolean(true),
+        QTValue::Boolean(config.hevc),
     )));
 
     display_arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
         QTValue::StringKey(String::from("Width")),
-        QTValue::Float(1920f64),
+        QTValue::Float(config.width as f64),
     )));
 
     display_arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
         QTValue::StringKey(String::from("Height")),
-        QTValue::Float(1200f64),
+        QTValue::Float(config.height as f64),
     )));
 
     arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
@@ -30,15 +177,24 @@ pub fn qt_hpd1_device_info() -> QTValue {
         QTValue::Object(display_arr),
     )));
 
+    for (key, value) in config.options.iter() {
+        arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(key.clone()),
+            option_value(value),
+        )));
+    }
+
     QTValue::Object(arr)
 }
 
-pub fn qt_hpa1_device_info() -> QTValue {
+pub fn qt_hpa1_device_info(config: &AudioConfig) -> QTValue {
     let mut arr: Vec<QTValue> = Vec::new();
 
-    let buffer = AudioStreamDescription::default()
-        .as_buffer()
-        .expect("audio stream description failed");
+    // one ranged description per format, back to back
+    let mut buffer: Vec<u8> = Vec::new();
+    for asd in config.formats.iter() {
+        buffer.extend_from_slice(&asd.as_buffer().expect("audio stream description failed"));
+    }
 
     arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
         QTValue::StringKey(String::from("BufferAheadInterval")),
@@ -1,21 +1,36 @@
-use crate::coremedia::audio_desc::AudioStreamDescription;
+#![allow(clippy::needless_return, clippy::question_mark, clippy::single_match)]
+
 use crate::coremedia::time::Time;
-use crate::qt_value::{QTKeyValuePair, QTValue};
+use crate::error::QTError;
+use crate::packet_buffer::PacketBytes;
+use crate::qt_value::QTValue;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::borrow::BorrowMut;
 use std::fmt::{Debug, Formatter};
 use std::io;
-use std::io::{BufRead, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, Cursor, Error, Read, Seek, SeekFrom, Write};
+
+// packets we build are written into an owned buffer, packets from the device are read in place
+// from the transport buffer
+enum PacketData {
+    Owned(Cursor<Vec<u8>>),
+    Shared(Cursor<PacketBytes>),
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
 
 pub struct QTPacket {
-    inner: Cursor<Vec<u8>>,
+    inner: PacketData,
 }
 
 impl QTPacket {
     pub fn new() -> QTPacket {
         let mut cur = Cursor::new(Vec::from([0, 0, 0, 0]));
         cur.seek(SeekFrom::End(0)).expect("cur seek");
-        return QTPacket { inner: cur };
+        return QTPacket {
+            inner: PacketData::Owned(cur),
+        };
     }
 
     pub fn new_with_magic(magic: u32) -> QTPacket {
@@ -24,22 +39,27 @@ impl QTPacket {
         pkt
     }
 
-    pub fn read_qt_packet(pkt: &mut QTPacket, size: usize) -> Result<QTPacket, Error> {
+    pub fn read_qt_packet(pkt: &mut QTPacket, size: usize) -> Result<QTPacket, QTError> {
+        if let Some(data) = pkt.take_shared(size) {
+            // keeps the same layout as below, 4 bytes of length, then the data
+            return Ok(QTPacket::from_shared_at(data, 4));
+        }
+
         let mut data: Vec<u8> = vec![0; size];
         match pkt.read_exact(&mut data) {
             Ok(e) => e,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
         let mut new_pkt = QTPacket::new();
         match new_pkt.write(data.as_slice()) {
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
             _ => {}
         };
 
         // restore position
-        match new_pkt.inner.seek(SeekFrom::Start(4)) {
-            Err(e) => return Err(e),
+        match new_pkt.reader().seek(SeekFrom::Start(4)) {
+            Err(e) => return Err(e.into()),
             _ => {}
         };
 
@@ -49,7 +69,7 @@ impl QTPacket {
     pub fn from_qt_packet_with_magic(
         pkt: &mut QTPacket,
         magic: u32,
-    ) -> Result<(QTPacket, u32), Error> {
+    ) -> Result<(QTPacket, u32), QTError> {
         let mut val_pkt = match QTPacket::from_qt_packet(pkt) {
             Ok(e) => e,
             Err(e) => return Err(e),
@@ -57,17 +77,17 @@ impl QTPacket {
 
         let val_magic = match val_pkt.read_u32() {
             Ok(e) => e,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
         if val_magic != magic {
-            return Err(Error::new(ErrorKind::InvalidData, "magic not compare"));
+            return Err(QTError::UnknownMagic(val_magic));
         }
 
         Ok((val_pkt, val_magic))
     }
 
-    pub fn read_qt_packet_with_magic(&mut self) -> Result<(QTPacket, u32), Error> {
+    pub fn read_qt_packet_with_magic(&mut self) -> Result<(QTPacket, u32), QTError> {
         let mut pkt = match QTPacket::from_qt_packet(self) {
             Ok(e) => e,
             Err(e) => return Err(e),
@@ -75,77 +95,200 @@ impl QTPacket {
 
         let magic = match pkt.read_u32() {
             Ok(e) => e,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
         Ok((pkt, magic))
     }
 
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<QTPacket, Error> {
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<QTPacket, QTError> {
         let read_pkt_len = match pkt.read_u32() {
             Ok(e) => e,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
-        let pkt_len = match pkt.len() {
-            Err(e) => return Err(e),
+        let remain = match pkt.len() {
+            Err(e) => return Err(e.into()),
             Ok(e) => e,
-        } as u32;
+        } - pkt.pos()
+            + 4;
+
+        if read_pkt_len < 4 || remain < read_pkt_len as u64 {
+            return Err(QTError::Framing(format!(
+                "qt package length {} not compare data size {}",
+                read_pkt_len, remain
+            )));
+        }
 
-        if pkt_len < read_pkt_len {
-            return Err(Error::new(
-                ErrorKind::UnexpectedEof,
-                "qt package length not compare data size",
-            ));
+        if let Some(data) = pkt.take_shared(read_pkt_len as usize - 4) {
+            return Ok(QTPacket::from_shared_at(data, 4));
         }
 
         let mut buffer: Vec<u8> = vec![0; read_pkt_len as usize];
 
-        if read_pkt_len > 0 {
-            match pkt.read_exact(&mut buffer[4..]) {
-                Err(e) => return Err(e),
-                _ => {}
-            };
-        }
+        match pkt.read_exact(&mut buffer[4..]) {
+            Err(e) => return Err(e.into()),
+            _ => {}
+        };
 
         let mut cur = Cursor::new(buffer);
 
-        cur.seek(SeekFrom::Start(4)).expect("cur seek");
+        match cur.seek(SeekFrom::Start(4)) {
+            Err(e) => return Err(e.into()),
+            _ => {}
+        };
 
-        Ok(QTPacket { inner: cur })
+        Ok(QTPacket {
+            inner: PacketData::Owned(cur),
+        })
     }
 
-    pub fn from_bytes(data: &[u8]) -> Result<QTPacket, Error> {
-        let pkt_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
-        if data.len() < pkt_len {
-            return Err(Error::new(
-                ErrorKind::UnexpectedEof,
-                "qt package length not compare data size",
-            ));
+    pub fn from_bytes(data: &[u8]) -> Result<QTPacket, QTError> {
+        if data.len() < 4 {
+            return Err(QTError::Framing(format!(
+                "qt package too short {}",
+                data.len()
+            )));
         }
 
-        // println!("read data {:#2x?}", data);
+        let pkt_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if pkt_len < 4 || data.len() < pkt_len {
+            return Err(QTError::Framing(format!(
+                "qt package length {} not compare data size {}",
+                pkt_len,
+                data.len()
+            )));
+        }
 
         let mut cur = Cursor::new(Vec::from(&data[..pkt_len]));
 
-        cur.seek(SeekFrom::Start(4)).expect("cur seek");
+        match cur.seek(SeekFrom::Start(4)) {
+            Err(e) => return Err(e.into()),
+            _ => {}
+        };
 
-        Ok(QTPacket { inner: cur })
+        Ok(QTPacket {
+            inner: PacketData::Owned(cur),
+        })
+    }
+
+    // same as from_bytes without copying, the packet reads straight from the transport buffer
+    pub fn from_shared(data: PacketBytes) -> Result<QTPacket, QTError> {
+        let bytes = data.as_ref();
+        if bytes.len() < 4 {
+            return Err(QTError::Framing(format!(
+                "qt package too short {}",
+                bytes.len()
+            )));
+        }
+
+        let pkt_len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if pkt_len < 4 || bytes.len() < pkt_len {
+            return Err(QTError::Framing(format!(
+                "qt package length {} not compare data size {}",
+                pkt_len,
+                bytes.len()
+            )));
+        }
+
+        Ok(QTPacket::from_shared_at(data.slice(0, pkt_len), 4))
+    }
+
+    // reads a message payload, values and sample buffers decode from it like from any packet
+    pub fn from_payload(data: PacketBytes) -> QTPacket {
+        QTPacket::from_shared_at(data, 0)
+    }
+
+    fn from_shared_at(data: PacketBytes, pos: u64) -> QTPacket {
+        let mut cur = Cursor::new(data);
+        cur.set_position(pos);
+
+        QTPacket {
+            inner: PacketData::Shared(cur),
+        }
+    }
+
+    // the next size bytes, with the 4 before them standing in for the length, as a slice of a
+    // shared packet. None for owned packets, or when the data is not all there
+    fn take_shared(&mut self, size: usize) -> Option<PacketBytes> {
+        let cur = match &mut self.inner {
+            PacketData::Shared(cur) => cur,
+            PacketData::Owned(_) => return None,
+        };
+
+        let pos = cur.position() as usize;
+        if pos < 4 || pos + size > cur.get_ref().len() {
+            return None;
+        }
+
+        let data = cur.get_ref().slice(pos - 4, pos + size);
+        cur.set_position((pos + size) as u64);
+
+        Some(data)
+    }
+
+    // everything from the position to the end, the packet is read to the end after this
+    fn rest(&mut self) -> PacketBytes {
+        match &mut self.inner {
+            PacketData::Shared(cur) => {
+                let pos = (cur.position() as usize).min(cur.get_ref().len());
+                let data = cur.get_ref().slice(pos, cur.get_ref().len());
+                cur.set_position(cur.get_ref().len() as u64);
+                data
+            }
+            PacketData::Owned(cur) => {
+                let pos = (cur.position() as usize).min(cur.get_ref().len());
+                let data = Vec::from(&cur.get_ref()[pos..]);
+                cur.set_position(cur.get_ref().len() as u64);
+                PacketBytes::from_vec(data)
+            }
+        }
+    }
+
+    fn reader(&mut self) -> &mut dyn ReadSeek {
+        match &mut self.inner {
+            PacketData::Owned(cur) => cur,
+            PacketData::Shared(cur) => cur,
+        }
+    }
+
+    // a shared packet is copied once it is written to, only pings are sent back as they came
+    fn writer(&mut self) -> &mut Cursor<Vec<u8>> {
+        if let PacketData::Shared(cur) = &self.inner {
+            let mut owned = Cursor::new(Vec::from(cur.get_ref().as_ref()));
+            owned.set_position(cur.position());
+            self.inner = PacketData::Owned(owned);
+        }
+
+        match &mut self.inner {
+            PacketData::Owned(cur) => cur,
+            PacketData::Shared(_) => unreachable!(),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.inner {
+            PacketData::Owned(cur) => cur.get_ref().as_slice(),
+            PacketData::Shared(cur) => cur.get_ref().as_ref(),
+        }
     }
 
     pub fn pos(&mut self) -> u64 {
-        return self.inner.position();
+        match &self.inner {
+            PacketData::Owned(cur) => cur.position(),
+            PacketData::Shared(cur) => cur.position(),
+        }
     }
 
     pub fn len(&mut self) -> Result<u64, Error> {
-        let cur = self.inner.position();
+        let cur = self.pos();
 
-        let size = match self.inner.seek(SeekFrom::End(0)) {
+        let size = match self.reader().seek(SeekFrom::End(0)) {
             Ok(e) => e,
             Err(e) => return Err(e),
         };
 
-        match self.inner.seek(SeekFrom::Start(cur)) {
+        match self.reader().seek(SeekFrom::Start(cur)) {
             Err(e) => return Err(e),
             _ => {}
         };
@@ -154,72 +297,77 @@ impl QTPacket {
     }
 
     pub fn write_u8(&mut self, d: u8) -> Result<(), Error> {
-        self.inner.write_u8(d)
+        self.writer().write_u8(d)
     }
 
     pub fn write_u16(&mut self, d: u16) -> Result<(), Error> {
-        self.inner.write_u16::<LittleEndian>(d)
+        self.writer().write_u16::<LittleEndian>(d)
     }
 
     pub fn write_u32(&mut self, d: u32) -> Result<(), Error> {
-        self.inner.write_u32::<LittleEndian>(d)
+        self.writer().write_u32::<LittleEndian>(d)
     }
 
     pub fn write_f64(&mut self, n: f64) -> Result<(), Error> {
-        self.inner.write_f64::<LittleEndian>(n)
+        self.writer().write_f64::<LittleEndian>(n)
     }
 
     pub fn write_u64(&mut self, d: u64) -> Result<(), Error> {
-        self.inner.write_u64::<LittleEndian>(d)
+        self.writer().write_u64::<LittleEndian>(d)
     }
 
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
-        self.inner.write(buf)
+        self.writer().write(buf)
     }
 
     pub fn read_u8(&mut self) -> Result<u8, Error> {
-        self.inner.read_u8()
+        self.reader().read_u8()
     }
 
     pub fn read_u16(&mut self) -> Result<u16, Error> {
-        self.inner.read_u16::<LittleEndian>()
+        self.reader().read_u16::<LittleEndian>()
     }
 
     pub fn read_u32(&mut self) -> Result<u32, Error> {
-        self.inner.read_u32::<LittleEndian>()
+        self.reader().read_u32::<LittleEndian>()
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, Error> {
+        self.reader().read_f32::<LittleEndian>()
     }
 
     pub fn read_f64(&mut self) -> Result<f64, Error> {
-        self.inner.read_f64::<LittleEndian>()
+        self.reader().read_f64::<LittleEndian>()
     }
 
     pub fn read_u64(&mut self) -> Result<u64, Error> {
-        self.inner.read_u64::<LittleEndian>()
+        self.reader().read_u64::<LittleEndian>()
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-        self.inner.read(buf)
+        self.reader().read(buf)
     }
 
     pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        match self.inner.read_exact(buf) {
+        match self.reader().read_exact(buf) {
             Ok(_size) => Ok(()),
             Err(e) => return Err(e),
         }
     }
 
     pub fn as_bytes(&mut self) -> Result<&[u8], Error> {
-        let pkt_len = self.inner.seek(SeekFrom::End(0)).expect("seek failed") as u32;
+        let inner = self.writer();
+        let pkt_len = inner.seek(SeekFrom::End(0)).expect("seek failed") as u32;
 
-        self.inner.seek(SeekFrom::Start(0)).expect("seek failed");
-        self.write_u32(pkt_len).expect("write pkg len");
-        self.inner.seek(SeekFrom::Start(0)).expect("seek failed");
+        inner.seek(SeekFrom::Start(0)).expect("seek failed");
+        inner.write_u32::<LittleEndian>(pkt_len).expect("write pkg len");
+        inner.seek(SeekFrom::Start(0)).expect("seek failed");
 
-        self.inner.fill_buf()
+        inner.fill_buf()
     }
 
     pub fn borrow_mut(&mut self) -> &mut Cursor<Vec<u8>> {
-        self.inner.borrow_mut()
+        self.writer()
     }
 }
 
@@ -228,8 +376,8 @@ impl Debug for QTPacket {
         f.write_str(
             format!(
                 "pkt_len: {}\npkt_buf: {}",
-                self.inner.get_ref().len(),
-                hex::encode_upper(self.inner.get_ref().as_slice())
+                self.bytes().len(),
+                hex::encode_upper(self.bytes())
             )
             .as_str(),
         )
@@ -240,35 +388,7 @@ pub const PACKET_MAGIC_PING: u32 = 0x70696E67;
 pub const PACKET_MAGIC_SYNC: u32 = 0x73796E63;
 pub const PACKET_MAGIC_ASYN: u32 = 0x6173796E;
 
-const PACKET_MAGIC_REPLY: u32 = 0x72706C79;
-
-pub struct QTPacketPing {
-    header: u64,
-}
-
-impl QTPacketPing {
-    pub fn new(header: u64) -> QTPacket {
-        let mut pkt = QTPacket::new();
-        pkt.write_u32(PACKET_MAGIC_PING).unwrap();
-        pkt.write_u64(header).unwrap();
-        pkt
-    }
-
-    pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketPing, Error> {
-        let header = match pkt.read_u64() {
-            Ok(m) => m,
-            Err(e) => return Err(e),
-        };
-
-        Ok(QTPacketPing { header })
-    }
-}
-
-impl Debug for QTPacketPing {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(format!("header: {}", self.header).as_str())
-    }
-}
+pub const PACKET_MAGIC_REPLY: u32 = 0x72706C79;
 
 pub const SYNC_PACKET_MAGIC_OG: u32 = 0x676F2120;
 pub const SYNC_PACKET_MAGIC_STOP: u32 = 0x73746F70;
@@ -288,311 +408,625 @@ pub const ASYN_PACKET_MAGIC_TBAS: u32 = 0x74626173;
 pub const ASYN_PACKET_MAGIC_RELS: u32 = 0x72656C73;
 pub const ASYN_PACKET_MAGIC_HPA0: u32 = 0x68706130;
 pub const ASYN_PACKET_MAGIC_HPD0: u32 = 0x68706430;
-
-pub struct QTPacketCWPA {
-    device_clock_ref: u64,
+// sent by the host
+pub const ASYN_PACKET_MAGIC_HPA1: u32 = 0x68706131;
+pub const ASYN_PACKET_MAGIC_HPD1: u32 = 0x68706431;
+pub const ASYN_PACKET_MAGIC_NEED: u32 = 0x6E656564;
+
+// the magic as its ascii name
+pub fn fourcc(magic: u32) -> String {
+    magic
+        .to_be_bytes()
+        .iter()
+        .map(|b| match b.is_ascii_graphic() || *b == b' ' {
+            true => *b as char,
+            false => '?',
+        })
+        .collect()
 }
 
-fn reply_packet(correlation_id: u64) -> Result<QTPacket, Error> {
-    let mut pkt = QTPacket::new();
+// sync packets, the device asks and waits for a reply with the same correlation id
+#[derive(PartialEq)]
+pub enum SyncMessage {
+    // the device audio clock, the host answers with its own
+    Cwpa { device_clock_ref: u64 },
+    // the device video clock and a dictionary of stream settings
+    Cvrp { device_clock_ref: u64, payload: PacketBytes },
+    // AudioStreamDescription the device proposes
+    Afmt { payload: PacketBytes },
+    Clok,
+    Time,
+    Skew,
+    Og { unknown: u32 },
+    Stop,
+    // magics we don't know, and known ones whose payload is not laid out the way we expect
+    Unknown { magic: u32, payload: PacketBytes },
+}
 
-    match pkt.write_u32(PACKET_MAGIC_REPLY) {
-        Err(e) => return Err(e),
-        _ => {}
-    };
+impl SyncMessage {
+    pub fn magic(&self) -> u32 {
+        match self {
+            SyncMessage::Cwpa { .. } => SYNC_PACKET_MAGIC_CWPA,
+            SyncMessage::Cvrp { .. } => SYNC_PACKET_MAGIC_CVRP,
+            SyncMessage::Afmt { .. } => SYNC_PACKET_MAGIC_AFMT,
+            SyncMessage::Clok => SYNC_PACKET_MAGIC_CLOK,
+            SyncMessage::Time => SYNC_PACKET_MAGIC_TIME,
+            SyncMessage::Skew => SYNC_PACKET_MAGIC_SKEW,
+            SyncMessage::Og { .. } => SYNC_PACKET_MAGIC_OG,
+            SyncMessage::Stop => SYNC_PACKET_MAGIC_STOP,
+            SyncMessage::Unknown { magic, .. } => *magic,
+        }
+    }
 
-    match pkt.write_u64(correlation_id) {
-        Err(e) => return Err(e),
-        _ => {}
-    };
+    fn decode(magic: u32, payload: PacketBytes) -> SyncMessage {
+        let mut pkt = QTPacket::from_payload(payload.clone());
+
+        let msg = match magic {
+            SYNC_PACKET_MAGIC_CWPA => match pkt.read_u64() {
+                Ok(device_clock_ref) => Some(SyncMessage::Cwpa { device_clock_ref }),
+                Err(_) => None,
+            },
+            SYNC_PACKET_MAGIC_CVRP => match pkt.read_u64() {
+                Ok(device_clock_ref) => Some(SyncMessage::Cvrp {
+                    device_clock_ref,
+                    payload: pkt.rest(),
+                }),
+                Err(_) => None,
+            },
+            SYNC_PACKET_MAGIC_AFMT => Some(SyncMessage::Afmt { payload: pkt.rest() }),
+            SYNC_PACKET_MAGIC_CLOK => Some(SyncMessage::Clok),
+            SYNC_PACKET_MAGIC_TIME => Some(SyncMessage::Time),
+            SYNC_PACKET_MAGIC_SKEW => Some(SyncMessage::Skew),
+            SYNC_PACKET_MAGIC_OG => match pkt.read_u32() {
+                Ok(unknown) => Some(SyncMessage::Og { unknown }),
+                Err(_) => None,
+            },
+            SYNC_PACKET_MAGIC_STOP => Some(SyncMessage::Stop),
+            _ => None,
+        };
 
-    match pkt.write_u32(0) {
-        Err(e) => return Err(e),
-        _ => {}
-    };
+        // anything left over would be lost on encode
+        match msg {
+            Some(msg) if pkt.rest().is_empty() => msg,
+            _ => SyncMessage::Unknown { magic, payload },
+        }
+    }
 
-    Ok(pkt)
+    fn encode_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            SyncMessage::Cwpa { device_clock_ref } => {
+                buf.extend_from_slice(&device_clock_ref.to_le_bytes())
+            }
+            SyncMessage::Cvrp {
+                device_clock_ref,
+                payload,
+            } => {
+                buf.extend_from_slice(&device_clock_ref.to_le_bytes());
+                buf.extend_from_slice(payload.as_ref());
+            }
+            SyncMessage::Afmt { payload } => buf.extend_from_slice(payload.as_ref()),
+            SyncMessage::Og { unknown } => buf.extend_from_slice(&unknown.to_le_bytes()),
+            SyncMessage::Unknown { payload, .. } => buf.extend_from_slice(payload.as_ref()),
+            SyncMessage::Clok | SyncMessage::Time | SyncMessage::Skew | SyncMessage::Stop => {}
+        }
+    }
 }
 
-fn reply_packet_with_clock_ref(correlation_id: u64, clock_ref: u64) -> Result<QTPacket, Error> {
-    let mut pkt = match reply_packet(correlation_id) {
-        Ok(e) => e,
-        Err(e) => return Err(e),
-    };
-
-    match pkt.write_u64(clock_ref) {
-        Err(e) => return Err(e),
-        _ => {}
-    };
-
-    Ok(pkt)
+// asyn packets, nobody waits for an answer
+#[derive(PartialEq)]
+pub enum AsynMessage {
+    // a video sample buffer
+    Feed { payload: PacketBytes },
+    // an audio sample buffer
+    Eat { payload: PacketBytes },
+    // set property, one key value pair
+    Sprp { payload: PacketBytes },
+    // set rate and anchor, rate 0 pauses the clock, time is where the rate applies from
+    Srat { rate1: f32, rate2: f32, time: Time },
+    // the clock the packet's clock ref is derived from
+    Tbas { time_base_ref: u64 },
+    // time jump, the layout is not known
+    Tjmp { payload: PacketBytes },
+    // the device released the clock
+    Rels,
+    // host display and audio device info, dictionaries
+    Hpd1 { payload: PacketBytes },
+    Hpa1 { payload: PacketBytes },
+    // host stops display and audio
+    Hpd0,
+    Hpa0,
+    // host wants the next video frame
+    Need,
+    // same as in SyncMessage
+    Unknown { magic: u32, payload: PacketBytes },
 }
 
-impl QTPacketCWPA {
-    pub fn device_clock_ref(&self) -> u64 {
-        self.device_clock_ref
+impl AsynMessage {
+    pub fn magic(&self) -> u32 {
+        match self {
+            AsynMessage::Feed { .. } => ASYN_PACKET_MAGIC_FEED,
+            AsynMessage::Eat { .. } => ASYN_PACKET_MAGIC_EAT,
+            AsynMessage::Sprp { .. } => ASYN_PACKET_MAGIC_SPRP,
+            AsynMessage::Srat { .. } => ASYN_PACKET_MAGIC_SRAT,
+            AsynMessage::Tbas { .. } => ASYN_PACKET_MAGIC_TBAS,
+            AsynMessage::Tjmp { .. } => ASYN_PACKET_MAGIC_TJMP,
+            AsynMessage::Rels => ASYN_PACKET_MAGIC_RELS,
+            AsynMessage::Hpd1 { .. } => ASYN_PACKET_MAGIC_HPD1,
+            AsynMessage::Hpa1 { .. } => ASYN_PACKET_MAGIC_HPA1,
+            AsynMessage::Hpd0 => ASYN_PACKET_MAGIC_HPD0,
+            AsynMessage::Hpa0 => ASYN_PACKET_MAGIC_HPA0,
+            AsynMessage::Need => ASYN_PACKET_MAGIC_NEED,
+            AsynMessage::Unknown { magic, .. } => *magic,
+        }
     }
 
-    pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketCWPA, Error> {
-        // read reversed
-        let device_clock_ref = match pkt.read_u64() {
-            Ok(m) => m,
-            Err(e) => return Err(e),
+    fn decode(magic: u32, payload: PacketBytes) -> AsynMessage {
+        let mut pkt = QTPacket::from_payload(payload.clone());
+
+        let msg = match magic {
+            ASYN_PACKET_MAGIC_FEED => Some(AsynMessage::Feed { payload: pkt.rest() }),
+            ASYN_PACKET_MAGIC_EAT => Some(AsynMessage::Eat { payload: pkt.rest() }),
+            ASYN_PACKET_MAGIC_SPRP => Some(AsynMessage::Sprp { payload: pkt.rest() }),
+            ASYN_PACKET_MAGIC_SRAT => match (pkt.read_f32(), pkt.read_f32(), Time::from_qt_packet(&mut pkt)) {
+                (Ok(rate1), Ok(rate2), Ok(time)) => Some(AsynMessage::Srat { rate1, rate2, time }),
+                _ => None,
+            },
+            ASYN_PACKET_MAGIC_TBAS => match pkt.read_u64() {
+                Ok(time_base_ref) => Some(AsynMessage::Tbas { time_base_ref }),
+                Err(_) => None,
+            },
+            ASYN_PACKET_MAGIC_TJMP => Some(AsynMessage::Tjmp { payload: pkt.rest() }),
+            ASYN_PACKET_MAGIC_RELS => Some(AsynMessage::Rels),
+            ASYN_PACKET_MAGIC_HPD1 => Some(AsynMessage::Hpd1 { payload: pkt.rest() }),
+            ASYN_PACKET_MAGIC_HPA1 => Some(AsynMessage::Hpa1 { payload: pkt.rest() }),
+            ASYN_PACKET_MAGIC_HPD0 => Some(AsynMessage::Hpd0),
+            ASYN_PACKET_MAGIC_HPA0 => Some(AsynMessage::Hpa0),
+            ASYN_PACKET_MAGIC_NEED => Some(AsynMessage::Need),
+            _ => None,
         };
 
-        Ok(QTPacketCWPA { device_clock_ref })
+        match msg {
+            Some(msg) if pkt.rest().is_empty() => msg,
+            _ => AsynMessage::Unknown { magic, payload },
+        }
     }
 
-    pub fn reply_packet(&self, correlation_id: u64, clock_ref: u64) -> Result<QTPacket, Error> {
-        reply_packet_with_clock_ref(correlation_id, clock_ref)
+    fn encode_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            AsynMessage::Feed { payload }
+            | AsynMessage::Eat { payload }
+            | AsynMessage::Sprp { payload }
+            | AsynMessage::Tjmp { payload }
+            | AsynMessage::Hpd1 { payload }
+            | AsynMessage::Hpa1 { payload }
+            | AsynMessage::Unknown { payload, .. } => buf.extend_from_slice(payload.as_ref()),
+            AsynMessage::Srat { rate1, rate2, time } => {
+                buf.extend_from_slice(&rate1.to_le_bytes());
+                buf.extend_from_slice(&rate2.to_le_bytes());
+                buf.extend_from_slice(&time.value().to_le_bytes());
+                buf.extend_from_slice(&time.scale().to_le_bytes());
+                buf.extend_from_slice(&time.flags().to_le_bytes());
+                buf.extend_from_slice(&time.epoch().to_le_bytes());
+            }
+            AsynMessage::Tbas { time_base_ref } => {
+                buf.extend_from_slice(&time_base_ref.to_le_bytes())
+            }
+            AsynMessage::Rels
+            | AsynMessage::Hpd0
+            | AsynMessage::Hpa0
+            | AsynMessage::Need => {}
+        }
     }
 }
 
-pub struct QTPacketASYN {
-    sub_type_mark: u32,
-    type_header: u64,
-    qt_value: Option<QTValue>,
+// one packet on the wire, in either direction. fixed fields are decoded, dictionaries and sample
+// buffers stay the bytes they came in as, QTPacket::from_payload reads them. that way encode
+// gives back exactly the bytes decode was given
+#[derive(PartialEq)]
+pub enum Message {
+    Ping {
+        header: u64,
+    },
+    Sync {
+        clock_ref: u64,
+        correlation_id: u64,
+        message: SyncMessage,
+    },
+    Asyn {
+        clock_ref: u64,
+        message: AsynMessage,
+    },
+    Reply {
+        correlation_id: u64,
+        reserved: u32,
+        payload: PacketBytes,
+    },
 }
 
-impl QTPacketASYN {
-    pub fn new(qt_value: Option<QTValue>, sub_type_mark: u32, type_header: u64) -> QTPacketASYN {
-        QTPacketASYN {
-            sub_type_mark,
-            type_header,
-            qt_value,
-        }
+impl Message {
+    pub fn decode(data: &[u8]) -> Result<Message, QTError> {
+        Message::decode_shared(PacketBytes::from_vec(Vec::from(data)))
     }
 
-    pub fn as_qt_packet(&mut self) -> Result<QTPacket, Error> {
-        let mut pkt = QTPacket::new();
-        match pkt.write_u32(PACKET_MAGIC_ASYN) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
-        match pkt.write_u64(self.type_header) {
+    // same as decode, the payloads are slices of data instead of copies
+    pub fn decode_shared(data: PacketBytes) -> Result<Message, QTError> {
+        let mut pkt = match QTPacket::from_shared(data) {
+            Ok(e) => e,
             Err(e) => return Err(e),
-            _ => {}
         };
-        match pkt.write_u32(self.sub_type_mark) {
-            Err(e) => return Err(e),
-            _ => {}
+
+        let magic = match pkt.read_u32() {
+            Ok(e) => e,
+            Err(e) => return Err(e.into()),
         };
 
-        match &mut self.qt_value {
-            Some(qt_pkt) => {
-                let mut val_pkt = match qt_pkt.as_qt_packet() {
+        let msg = match magic {
+            PACKET_MAGIC_PING => {
+                let header = match pkt.read_u64() {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
-                let val_pkt_val = match val_pkt.as_bytes() {
+                Message::Ping { header }
+            }
+            PACKET_MAGIC_SYNC => {
+                let clock_ref = match pkt.read_u64() {
                     Ok(e) => e,
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.into()),
                 };
 
-                match pkt.write(val_pkt_val) {
-                    Err(e) => return Err(e),
-                    _ => {}
+                let sync_magic = match pkt.read_u32() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
                 };
-            }
-            _ => {}
-        };
 
-        Ok(pkt)
-    }
-}
+                let correlation_id = match pkt.read_u64() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
 
-pub struct QTPacketOG {
-    unknown: u32,
-}
+                Message::Sync {
+                    clock_ref,
+                    correlation_id,
+                    message: SyncMessage::decode(sync_magic, pkt.rest()),
+                }
+            }
+            PACKET_MAGIC_ASYN => {
+                let clock_ref = match pkt.read_u64() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
 
-impl QTPacketOG {
-    pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketOG, Error> {
-        // read reversed
-        let unknown = match pkt.read_u32() {
-            Ok(m) => m,
-            Err(e) => return Err(e),
-        };
+                let asyn_magic = match pkt.read_u32() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
 
-        Ok(QTPacketOG { unknown })
-    }
+                Message::Asyn {
+                    clock_ref,
+                    message: AsynMessage::decode(asyn_magic, pkt.rest()),
+                }
+            }
+            PACKET_MAGIC_REPLY => {
+                let correlation_id = match pkt.read_u64() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
 
-    pub fn reply_packet(&self, correlation_id: u64) -> Result<QTPacket, Error> {
-        let mut pkt = match reply_packet(correlation_id) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+                let reserved = match pkt.read_u32() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
 
-        match pkt.write_u32(0) {
-            Err(e) => return Err(e),
-            _ => {}
+                Message::Reply {
+                    correlation_id,
+                    reserved,
+                    payload: pkt.rest(),
+                }
+            }
+            _ => return Err(QTError::UnknownMagic(magic)),
         };
 
-        Ok(pkt)
-    }
-}
-
-pub struct QTPacketCVRP {
-    device_clock_ref: u64,
-    payload: QTValue,
-}
+        // only a ping can have bytes left, they would be lost on encode
+        let rest = pkt.rest();
+        if !rest.is_empty() {
+            return Err(QTError::Framing(format!("{} bytes after ping header", rest.len())));
+        }
 
-impl QTPacketCVRP {
-    pub fn device_clock_ref(&self) -> u64 {
-        self.device_clock_ref
+        Ok(msg)
     }
 
-    pub fn payload(&self) -> &QTValue {
-        &self.payload
-    }
+    pub fn encode(&self) -> Vec<u8> {
+        // length goes in front once the rest is written
+        let mut buf: Vec<u8> = Vec::from([0, 0, 0, 0]);
 
-    pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketCVRP, Error> {
-        // read reversed
-        let device_clock_ref = match pkt.read_u64() {
-            Ok(m) => m,
-            Err(e) => return Err(e),
+        match self {
+            Message::Ping { header } => {
+                buf.extend_from_slice(&PACKET_MAGIC_PING.to_le_bytes());
+                buf.extend_from_slice(&header.to_le_bytes());
+            }
+            Message::Sync {
+                clock_ref,
+                correlation_id,
+                message,
+            } => {
+                buf.extend_from_slice(&PACKET_MAGIC_SYNC.to_le_bytes());
+                buf.extend_from_slice(&clock_ref.to_le_bytes());
+                buf.extend_from_slice(&message.magic().to_le_bytes());
+                buf.extend_from_slice(&correlation_id.to_le_bytes());
+                message.encode_payload(&mut buf);
+            }
+            Message::Asyn { clock_ref, message } => {
+                buf.extend_from_slice(&PACKET_MAGIC_ASYN.to_le_bytes());
+                buf.extend_from_slice(&clock_ref.to_le_bytes());
+                buf.extend_from_slice(&message.magic().to_le_bytes());
+                message.encode_payload(&mut buf);
+            }
+            Message::Reply {
+                correlation_id,
+                reserved,
+                payload,
+            } => {
+                buf.extend_from_slice(&PACKET_MAGIC_REPLY.to_le_bytes());
+                buf.extend_from_slice(&correlation_id.to_le_bytes());
+                buf.extend_from_slice(&reserved.to_le_bytes());
+                buf.extend_from_slice(payload.as_ref());
+            }
         };
 
-        let qt_value = match QTValue::from_qt_packet(pkt) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+        let len = (buf.len() as u32).to_le_bytes();
+        buf[..4].copy_from_slice(&len);
 
-        Ok(QTPacketCVRP {
-            device_clock_ref,
-            payload: qt_value,
-        })
+        buf
     }
 
-    pub fn reply_packet(&self, correlation_id: u64, clock_ref: u64) -> Result<QTPacket, Error> {
-        reply_packet_with_clock_ref(correlation_id, clock_ref)
+    // the sync or asyn magic, the packet magic for pings and replies
+    pub fn magic(&self) -> u32 {
+        match self {
+            Message::Ping { .. } => PACKET_MAGIC_PING,
+            Message::Sync { message, .. } => message.magic(),
+            Message::Asyn { message, .. } => message.magic(),
+            Message::Reply { .. } => PACKET_MAGIC_REPLY,
+        }
     }
-}
 
-pub struct QTPacketCLOCK {}
-
-impl QTPacketCLOCK {
-    pub fn new() -> QTPacketCLOCK {
-        return QTPacketCLOCK {};
+    pub fn asyn(clock_ref: u64, message: AsynMessage) -> Message {
+        Message::Asyn { clock_ref, message }
     }
 
-    pub fn reply_packet(&self, correlation_id: u64, clock_ref: u64) -> Result<QTPacket, Error> {
-        reply_packet_with_clock_ref(correlation_id, clock_ref)
+    pub fn reply(correlation_id: u64, payload: Vec<u8>) -> Message {
+        Message::Reply {
+            correlation_id,
+            reserved: 0,
+            payload: PacketBytes::from_vec(payload),
+        }
     }
-}
-
-pub struct QTPacketTIME {}
 
-impl QTPacketTIME {
-    pub fn new() -> QTPacketTIME {
-        return QTPacketTIME {};
+    // CWPA, CVRP and CLOK are answered with the host clock ref
+    pub fn reply_clock_ref(correlation_id: u64, clock_ref: u64) -> Message {
+        Message::reply(correlation_id, Vec::from(clock_ref.to_le_bytes()))
     }
 
-    pub fn reply_packet(&self, correlation_id: u64, t: Time) -> Result<QTPacket, Error> {
-        let mut pkt = match reply_packet(correlation_id) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    // OG and STOP
+    pub fn reply_u32(correlation_id: u64, value: u32) -> Message {
+        Message::reply(correlation_id, Vec::from(value.to_le_bytes()))
+    }
 
-        let t_buffer = match t.as_bytes() {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    pub fn reply_skew(correlation_id: u64, skew: f64) -> Message {
+        Message::reply(correlation_id, Vec::from(skew.to_le_bytes()))
+    }
 
-        match pkt.write(t_buffer.as_slice()) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
+    pub fn reply_time(correlation_id: u64, t: &Time) -> Result<Message, Error> {
+        match t.as_bytes() {
+            Ok(e) => Ok(Message::reply(correlation_id, e)),
+            Err(e) => Err(e),
+        }
+    }
 
-        Ok(pkt)
+    pub fn reply_value(correlation_id: u64, value: &QTValue) -> Result<Message, Error> {
+        match value_payload(value) {
+            Ok(e) => Ok(Message::Reply {
+                correlation_id,
+                reserved: 0,
+                payload: e,
+            }),
+            Err(e) => Err(e),
+        }
     }
 }
 
-pub struct QTPacketAFMT {
-    audio_desc: AudioStreamDescription,
-}
+// a QTValue as the payload of a message
+pub fn value_payload(value: &QTValue) -> Result<PacketBytes, Error> {
+    let mut pkt = match value.as_qt_packet() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
 
-impl QTPacketAFMT {
-    pub fn from_packet(pkt: &mut QTPacket) -> Result<QTPacketAFMT, Error> {
-        let audio_desc = match AudioStreamDescription::from_qt_packet(pkt) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
-        Ok(QTPacketAFMT { audio_desc })
+    match pkt.as_bytes() {
+        Ok(e) => Ok(PacketBytes::from_vec(Vec::from(e))),
+        Err(e) => Err(e),
     }
+}
 
-    pub fn reply_packet(&self, correlation_id: u64) -> Result<QTPacket, Error> {
-        let mut pkt = match reply_packet(correlation_id) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
-
-        let mut arr: Vec<QTValue> = Vec::new();
+impl Debug for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(hex::encode_upper(self.encode()).as_str())
+    }
+}
 
-        arr.push(QTValue::KeyValuePair(QTKeyValuePair::new(
-            QTValue::StringKey(String::from("Error")),
-            QTValue::UInt32(0),
-        )));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qt_value::QTKeyValuePair;
 
-        let mut val_pkt = match QTValue::Object(arr).as_qt_packet() {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    fn bytes(data: &[u8]) -> PacketBytes {
+        PacketBytes::from_vec(Vec::from(data))
+    }
 
-        let val_pkt_buffer = match val_pkt.as_bytes() {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    // fixtures are written like the tables in technical_documentation.md
+    fn fixture(s: &str) -> Vec<u8> {
+        hex::decode(s.replace(' ', "")).unwrap()
+    }
 
-        match pkt.write(val_pkt_buffer) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
+    fn round_trip(msg: Message) {
+        let decoded = Message::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded, msg);
+    }
 
-        Ok(pkt)
+    fn sync(message: SyncMessage) -> Message {
+        Message::Sync {
+            clock_ref: 0x7FA66CE20CB0,
+            correlation_id: 0x113573DE0,
+            message,
+        }
     }
-}
 
-pub struct QTPacketSKEW {}
+    #[test]
+    fn sync_messages_round_trip() {
+        let messages = [
+            SyncMessage::Cwpa { device_clock_ref: 0x4000135A000074E0 },
+            SyncMessage::Cvrp {
+                device_clock_ref: 0x113538DA0,
+                payload: bytes(&[0x08, 0x00, 0x00, 0x00, 0x74, 0x63, 0x69, 0x64]),
+            },
+            SyncMessage::Afmt { payload: bytes(&[1, 2, 3, 4, 5, 6, 7, 8]) },
+            SyncMessage::Clok,
+            SyncMessage::Time,
+            SyncMessage::Skew,
+            SyncMessage::Og { unknown: 0 },
+            SyncMessage::Stop,
+            SyncMessage::Unknown {
+                magic: 0x61626364,
+                payload: bytes(&[0xFF, 0x00, 0xFF]),
+            },
+            // a known magic with a payload it doesn't expect stays unknown
+            SyncMessage::Unknown {
+                magic: SYNC_PACKET_MAGIC_CLOK,
+                payload: bytes(&[0x01]),
+            },
+        ];
+
+        for message in messages {
+            round_trip(sync(message));
+        }
+    }
 
-impl QTPacketSKEW {
-    pub fn new() -> QTPacketSKEW {
-        QTPacketSKEW {}
+    #[test]
+    fn asyn_messages_round_trip() {
+        let messages = [
+            AsynMessage::Feed { payload: bytes(&[0x08, 0x00, 0x00, 0x00, 0x66, 0x75, 0x62, 0x73]) },
+            AsynMessage::Eat { payload: bytes(&[0x08, 0x00, 0x00, 0x00, 0x66, 0x75, 0x62, 0x73]) },
+            AsynMessage::Sprp { payload: bytes(&[1, 2, 3]) },
+            AsynMessage::Srat {
+                rate1: 1.0,
+                rate2: 0.0,
+                time: Time::new(0x2B8D2E5D3, 1_000_000_000, 1, 0),
+            },
+            AsynMessage::Tbas { time_base_ref: 0x7FA66CE20CB0 },
+            AsynMessage::Tjmp { payload: bytes(&[0; 12]) },
+            AsynMessage::Rels,
+            AsynMessage::Hpd1 { payload: bytes(&[4, 5]) },
+            AsynMessage::Hpa1 { payload: bytes(&[6, 7]) },
+            AsynMessage::Hpd0,
+            AsynMessage::Hpa0,
+            AsynMessage::Need,
+            AsynMessage::Unknown {
+                magic: 0x61626364,
+                payload: bytes(&[0xFF]),
+            },
+            AsynMessage::Unknown {
+                magic: ASYN_PACKET_MAGIC_TBAS,
+                payload: bytes(&[0x01, 0x02]),
+            },
+        ];
+
+        for message in messages {
+            round_trip(Message::asyn(0x7FA66CD10250, message));
+        }
     }
 
-    pub fn reply_packet(&self, correlation_id: u64, skew: f64) -> Result<QTPacket, Error> {
-        let mut pkt = match reply_packet(correlation_id) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    #[test]
+    fn ping_and_reply_round_trip() {
+        round_trip(Message::Ping { header: 0x100000000 });
+        round_trip(Message::reply_clock_ref(0x113573DE0, 0x7FA66CE20CB0));
+        round_trip(Message::reply(0x113573DE0, Vec::new()));
+    }
 
-        match pkt.write_f64(skew) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
+    // decodes to msg and encodes back to the same bytes
+    fn golden(data: &[u8], msg: Message) {
+        assert_eq!(Message::decode(data).unwrap(), msg);
+        assert_eq!(msg.encode(), data);
+    }
 
-        Ok(pkt)
+    #[test]
+    fn golden_ping() {
+        golden(
+            &fixture("10000000 676E6970 00000000 01000000"),
+            Message::Ping { header: 0x100000000 },
+        );
     }
-}
 
-pub struct QTPacketSTOP {}
+    #[test]
+    fn golden_cwpa() {
+        golden(
+            &fixture("24000000 636E7973 01000000 00000000 61707763 E03D5713 01000000 E0740000 5A130040"),
+            Message::Sync {
+                clock_ref: 1,
+                correlation_id: 0x113573DE0,
+                message: SyncMessage::Cwpa { device_clock_ref: 0x4000135A000074E0 },
+            },
+        );
+    }
 
-impl QTPacketSTOP {
-    pub fn new() -> QTPacketSTOP {
-        QTPacketSTOP {}
+    #[test]
+    fn golden_afmt() {
+        let asbd = fixture(
+            "00000000 0070E740 6D63706C 4C000000 04000000 01000000 04000000 02000000 10000000 00000000",
+        );
+
+        golden(
+            &fixture(
+                "44000000 636E7973 B00CE26C A67F0000 746D6661 809D2213 01000000 \
+                 00000000 0070E740 6D63706C 4C000000 04000000 01000000 04000000 02000000 10000000 00000000",
+            ),
+            Message::Sync {
+                clock_ref: 0x7FA66CE20CB0,
+                correlation_id: 0x113229D80,
+                message: SyncMessage::Afmt { payload: PacketBytes::from_vec(asbd) },
+            },
+        );
     }
 
-    pub fn reply_packet(&self, correlation_id: u64) -> Result<QTPacket, Error> {
-        let mut pkt = match reply_packet(correlation_id) {
-            Ok(e) => e,
-            Err(e) => return Err(e),
-        };
+    #[test]
+    fn golden_feed() {
+        golden(
+            &fixture("1C000000 6E797361 5002D16C A67F0000 64656566 08000000 66756273"),
+            Message::asyn(
+                0x7FA66CD10250,
+                AsynMessage::Feed { payload: bytes(&[0x08, 0x00, 0x00, 0x00, 0x66, 0x75, 0x62, 0x73]) },
+            ),
+        );
+    }
 
-        match pkt.write_u32(0) {
-            Err(e) => return Err(e),
-            _ => {}
-        };
+    #[test]
+    fn golden_rply() {
+        // the CWPA reply with our clock ref
+        golden(
+            &fixture("1C000000 796C7072 E03D5713 01000000 00000000 B00CE26C A67F0000"),
+            Message::reply_clock_ref(0x113573DE0, 0x7FA66CE20CB0),
+        );
 
-        Ok(pkt)
+        // the AFMT reply, {"Error": 0}
+        let error = QTValue::Object(Vec::from([QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::StringKey(String::from("Error")),
+            QTValue::UInt32(0),
+        ))]));
+        golden(
+            &fixture(
+                "3E000000 796C7072 809D2213 01000000 00000000 2A000000 74636964 22000000 7679656B \
+                 0D000000 6B727473 4572726F 720D0000 0076626D 6E030000 0000",
+            ),
+            Message::reply_value(0x113229D80, &error).unwrap(),
+        );
     }
 }
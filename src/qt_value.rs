@@ -1,8 +1,11 @@
+#![allow(clippy::needless_return, clippy::question_mark, clippy::single_match)]
+
 use crate::coremedia::format_desc::FormatDescriptor;
 use crate::coremedia::sample::MAGIC_FORMAT_DESCRIPTOR;
+use crate::error::QTError;
 use crate::qt_pkt::QTPacket;
 use std::fmt::{Debug, Formatter};
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 const MAGIC_KEY_VALUE_PAIR: u32 = 0x6B657976; // keyv - vyek
 const MAGIC_KEY_STRING: u32 = 0x7374726B; // strk - krts
@@ -192,7 +195,8 @@ impl QTValue {
                     Err(e) => return Err(e),
                 };
 
-                match pkt.write(fd_buffer) {
+                // skip length and magic, already written above
+                match pkt.write(&fd_buffer[8..]) {
                     Err(e) => return Err(e),
                     _ => {}
                 };
@@ -206,28 +210,34 @@ impl QTValue {
         Ok(pkt)
     }
 
-    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<QTValue, Error> {
+    pub fn from_qt_packet(pkt: &mut QTPacket) -> Result<QTValue, QTError> {
         let pkt_len = match pkt.read_u32() {
             Ok(m) => m,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
+        if pkt_len < 8 {
+            return Err(QTError::Framing(format!("qt value length {}", pkt_len)));
+        }
+
         let magic = match pkt.read_u32() {
             Ok(m) => m,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
-        let obj_val = match magic {
-            MAGIC_KEY_VALUE_PAIR => Some(QTValue::KeyValuePair(Box::new(QTKeyValuePair {
-                key: match QTValue::from_qt_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                },
-                value: match QTValue::from_qt_packet(pkt) {
-                    Ok(e) => e,
-                    Err(e) => return Err(e),
-                },
-            }))),
+        match magic {
+            MAGIC_KEY_VALUE_PAIR => {
+                return Ok(QTValue::KeyValuePair(Box::new(QTKeyValuePair {
+                    key: match QTValue::from_qt_packet(pkt) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    },
+                    value: match QTValue::from_qt_packet(pkt) {
+                        Ok(e) => e,
+                        Err(e) => return Err(e),
+                    },
+                })))
+            }
             MAGIC_KEY_DICTIONARY => {
                 // create new qt packet
                 let mut obj_pkt = match QTPacket::read_qt_packet(pkt, pkt_len as usize - 8) {
@@ -235,68 +245,71 @@ impl QTValue {
                     Err(e) => return Err(e),
                 };
 
+                let obj_len = match obj_pkt.len() {
+                    Ok(e) => e,
+                    Err(e) => return Err(e.into()),
+                };
+
                 let mut arr: Vec<QTValue> = Vec::new();
-                loop {
+                while obj_pkt.pos() < obj_len {
                     match QTValue::from_qt_packet(&mut obj_pkt) {
                         Ok(e) => arr.push(e),
-                        Err(e) => match e.kind() {
-                            ErrorKind::UnexpectedEof => break,
-                            _ => return Err(e),
-                        },
+                        Err(e) => return Err(e),
                     }
                 }
 
-                Some(QTValue::Object(arr))
+                return Ok(QTValue::Object(arr));
             }
-            MAGIC_FORMAT_DESCRIPTOR => match FormatDescriptor::from_qt_packet(pkt) {
-                Ok(e) => Some(QTValue::FormatDescriptor(Box::new(e))),
-                Err(e) => return Err(e),
-            },
-            _ => None,
+            MAGIC_FORMAT_DESCRIPTOR => {
+                return match FormatDescriptor::from_qt_packet(pkt) {
+                    Ok(e) => Ok(QTValue::FormatDescriptor(Box::new(e))),
+                    Err(e) => Err(e),
+                }
+            }
+            _ => {}
         };
 
-        if obj_val.is_some() {
-            return Ok(obj_val.unwrap());
-        }
-
         let mut data: Vec<u8> = vec![0; pkt_len as usize - 8];
         match pkt.read_exact(&mut data) {
             Ok(e) => e,
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.into()),
         };
 
         match magic {
             MAGIC_KEY_STRING => Ok(QTValue::StringKey(match String::from_utf8(data) {
                 Ok(e) => e,
-                Err(_err) => return Err(Error::new(ErrorKind::InvalidData, "string utf8")),
+                Err(_err) => return Err(QTError::MalformedValue(String::from("string utf8"))),
             })),
             MAGIC_KEY_STRING_VALUE => Ok(QTValue::StringKey(match String::from_utf8(data) {
                 Ok(e) => e,
-                Err(_err) => return Err(Error::new(ErrorKind::InvalidData, "string utf8")),
+                Err(_err) => return Err(QTError::MalformedValue(String::from("string utf8"))),
             })),
-            MAGIC_KEY_BOOLEAN => match data[0] {
-                0 => Ok(QTValue::Boolean(false)),
-                1 => Ok(QTValue::Boolean(true)),
-                _ => return Err(Error::new(ErrorKind::InvalidData, "boolean overflow")),
+            MAGIC_KEY_BOOLEAN => match data.first() {
+                Some(0) => Ok(QTValue::Boolean(false)),
+                Some(1) => Ok(QTValue::Boolean(true)),
+                _ => Err(QTError::MalformedValue(String::from("boolean overflow"))),
             },
             MAGIC_KEY_DATA_VALUE => Ok(QTValue::Data(data)),
-            MAGIC_KEY_NUMBER_VALUE => match data[0] {
-                6 => Ok(QTValue::Float(f64::from_le_bytes([
+            MAGIC_KEY_NUMBER_VALUE => match (data.first(), data.len()) {
+                (Some(6), 9..) => Ok(QTValue::Float(f64::from_le_bytes([
                     data[1], data[2], data[3], data[4], data[5], data[6], data[7], data[8],
                 ]))),
-                5 => Ok(QTValue::UInt32(u32::from_le_bytes([
+                (Some(5), 5..) => Ok(QTValue::UInt32(u32::from_le_bytes([
                     data[1], data[2], data[3], data[4],
                 ]))),
-                4 => Ok(QTValue::UInt64(u64::from_le_bytes([
+                (Some(4), 9..) => Ok(QTValue::UInt64(u64::from_le_bytes([
                     data[1], data[2], data[3], data[4], data[5], data[6], data[7], data[8],
                 ]))),
-                3 => Ok(QTValue::UInt32(u32::from_le_bytes([
+                (Some(3), 5..) => Ok(QTValue::UInt32(u32::from_le_bytes([
                     data[1], data[2], data[3], data[4],
                 ]))),
-                _ => return Err(Error::new(ErrorKind::InvalidData, "unknown number spec")),
+                _ => Err(QTError::MalformedValue(String::from("unknown number spec"))),
+            },
+            MAGIC_KEY_IDX => match data.len() {
+                2.. => Ok(QTValue::IdxKey(u16::from_le_bytes([data[0], data[1]]))),
+                _ => Err(QTError::MalformedValue(String::from("idx key too short"))),
             },
-            MAGIC_KEY_IDX => Ok(QTValue::IdxKey(u16::from_le_bytes([data[0], data[1]]))),
-            _ => return Err(Error::new(ErrorKind::InvalidData, "unknown magic")),
+            _ => Err(QTError::UnknownMagic(magic)),
         }
     }
 
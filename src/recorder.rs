@@ -0,0 +1,37 @@
+#![allow(clippy::single_match)]
+
+use crate::coremedia::sample::SampleBuffer;
+use log::{info, warn};
+
+use std::io::Error;
+
+pub trait SampleWriter {
+    fn write_sample(&mut self, sample: &SampleBuffer) -> Result<(), Error>;
+    // writes whatever is buffered and the trailing boxes, safe to call twice
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+pub struct Recorder {
+    writer: Box<dyn SampleWriter + Send>,
+}
+
+impl Recorder {
+    pub fn new(writer: Box<dyn SampleWriter + Send>) -> Recorder {
+        Recorder { writer }
+    }
+
+    // blocks on the samples until the session ends, so nothing queued is lost on shutdown
+    pub fn run<I: Iterator<Item = SampleBuffer>>(&mut self, samples: I) {
+        for sample in samples {
+            match self.writer.write_sample(&sample) {
+                Err(e) => warn!("record sample: {}", e),
+                _ => {}
+            };
+        }
+
+        match self.writer.finish() {
+            Err(e) => warn!("finish recording: {}", e),
+            _ => info!("recording finished"),
+        };
+    }
+}
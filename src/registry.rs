@@ -0,0 +1,160 @@
+#![allow(clippy::question_mark, clippy::single_match)]
+
+use log::{info, warn};
+use std::io;
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub struct Endpoint {
+    udid: String,
+    name: Option<String>,
+    video_port: u16,
+    audio_port: u16,
+    state: String,
+}
+
+impl Endpoint {
+    pub fn udid(&self) -> &str {
+        self.udid.as_str()
+    }
+
+    pub fn video_port(&self) -> u16 {
+        self.video_port
+    }
+
+    pub fn audio_port(&self) -> u16 {
+        self.audio_port
+    }
+
+    pub fn state(&self) -> &str {
+        self.state.as_str()
+    }
+}
+
+// which device streams on which ports, shared between the session threads
+pub struct Registry {
+    endpoints: Mutex<Vec<Endpoint>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            endpoints: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&self, udid: &str, name: Option<&str>, video_port: u16, audio_port: u16) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        endpoints.retain(|e| e.udid != udid);
+        endpoints.push(Endpoint {
+            udid: String::from(udid),
+            name: name.map(String::from),
+            video_port,
+            audio_port,
+            state: String::from("starting"),
+        });
+    }
+
+    pub fn set_state(&self, udid: &str, state: &str) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if let Some(e) = endpoints.iter_mut().find(|e| e.udid == udid) {
+            e.state = String::from(state);
+        }
+    }
+
+    // one line per endpoint, for the terminal
+    pub fn to_text(&self) -> String {
+        let lines: Vec<String> = self
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                format!(
+                    "{}  {}  video {}  audio {}  {}",
+                    e.udid,
+                    e.name.as_deref().unwrap_or("-"),
+                    e.video_port,
+                    e.audio_port,
+                    e.state
+                )
+            })
+            .collect();
+
+        lines.join("\n")
+    }
+
+    pub fn to_json(&self) -> String {
+        let items: Vec<String> = self
+            .endpoints
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"udid\":\"{}\",\"name\":{},\"video_port\":{},\"audio_port\":{},\"state\":\"{}\"}}",
+                    e.udid,
+                    match &e.name {
+                        Some(n) => format!("\"{}\"", n.replace('\\', "\\\\").replace('"', "\\\"")),
+                        None => String::from("null"),
+                    },
+                    e.video_port,
+                    e.audio_port,
+                    e.state
+                )
+            })
+            .collect();
+
+        format!("[{}]", items.join(","))
+    }
+
+    // every connection gets the current registry as one JSON line, then it is closed
+    pub fn serve(registry: Arc<Registry>, address: String, term: Arc<AtomicBool>) -> io::Result<thread::JoinHandle<()>> {
+        let listener = match TcpListener::bind(address.as_str()) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        match listener.set_nonblocking(true) {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        info!("registry on {}", address);
+
+        Ok(thread::spawn(move || {
+            while !term.load(Ordering::SeqCst) {
+                let mut stream = match listener.accept() {
+                    Ok((s, _)) => s,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(100));
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("registry accept: {}", e);
+                        continue;
+                    }
+                };
+
+                let body = format!("{}\n", registry.to_json());
+                let res = stream
+                    .set_nonblocking(false)
+                    .and_then(|_| stream.write_all(body.as_bytes()));
+                match res {
+                    Err(e) => warn!("registry write: {}", e),
+                    _ => {}
+                };
+            }
+        }))
+    }
+}
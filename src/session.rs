@@ -0,0 +1,243 @@
+use crate::error::QTError;
+use crate::qt_pkt;
+use crate::qt_value::QTValue;
+use log::info;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+// handshake phases in the order the device walks through them
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+pub enum Phase {
+    // nothing received yet
+    Idle = 0,
+    // PING answered
+    Connected = 1,
+    // CWPA answered, HPD1/HPA1 sent
+    AudioClock = 2,
+    // AFMT answered, skipped without audio
+    AudioFormat = 3,
+    // CVRP answered, NEED sent
+    VideoClock = 4,
+    // CLOK answered, TIME can be answered from here on
+    Clocked = 5,
+    // first FEED or EAT! received once clocked
+    Streaming = 6,
+    // HPA0/HPD0 sent, the device is flushing
+    Closing = 7,
+    // STOP received
+    Stopped = 8,
+}
+
+impl Phase {
+    fn from_u8(v: u8) -> Phase {
+        match v {
+            1 => Phase::Connected,
+            2 => Phase::AudioClock,
+            3 => Phase::AudioFormat,
+            4 => Phase::VideoClock,
+            5 => Phase::Clocked,
+            6 => Phase::Streaming,
+            7 => Phase::Closing,
+            8 => Phase::Stopped,
+            _ => Phase::Idle,
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Phase::Idle => "idle",
+            Phase::Connected => "connected",
+            Phase::AudioClock => "audio clock",
+            Phase::AudioFormat => "audio format",
+            Phase::VideoClock => "video clock",
+            Phase::Clocked => "clocked",
+            Phase::Streaming => "streaming",
+            Phase::Closing => "closing",
+            Phase::Stopped => "stopped",
+        };
+        f.write_str(s)
+    }
+}
+
+// the phase a packet needs before it can be handled, and the one it moves the session to
+fn transition(magic: u32) -> Option<(Phase, Option<Phase>)> {
+    match magic {
+        qt_pkt::PACKET_MAGIC_PING => Some((Phase::Idle, Some(Phase::Connected))),
+        qt_pkt::SYNC_PACKET_MAGIC_CWPA => Some((Phase::Connected, Some(Phase::AudioClock))),
+        qt_pkt::SYNC_PACKET_MAGIC_AFMT => Some((Phase::AudioClock, Some(Phase::AudioFormat))),
+        qt_pkt::SYNC_PACKET_MAGIC_CVRP => Some((Phase::AudioClock, Some(Phase::VideoClock))),
+        qt_pkt::SYNC_PACKET_MAGIC_CLOK => Some((Phase::VideoClock, Some(Phase::Clocked))),
+        qt_pkt::SYNC_PACKET_MAGIC_TIME => Some((Phase::Clocked, None)),
+        qt_pkt::SYNC_PACKET_MAGIC_SKEW => Some((Phase::AudioClock, None)),
+        qt_pkt::SYNC_PACKET_MAGIC_OG => Some((Phase::Connected, None)),
+        qt_pkt::SYNC_PACKET_MAGIC_STOP => Some((Phase::Connected, Some(Phase::Stopped))),
+        qt_pkt::ASYN_PACKET_MAGIC_EAT => Some((Phase::AudioClock, Some(Phase::Streaming))),
+        qt_pkt::ASYN_PACKET_MAGIC_FEED => Some((Phase::VideoClock, Some(Phase::Streaming))),
+        qt_pkt::ASYN_PACKET_MAGIC_SPRP
+        | qt_pkt::ASYN_PACKET_MAGIC_SRAT
+        | qt_pkt::ASYN_PACKET_MAGIC_TBAS
+        | qt_pkt::ASYN_PACKET_MAGIC_TJMP
+        | qt_pkt::ASYN_PACKET_MAGIC_RELS
+        | qt_pkt::ASYN_PACKET_MAGIC_HPA0
+        | qt_pkt::ASYN_PACKET_MAGIC_HPD0 => Some((Phase::Idle, None)),
+        _ => None,
+    }
+}
+
+// where the protocol session is. packets that arrive before the phase they depend on are
+// rejected with a protocol error and dropped, repeats of earlier phases are tolerated
+pub struct Session {
+    phase: Arc<AtomicU8>,
+    // SPRP properties, until the device says otherwise empty samples are passed on like any other
+    obey_empty_media_markers: bool,
+    render_empty_media: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            phase: Arc::new(AtomicU8::new(Phase::Idle as u8)),
+            obey_empty_media_markers: false,
+            render_empty_media: true,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        Phase::from_u8(self.phase.load(Ordering::SeqCst))
+    }
+
+    // follows the phase from another thread
+    pub fn watch(&self) -> SessionWatch {
+        SessionWatch {
+            phase: self.phase.clone(),
+        }
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.phase() >= Phase::Closing
+    }
+
+    pub fn check(&self, magic: u32) -> Result<(), QTError> {
+        // unknown packets are up to the handlers
+        let (requires, _) = match transition(magic) {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        let phase = self.phase();
+        if phase < requires {
+            return Err(QTError::Protocol(format!(
+                "{} in phase {}, needs {}",
+                qt_pkt::fourcc(magic),
+                phase,
+                requires
+            )));
+        }
+
+        Ok(())
+    }
+
+    // after the packet was handled, phases never go back
+    pub fn advance(&self, magic: u32) {
+        let next = match transition(magic) {
+            Some((_, Some(e))) => e,
+            _ => return,
+        };
+
+        // EAT! can come as soon as the audio clock is up, samples don't carry the session past
+        // the video clock handshake it has not been through yet
+        if next == Phase::Streaming && self.phase() < Phase::Clocked {
+            return;
+        }
+
+        self.enter(next);
+    }
+
+    pub fn enter(&self, next: Phase) {
+        let phase = self.phase();
+        if next > phase {
+            self.phase.store(next as u8, Ordering::SeqCst);
+            info!("session {} -> {}", phase, next);
+        }
+    }
+
+    // false for properties we don't know
+    pub fn set_property(&mut self, key: &str, value: &QTValue) -> bool {
+        let flag = match value.as_bool() {
+            Some(e) => e,
+            None => return false,
+        };
+
+        match key {
+            "ObeyEmptyMediaMarkers" => self.obey_empty_media_markers = flag,
+            "RenderEmptyMedia" => self.render_empty_media = flag,
+            _ => return false,
+        };
+
+        true
+    }
+
+    // samples without data mark a stretch of nothing, they are dropped when the device asks us
+    // to obey the markers but not to render them
+    pub fn skip_empty_media(&self) -> bool {
+        self.obey_empty_media_markers && !self.render_empty_media
+    }
+
+    // a new session after a reconnect
+    pub fn reset(&mut self) {
+        self.phase.store(Phase::Idle as u8, Ordering::SeqCst);
+        self.obey_empty_media_markers = false;
+        self.render_empty_media = true;
+    }
+}
+
+#[derive(Clone)]
+pub struct SessionWatch {
+    phase: Arc<AtomicU8>,
+}
+
+impl SessionWatch {
+    pub fn phase(&self) -> Phase {
+        Phase::from_u8(self.phase.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn early_audio_does_not_skip_the_video_handshake() {
+        let session = Session::new();
+        for magic in [qt_pkt::PACKET_MAGIC_PING, qt_pkt::SYNC_PACKET_MAGIC_CWPA] {
+            session.check(magic).unwrap();
+            session.advance(magic);
+        }
+
+        session.check(qt_pkt::ASYN_PACKET_MAGIC_EAT).unwrap();
+        session.advance(qt_pkt::ASYN_PACKET_MAGIC_EAT);
+        assert_eq!(session.phase(), Phase::AudioClock);
+
+        // still waiting for CVRP and CLOK
+        session.check(qt_pkt::SYNC_PACKET_MAGIC_CVRP).unwrap();
+        session.advance(qt_pkt::SYNC_PACKET_MAGIC_CVRP);
+        assert_eq!(session.phase(), Phase::VideoClock);
+
+        session.advance(qt_pkt::ASYN_PACKET_MAGIC_FEED);
+        assert_eq!(session.phase(), Phase::VideoClock);
+
+        session.check(qt_pkt::SYNC_PACKET_MAGIC_CLOK).unwrap();
+        session.advance(qt_pkt::SYNC_PACKET_MAGIC_CLOK);
+        session.advance(qt_pkt::ASYN_PACKET_MAGIC_EAT);
+        assert_eq!(session.phase(), Phase::Streaming);
+    }
+}
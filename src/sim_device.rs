@@ -0,0 +1,703 @@
+#![allow(clippy::question_mark)]
+
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::format_desc::{FormatDescriptor, AVC1, HVC1};
+use crate::coremedia::sample::{SampleBuffer, SampleTimingInfo, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO, SATT_NOT_SYNC};
+use crate::coremedia::time::Time;
+use crate::error::QTError;
+use crate::qt::{EMPTY_CF_TYPE, HPA0, HPA1, HPD0, HPD1, NEED};
+use crate::packet_buffer::PacketBytes;
+use crate::qt_pkt::{value_payload, AsynMessage, Message, QTPacket, SyncMessage};
+use crate::qt_value::{QTKeyValuePair, QTValue};
+use crate::transport::Transport;
+use std::collections::VecDeque;
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const SIM_MAX_READ_PACKET_SIZE: usize = 512;
+const SIM_PING_HEADER: u64 = 0x0000000100000000;
+const SIM_AUDIO_CLOCK_REF: u64 = 0x7FA66CE20CB0;
+const SIM_VIDEO_CLOCK_REF: u64 = 0x7FA67CC17980;
+const SIM_CLOK_CLOCK_REF: u64 = 0x7FA67CE03D70;
+const SIM_VIDEO_TIME_SCALE: u32 = 1_000_000_000;
+const SIM_AUDIO_TIME_SCALE: u32 = 48000;
+const SIM_AUDIO_FRAMES_PER_SAMPLE: u32 = 1024;
+// an IDR every this many frames
+const SIM_GOP_LENGTH: u32 = 10;
+
+// local audio clock ref the host hands back in the CWPA reply
+const HOST_AUDIO_CLOCK_OFFSET: u64 = 1000;
+// host clock ref the host hands back in the CLOK reply
+const HOST_CLOK_CLOCK_OFFSET: u64 = 0x10000;
+// clock ref the host hands back in the CVRP reply
+const HOST_VIDEO_CLOCK_OFFSET: u64 = 0x1000AF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostPacket {
+    Ping,
+    Reply {
+        correlation_id: u64,
+        payload: Option<Vec<u8>>,
+    },
+    Asyn {
+        magic: u32,
+        clock_ref: u64,
+    },
+}
+
+impl HostPacket {
+    fn parse(buf: &[u8]) -> Result<HostPacket, QTError> {
+        let msg = match Message::decode(buf) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        match msg {
+            Message::Ping { .. } => Ok(HostPacket::Ping),
+            Message::Reply {
+                correlation_id,
+                reserved,
+                payload,
+            } => {
+                if reserved != 0 {
+                    return Err(QTError::MalformedValue(format!(
+                        "reply reserved field {:#x}",
+                        reserved
+                    )));
+                }
+
+                Ok(HostPacket::Reply {
+                    correlation_id,
+                    payload: Some(Vec::from(payload.as_ref())),
+                })
+            }
+            Message::Asyn { clock_ref, message } => Ok(HostPacket::Asyn {
+                magic: message.magic(),
+                clock_ref,
+            }),
+            Message::Sync { .. } => Err(QTError::UnknownMagic(msg.magic())),
+        }
+    }
+
+    // an expected reply without payload matches any payload
+    fn matches(&self, received: &HostPacket) -> bool {
+        match (self, received) {
+            (
+                HostPacket::Reply {
+                    correlation_id: expected_id,
+                    payload: None,
+                },
+                HostPacket::Reply { correlation_id, .. },
+            ) => expected_id == correlation_id,
+            _ => self == received,
+        }
+    }
+}
+
+enum SimStep {
+    Send {
+        name: &'static str,
+        packet: Vec<u8>,
+        expect: Vec<HostPacket>,
+    },
+    // wait for the host to send packets without sending anything
+    Await {
+        name: &'static str,
+        expect: Vec<HostPacket>,
+    },
+    // the user asked the host to stop, flip term
+    Terminate,
+}
+
+impl SimStep {
+    fn name(&self) -> &'static str {
+        match self {
+            SimStep::Send { name, .. } => name,
+            SimStep::Await { name, .. } => name,
+            SimStep::Terminate => "terminate",
+        }
+    }
+
+    fn expect(&self) -> &[HostPacket] {
+        match self {
+            SimStep::Send { expect, .. } => expect,
+            SimStep::Await { expect, .. } => expect,
+            SimStep::Terminate => &[],
+        }
+    }
+}
+
+pub struct SimReport {
+    received: Vec<HostPacket>,
+    errors: Vec<String>,
+    finished: bool,
+}
+
+impl SimReport {
+    pub fn received(&self) -> &Vec<HostPacket> {
+        &self.received
+    }
+
+    pub fn errors(&self) -> &Vec<String> {
+        &self.errors
+    }
+
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn passed(&self) -> bool {
+        self.finished && self.errors.is_empty()
+    }
+}
+
+pub struct SimulatedDevice {
+    steps: VecDeque<SimStep>,
+    current: Option<SimStep>,
+    step_received: Vec<HostPacket>,
+    outbound: VecDeque<u8>,
+    report: Arc<Mutex<SimReport>>,
+    term: Arc<AtomicBool>,
+}
+
+impl SimulatedDevice {
+    pub fn new(video_frames: u32, audio: bool, hevc: bool) -> Result<SimulatedDevice, Error> {
+        let steps = match build_script(video_frames, audio, hevc) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        Ok(SimulatedDevice {
+            steps,
+            current: None,
+            step_received: Vec::new(),
+            outbound: VecDeque::new(),
+            report: Arc::new(Mutex::new(SimReport {
+                received: Vec::new(),
+                errors: Vec::new(),
+                finished: false,
+            })),
+            term: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn report(&self) -> Arc<Mutex<SimReport>> {
+        self.report.clone()
+    }
+
+    // flipped by the script when it wants the host to shut the session down
+    pub fn term(&self) -> Arc<AtomicBool> {
+        self.term.clone()
+    }
+
+    fn finish_step(&mut self) {
+        let step = match self.current.take() {
+            Some(s) => s,
+            None => return,
+        };
+
+        let received = std::mem::take(&mut self.step_received);
+        let mut report = self.report.lock().unwrap();
+
+        if received.len() != step.expect().len() {
+            report.errors.push(format!(
+                "{}: expected {} host packets, got {}: {:?}",
+                step.name(),
+                step.expect().len(),
+                received.len(),
+                received
+            ));
+            return;
+        }
+
+        for (expected, got) in step.expect().iter().zip(received.iter()) {
+            if !expected.matches(got) {
+                report.errors.push(format!(
+                    "{}: expected {:?}, got {:?}",
+                    step.name(),
+                    expected,
+                    got
+                ));
+            }
+        }
+    }
+}
+
+impl Transport for SimulatedDevice {
+    fn init(&mut self) -> Result<(), QTError> {
+        Ok(())
+    }
+
+    fn max_read_packet_size(&self) -> usize {
+        SIM_MAX_READ_PACKET_SIZE
+    }
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, QTError> {
+        while self.outbound.is_empty() {
+            if let Some(SimStep::Await { expect, .. }) = &self.current {
+                if self.step_received.len() < expect.len() {
+                    thread::sleep(Duration::from_millis(1));
+                    return Ok(0);
+                }
+            }
+
+            self.finish_step();
+
+            let step = match self.steps.pop_front() {
+                Some(s) => s,
+                None => return Err(QTError::Transport(String::from("simulation finished"))),
+            };
+
+            match &step {
+                SimStep::Send { packet, .. } => self.outbound.extend(packet),
+                SimStep::Await { .. } => {}
+                SimStep::Terminate => self.term.store(true, Ordering::SeqCst),
+            };
+
+            self.current = Some(step);
+        }
+
+        let size = buf.len().min(self.outbound.len());
+        for (i, b) in self.outbound.drain(..size).enumerate() {
+            buf[i] = b;
+        }
+
+        Ok(size)
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, QTError> {
+        let pkt = match HostPacket::parse(buf) {
+            Ok(e) => e,
+            Err(e) => {
+                self.report
+                    .lock()
+                    .unwrap()
+                    .errors
+                    .push(format!("invalid host packet: {}", e));
+                return Ok(buf.len());
+            }
+        };
+
+        self.report.lock().unwrap().received.push(pkt.clone());
+        self.step_received.push(pkt);
+
+        Ok(buf.len())
+    }
+
+    fn teardown(&mut self) -> Result<(), QTError> {
+        self.finish_step();
+
+        let mut report = self.report.lock().unwrap();
+
+        if !self.steps.is_empty() {
+            report
+                .errors
+                .push(format!("session ended with {} steps left", self.steps.len()));
+        }
+
+        report.finished = true;
+
+        Ok(())
+    }
+}
+
+fn as_vec(pkt: &mut QTPacket) -> Result<Vec<u8>, Error> {
+    match pkt.as_bytes() {
+        Ok(e) => Ok(Vec::from(e)),
+        Err(e) => Err(e),
+    }
+}
+
+fn ping_packet() -> Vec<u8> {
+    Message::Ping {
+        header: SIM_PING_HEADER,
+    }
+    .encode()
+}
+
+fn sync_packet(clock_ref: u64, correlation_id: u64, message: SyncMessage) -> Vec<u8> {
+    Message::Sync {
+        clock_ref,
+        correlation_id,
+        message,
+    }
+    .encode()
+}
+
+fn asyn_packet(clock_ref: u64, message: AsynMessage) -> Vec<u8> {
+    Message::asyn(clock_ref, message).encode()
+}
+
+fn cvrp_payload() -> Result<PacketBytes, Error> {
+    let arr: Vec<QTValue> = vec![QTValue::KeyValuePair(QTKeyValuePair::new(
+        QTValue::StringKey(String::from("PreparedQueueHighWaterLevel")),
+        QTValue::UInt64(5),
+    ))];
+
+    value_payload(&QTValue::Object(arr))
+}
+
+fn sprp_payload(key: &str, value: bool) -> Result<PacketBytes, Error> {
+    value_payload(&QTValue::KeyValuePair(QTKeyValuePair::new(
+        QTValue::StringKey(String::from(key)),
+        QTValue::Boolean(value),
+    )))
+}
+
+fn video_sample(frame: u32, hevc: bool) -> Result<Vec<u8>, Error> {
+    let mut sample = SampleBuffer::new(MEDIA_TYPE_VIDEO);
+
+    // 60 fps in nanoseconds
+    let pts = frame as u64 * 16_666_667;
+    let duration = Time::new(16_666_667, SIM_VIDEO_TIME_SCALE, 1, 0);
+    let presentation = Time::new(pts, SIM_VIDEO_TIME_SCALE, 1, 0);
+    let decode = Time::new(0, 0, 0, 0);
+
+    sample.set_output_presentation_time_stamp(presentation.clone());
+    sample.set_sample_timing_info_array(Vec::from([SampleTimingInfo::new(
+        duration,
+        presentation,
+        decode,
+    )]));
+
+    let nalu: &[u8] = match (frame % SIM_GOP_LENGTH, hevc) {
+        (0, false) => &[0x65, 0x88, 0x84, 0x00, 0x10],
+        (_, false) => &[0x41, 0x9A, 0x02, 0x00, 0x10],
+        (0, true) => &[0x26, 0x01, 0xAF, 0x00, 0x10],
+        (_, true) => &[0x02, 0x01, 0xD0, 0x00, 0x10],
+    };
+
+    let mut data: Vec<u8> = Vec::from((nalu.len() as u32).to_be_bytes());
+    data.extend_from_slice(nalu);
+    let data_len = data.len() as u32;
+    sample.set_sample_data(data, Vec::from([data_len]));
+
+    // the device marks everything but the IDR NotSync
+    if !frame.is_multiple_of(SIM_GOP_LENGTH) {
+        sample.set_attachments(Vec::from([QTValue::KeyValuePair(QTKeyValuePair::new(
+            QTValue::IdxKey(SATT_NOT_SYNC),
+            QTValue::UInt32(1),
+        ))]));
+    }
+
+    if frame == 0 && hevc {
+        let hvc1 = HVC1::new(
+            &[0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF],
+            &[0x42, 0x01, 0x01, 0x01, 0x60, 0x00],
+            &[0x44, 0x01, 0xC1, 0x72],
+        );
+        sample.set_format_description(FormatDescriptor::new_hvc1(1920, 1080, hvc1));
+    } else if frame == 0 {
+        let avc1 = AVC1::new(
+            0x64,
+            0x00,
+            0x28,
+            &[0x67, 0x64, 0x00, 0x28, 0xAC, 0xD1],
+            &[0x68, 0xEB, 0xEF, 0x2C],
+        );
+        sample.set_format_description(FormatDescriptor::new_avc1(1920, 1080, avc1));
+    }
+
+    let mut pkt = match sample.as_qt_packet() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    as_vec(&mut pkt)
+}
+
+fn audio_sample(index: u32) -> Result<Vec<u8>, Error> {
+    let mut sample = SampleBuffer::new(MEDIA_TYPE_SOUND);
+
+    let pts = (index * SIM_AUDIO_FRAMES_PER_SAMPLE) as u64;
+    sample.set_output_presentation_time_stamp(Time::new(pts, SIM_AUDIO_TIME_SCALE, 1, 0));
+
+    let asd = AudioStreamDescription::default();
+    let size = SIM_AUDIO_FRAMES_PER_SAMPLE * asd.bytes_per_frame();
+    sample.set_sample_data(vec![0; size as usize], Vec::from([size]));
+
+    if index == 0 {
+        sample.set_format_description(FormatDescriptor::new_audio(asd));
+    }
+
+    let mut pkt = match sample.as_qt_packet() {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    as_vec(&mut pkt)
+}
+
+fn build_script(video_frames: u32, audio: bool, hevc: bool) -> Result<VecDeque<SimStep>, Error> {
+    let mut steps: VecDeque<SimStep> = VecDeque::new();
+    let mut correlation_id: u64 = 0x1000;
+
+    steps.push_back(SimStep::Send {
+        name: "PING",
+        packet: ping_packet(),
+        expect: Vec::from([HostPacket::Ping]),
+    });
+
+    correlation_id += 1;
+    let mut cwpa_expect = Vec::from([
+        HostPacket::Asyn {
+            magic: HPD1,
+            clock_ref: EMPTY_CF_TYPE,
+        },
+        HostPacket::Reply {
+            correlation_id,
+            payload: Some(Vec::from(
+                (SIM_AUDIO_CLOCK_REF + HOST_AUDIO_CLOCK_OFFSET).to_le_bytes(),
+            )),
+        },
+    ]);
+    if audio {
+        cwpa_expect.push(HostPacket::Asyn {
+            magic: HPA1,
+            clock_ref: SIM_AUDIO_CLOCK_REF,
+        });
+    }
+    steps.push_back(SimStep::Send {
+        name: "CWPA",
+        packet: sync_packet(
+            EMPTY_CF_TYPE,
+            correlation_id,
+            SyncMessage::Cwpa {
+                device_clock_ref: SIM_AUDIO_CLOCK_REF,
+            },
+        ),
+        expect: cwpa_expect,
+    });
+
+    correlation_id += 1;
+    let afmt_payload = AudioStreamDescription::default().as_buffer()?;
+    steps.push_back(SimStep::Send {
+        name: "AFMT",
+        packet: sync_packet(
+            SIM_AUDIO_CLOCK_REF + HOST_AUDIO_CLOCK_OFFSET,
+            correlation_id,
+            SyncMessage::Afmt {
+                payload: PacketBytes::from_vec(afmt_payload),
+            },
+        ),
+        expect: Vec::from([HostPacket::Reply {
+            correlation_id,
+            payload: None,
+        }]),
+    });
+
+    correlation_id += 1;
+    steps.push_back(SimStep::Send {
+        name: "CVRP",
+        packet: sync_packet(
+            EMPTY_CF_TYPE,
+            correlation_id,
+            SyncMessage::Cvrp {
+                device_clock_ref: SIM_VIDEO_CLOCK_REF,
+                payload: cvrp_payload()?,
+            },
+        ),
+        expect: Vec::from([
+            HostPacket::Asyn {
+                magic: NEED,
+                clock_ref: SIM_VIDEO_CLOCK_REF,
+            },
+            HostPacket::Reply {
+                correlation_id,
+                payload: Some(Vec::from(
+                    (SIM_VIDEO_CLOCK_REF + HOST_VIDEO_CLOCK_OFFSET).to_le_bytes(),
+                )),
+            },
+        ]),
+    });
+
+    correlation_id += 1;
+    steps.push_back(SimStep::Send {
+        name: "CLOK",
+        packet: sync_packet(SIM_CLOK_CLOCK_REF, correlation_id, SyncMessage::Clok),
+        expect: Vec::from([HostPacket::Reply {
+            correlation_id,
+            payload: Some(Vec::from(
+                (SIM_CLOK_CLOCK_REF + HOST_CLOK_CLOCK_OFFSET).to_le_bytes(),
+            )),
+        }]),
+    });
+
+    correlation_id += 1;
+    steps.push_back(SimStep::Send {
+        name: "TIME",
+        packet: sync_packet(
+            SIM_CLOK_CLOCK_REF + HOST_CLOK_CLOCK_OFFSET,
+            correlation_id,
+            SyncMessage::Time,
+        ),
+        expect: Vec::from([HostPacket::Reply {
+            correlation_id,
+            payload: None,
+        }]),
+    });
+
+    // what a device sends right before the first frame, none of it is answered
+    steps.push_back(SimStep::Send {
+        name: "SPRP",
+        packet: asyn_packet(
+            SIM_VIDEO_CLOCK_REF,
+            AsynMessage::Sprp {
+                payload: sprp_payload("ObeyEmptyMediaMarkers", true)?,
+            },
+        ),
+        expect: Vec::new(),
+    });
+
+    steps.push_back(SimStep::Send {
+        name: "SPRP",
+        packet: asyn_packet(
+            SIM_VIDEO_CLOCK_REF,
+            AsynMessage::Sprp {
+                payload: sprp_payload("RenderEmptyMedia", false)?,
+            },
+        ),
+        expect: Vec::new(),
+    });
+
+    steps.push_back(SimStep::Send {
+        name: "TBAS",
+        packet: asyn_packet(
+            SIM_VIDEO_CLOCK_REF,
+            AsynMessage::Tbas {
+                time_base_ref: SIM_CLOK_CLOCK_REF,
+            },
+        ),
+        expect: Vec::new(),
+    });
+
+    steps.push_back(SimStep::Send {
+        name: "SRAT",
+        packet: asyn_packet(
+            SIM_VIDEO_CLOCK_REF,
+            AsynMessage::Srat {
+                rate1: 1.0,
+                rate2: 1.0,
+                time: Time::new(0, SIM_VIDEO_TIME_SCALE, 1, 0),
+            },
+        ),
+        expect: Vec::new(),
+    });
+
+    for frame in 0..video_frames {
+        // the device moves its clock mid stream, the frames still continue
+        if frame > 0 && frame == video_frames / 2 {
+            steps.push_back(SimStep::Send {
+                name: "TJMP",
+                packet: asyn_packet(
+                    SIM_VIDEO_CLOCK_REF,
+                    AsynMessage::Tjmp {
+                        payload: PacketBytes::from_vec(vec![0; 16]),
+                    },
+                ),
+                expect: Vec::new(),
+            });
+        }
+
+        steps.push_back(SimStep::Send {
+            name: "FEED",
+            packet: asyn_packet(
+                SIM_VIDEO_CLOCK_REF,
+                AsynMessage::Feed {
+                    payload: PacketBytes::from_vec(video_sample(frame, hevc)?),
+                },
+            ),
+            expect: Vec::from([HostPacket::Asyn {
+                magic: NEED,
+                clock_ref: SIM_VIDEO_CLOCK_REF,
+            }]),
+        });
+
+        if audio {
+            steps.push_back(SimStep::Send {
+                name: "EAT!",
+                packet: asyn_packet(
+                    SIM_AUDIO_CLOCK_REF,
+                    AsynMessage::Eat {
+                        payload: PacketBytes::from_vec(audio_sample(frame)?),
+                    },
+                ),
+                expect: Vec::new(),
+            });
+        }
+    }
+
+    if audio && video_frames > 0 {
+        correlation_id += 1;
+        steps.push_back(SimStep::Send {
+            name: "SKEW",
+            packet: sync_packet(
+                SIM_AUDIO_CLOCK_REF + HOST_AUDIO_CLOCK_OFFSET,
+                correlation_id,
+                SyncMessage::Skew,
+            ),
+            expect: Vec::from([HostPacket::Reply {
+                correlation_id,
+                payload: None,
+            }]),
+        });
+    }
+
+    correlation_id += 1;
+    steps.push_back(SimStep::Send {
+        name: "OG",
+        packet: sync_packet(
+            SIM_AUDIO_CLOCK_REF + HOST_AUDIO_CLOCK_OFFSET,
+            correlation_id,
+            SyncMessage::Og { unknown: 1 },
+        ),
+        expect: Vec::from([HostPacket::Reply {
+            correlation_id,
+            payload: Some(Vec::from(0u32.to_le_bytes())),
+        }]),
+    });
+
+    // user hits ctrl-c, host closes the session and the device stops streaming
+    steps.push_back(SimStep::Terminate);
+
+    steps.push_back(SimStep::Await {
+        name: "close session",
+        expect: Vec::from([
+            HostPacket::Asyn {
+                magic: HPA0,
+                clock_ref: SIM_AUDIO_CLOCK_REF,
+            },
+            HostPacket::Asyn {
+                magic: HPD0,
+                clock_ref: EMPTY_CF_TYPE,
+            },
+        ]),
+    });
+
+    correlation_id += 1;
+    steps.push_back(SimStep::Send {
+        name: "STOP",
+        packet: sync_packet(SIM_VIDEO_CLOCK_REF, correlation_id, SyncMessage::Stop),
+        expect: Vec::from([HostPacket::Reply {
+            correlation_id,
+            payload: Some(Vec::from(0u32.to_le_bytes())),
+        }]),
+    });
+
+    steps.push_back(SimStep::Send {
+        name: "RELS video",
+        packet: asyn_packet(SIM_VIDEO_CLOCK_REF, AsynMessage::Rels),
+        expect: Vec::new(),
+    });
+
+    steps.push_back(SimStep::Send {
+        name: "RELS clok",
+        packet: asyn_packet(SIM_CLOK_CLOCK_REF, AsynMessage::Rels),
+        expect: Vec::new(),
+    });
+
+    Ok(steps)
+}
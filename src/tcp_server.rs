@@ -1,19 +1,37 @@
+#![allow(clippy::needless_return, clippy::question_mark, clippy::single_match)]
+
+use crate::error::QTError;
+use crate::coremedia::format_desc::FormatDescriptor;
 use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use log::{info, warn};
 
 use std::io;
 use std::io::Write;
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// samples queued per client before it starts skipping
+const CLIENT_QUEUE_SIZE: usize = 64;
+// about two seconds of video, after that the client is dropped
+const CLIENT_MAX_SKIPPED: u32 = 120;
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// longest wait for a sample before new connections and term are looked at again
+const RECV_TIMEOUT: Duration = Duration::from_millis(50);
+// frames since the last IDR kept for late joiners
+const GOP_CACHE_MAX_BYTES: usize = 16 * 1024 * 1024;
 
 pub struct TcpServer {
     address: String,
-    rx: Receiver<Result<SampleBuffer, io::Error>>,
+    rx: Receiver<Result<SampleBuffer, QTError>>,
     media_type: u32,
     include_header: bool,
     connected_state: Option<Arc<AtomicBool>>,
+    term: Arc<AtomicBool>,
 }
 
 impl AsRef<TcpServer> for TcpServer {
@@ -25,10 +43,11 @@ impl AsRef<TcpServer> for TcpServer {
 impl TcpServer {
     pub fn new(
         address: String,
-        rx: Receiver<Result<SampleBuffer, io::Error>>,
+        rx: Receiver<Result<SampleBuffer, QTError>>,
         media_type: u32,
         include_header: Option<bool>,
         connected_state: Option<Arc<AtomicBool>>,
+        term: Arc<AtomicBool>,
     ) -> TcpServer {
         return TcpServer {
             address,
@@ -36,6 +55,7 @@ impl TcpServer {
             media_type,
             include_header: include_header.unwrap_or(false),
             connected_state,
+            term,
         };
     }
 
@@ -47,90 +67,365 @@ impl TcpServer {
         };
         let listener = TcpListener::bind(&self.address).expect("Failed to bind address");
 
-        println!(
+        info!(
             "{} server started on port {}",
             media_type_str,
             listener.local_addr().unwrap().port()
         );
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    println!(
-                        "New {} connection: {}",
-                        media_type_str,
-                        stream.peer_addr().unwrap()
-                    );
+        // poll accept so we notice term and keep draining rx between connections
+        match listener.set_nonblocking(true) {
+            Err(e) => {
+                warn!("{} listener nonblocking: {}", media_type_str, e);
+                return;
+            }
+            _ => {}
+        };
+
+        let mut clients: Vec<Client> = Vec::new();
+        let mut gop = GopCache::new();
+        // size of the NALU lengths in the sample data, from the last avcC/hvcC
+        let mut nalu_len = 4;
+
+        while !self.term.load(Ordering::SeqCst) {
+            self.accept(&listener, &mut clients, &gop, media_type_str);
+
+            if let Some(state) = &self.connected_state {
+                state.store(!clients.is_empty(), Ordering::SeqCst);
+            }
 
-                    self.handle_send(stream);
+            // rx is always drained, with or without clients, so qt never blocks on us
+            let message = match self.rx.recv_timeout(RECV_TIMEOUT) {
+                Ok(msg) => msg,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(_) => break,
+            };
+
+            let sample_buffer = match message {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("sample error: {}", e);
+                    continue;
                 }
+            };
+
+            if let Some(codec) = sample_buffer.format_description().and_then(|fd| fd.video_codec()) {
+                nalu_len = codec.nalu_len();
+            }
+
+            let payload = match self.encode(&sample_buffer, nalu_len) {
+                Ok(Some(e)) => e,
+                Ok(None) => continue,
                 Err(e) => {
-                    eprintln!("{} connection error: {}", media_type_str, e);
+                    warn!("{} drop sample: {}", media_type_str, e);
+                    continue;
+                }
+            };
+
+            if self.media_type == MEDIA_TYPE_SOUND {
+                // raw lpcm has no header, tell whoever runs the player what it is
+                if let Some(asd) = sample_buffer.format_description().and_then(|fd| fd.audio_stream_description()) {
+                    match asd.ffmpeg_format() {
+                        Some(f) => info!(
+                            "audio format: -f {} -ar {} -ch_layout {}",
+                            f,
+                            asd.sample_rate(),
+                            asd.channels_per_frame()
+                        ),
+                        None => info!("audio format {:#x} has no raw ffmpeg equivalent", asd.format_flags()),
+                    };
                 }
+
+                let data = Arc::new(payload);
+                clients.retain_mut(|client| client.send(&data, None, media_type_str));
+                continue;
+            }
+
+            if let Some(fd) = sample_buffer.format_description() {
+                gop.set_parameter_sets(fd);
+            }
+
+            let sync = sample_buffer.is_sync();
+            let payload = Arc::new(payload);
+            gop.push(&payload, sync);
+
+            if clients.is_empty() {
+                continue;
             }
+
+            let data = Arc::new(self.frame(&payload));
+            // clients waiting for a sync sample get it with the cached sps/pps in front
+            let sync_data = match sync {
+                true => Some(Arc::new(self.frame(&gop.with_parameter_sets(&payload)))),
+                false => None,
+            };
+
+            clients.retain_mut(|client| client.send(&data, sync_data.as_ref(), media_type_str));
         }
-    }
 
-    fn handle_send(&self, mut stream: std::net::TcpStream) {
         if let Some(state) = &self.connected_state {
-            state.store(true, Ordering::SeqCst);
+            state.store(false, Ordering::SeqCst);
         }
 
+        // dropping the senders ends the client threads
+        clients.clear();
+
+        info!("{} server stopped", media_type_str);
+    }
+
+    fn accept(&self, listener: &TcpListener, clients: &mut Vec<Client>, gop: &GopCache, media_type_str: &str) {
         loop {
-            let message = match self.rx.try_recv() {
-                Ok(msg) => msg,
-                Err(mpsc::TryRecvError::Empty) => {
-                    std::thread::sleep(std::time::Duration::from_millis(1));
-                    continue;
+            let (stream, addr) = match listener.accept() {
+                Ok(e) => e,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("{} connection error: {}", media_type_str, e);
+                    return;
                 }
-                Err(_) => break,
             };
 
-            let sample_buffer = message.unwrap();
-            let buf = sample_buffer.sample_data().unwrap();
+            info!("New {} connection: {}", media_type_str, addr);
 
-            if self.media_type == MEDIA_TYPE_SOUND {
-                stream.write_all(buf).expect("Failed to write audio data");
-            } else {
-                let mut combined_data = Vec::new();
-
-                // Add format description data
-                if let Some(fd) = sample_buffer.format_description() {
-                    combined_data.extend_from_slice(&1u32.to_be_bytes());
-                    combined_data.extend_from_slice(fd.avc1().sps());
-                    combined_data.extend_from_slice(&1u32.to_be_bytes());
-                    combined_data.extend_from_slice(fd.avc1().pps());
+            let mut client = match Client::connect(stream, addr, self.media_type == MEDIA_TYPE_VIDEO) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("{} connection error: {}", media_type_str, e);
+                    continue;
                 }
+            };
+
+            // late joiners start with sps/pps and the IDR of the current GOP, or wait for the next one
+            if self.media_type == MEDIA_TYPE_VIDEO {
+                match gop.catch_up(|payload| self.frame(payload)) {
+                    Some(data) => match client.tx.try_send(Arc::new(data)) {
+                        Err(e) => warn!("{} catch up: {}", media_type_str, e),
+                        _ => {}
+                    },
+                    None => client.wait_for_sync(),
+                };
+            }
+
+            clients.push(client);
+        }
+    }
+
+    // annex b for video, raw lpcm for audio
+    fn encode(&self, sample_buffer: &SampleBuffer, nalu_len: u8) -> Result<Option<Vec<u8>>, io::Error> {
+        let buf = match sample_buffer.sample_data() {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        if self.media_type == MEDIA_TYPE_SOUND {
+            return Ok(Some(Vec::from(buf)));
+        }
+
+        let nal_units = match sample_buffer.nal_units_sized(nalu_len) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        let mut combined_data = Vec::new();
+
+        // Add format description data, vps/sps/pps for hevc, sps/pps for h264
+        if let Some(codec) = sample_buffer.format_description().and_then(|fd| fd.video_codec()) {
+            for nalu in codec.parameter_sets() {
+                combined_data.extend_from_slice(&1u32.to_be_bytes());
+                combined_data.extend_from_slice(nalu);
+            }
+        }
+
+        // Add sample data
+        for nalu in nal_units {
+            combined_data.extend_from_slice(&1u32.to_be_bytes());
+            combined_data.extend_from_slice(nalu);
+        }
+
+        Ok(Some(combined_data))
+    }
+
+    // the optional -i header in front of every video frame
+    fn frame(&self, payload: &[u8]) -> Vec<u8> {
+        if !self.include_header {
+            return Vec::from(payload);
+        }
+
+        let mut data_to_send = Vec::new();
+        let data_length = payload.len() as u32;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        data_to_send.extend_from_slice(&data_length.to_le_bytes());
+        data_to_send.extend_from_slice(&timestamp.to_le_bytes());
+        data_to_send.extend_from_slice(payload);
+
+        data_to_send
+    }
+}
+
+// parameter sets from the last format description and every frame since the last IDR
+struct GopCache {
+    parameter_sets: Option<Vec<u8>>,
+    frames: Vec<Arc<Vec<u8>>>,
+    size: usize,
+}
+
+impl GopCache {
+    fn new() -> GopCache {
+        GopCache {
+            parameter_sets: None,
+            frames: Vec::new(),
+            size: 0,
+        }
+    }
+
+    fn set_parameter_sets(&mut self, fd: &FormatDescriptor) {
+        let codec = match fd.video_codec() {
+            Some(e) => e,
+            None => return,
+        };
+
+        let mut ps: Vec<u8> = Vec::new();
+        for nalu in codec.parameter_sets() {
+            ps.extend_from_slice(&1u32.to_be_bytes());
+            ps.extend_from_slice(nalu);
+        }
+        self.parameter_sets = Some(ps);
+    }
+
+    fn push(&mut self, payload: &Arc<Vec<u8>>, sync: bool) {
+        if sync {
+            self.frames.clear();
+            self.size = 0;
+        } else if self.frames.is_empty() {
+            // nothing decodable to start from until the next IDR
+            return;
+        }
+
+        self.size += payload.len();
+        self.frames.push(payload.clone());
+
+        // a very long GOP is not worth the memory, late joiners wait for the next IDR instead
+        if self.size > GOP_CACHE_MAX_BYTES {
+            self.frames.clear();
+            self.size = 0;
+        }
+    }
+
+    fn with_parameter_sets(&self, payload: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = self.parameter_sets.clone().unwrap_or_default();
+        data.extend_from_slice(payload);
+        data
+    }
 
-                // Add sample data
-                let mut cur = buf;
-                while cur.len() > 0 {
-                    let slice_len = u32::from_be_bytes([cur[0], cur[1], cur[2], cur[3]]) as usize;
-                    combined_data.extend_from_slice(&1u32.to_be_bytes());
-                    combined_data.extend_from_slice(&cur[4..slice_len + 4]);
-                    cur = &cur[slice_len + 4..];
+    // everything a new client needs to start decoding right away, as one message
+    fn catch_up<F: Fn(&[u8]) -> Vec<u8>>(&self, frame: F) -> Option<Vec<u8>> {
+        let (first, rest) = match self.frames.split_first() {
+            Some(e) => e,
+            None => return None,
+        };
+
+        if self.parameter_sets.is_none() {
+            return None;
+        }
+
+        let mut data = frame(&self.with_parameter_sets(first));
+        for payload in rest {
+            data.extend_from_slice(&frame(payload));
+        }
+
+        Some(data)
+    }
+}
+
+pub(crate) struct Client {
+    addr: SocketAddr,
+    tx: SyncSender<Arc<Vec<u8>>>,
+    // samples in a row that did not fit in the queue
+    skipped: u32,
+    video: bool,
+    // video skipped a frame or joined without a GOP, wait for the next IDR
+    need_sync: bool,
+}
+
+impl Client {
+    // the socket gets its own writer thread behind a bounded queue
+    pub(crate) fn connect(stream: TcpStream, addr: SocketAddr, video: bool) -> Result<Client, io::Error> {
+        // a stalled peer must not pin its writer thread forever
+        let res = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT)));
+        match res {
+            Err(e) => return Err(e),
+            _ => {}
+        };
+
+        let (tx, rx) = mpsc::sync_channel::<Arc<Vec<u8>>>(CLIENT_QUEUE_SIZE);
+
+        thread::spawn(move || {
+            handle_send(stream, rx);
+        });
+
+        Ok(Client {
+            addr,
+            tx,
+            skipped: 0,
+            video,
+            need_sync: false,
+        })
+    }
+
+    // nothing is queued until the next sync sample
+    pub(crate) fn wait_for_sync(&mut self) {
+        self.need_sync = true;
+    }
+
+    // false once the client is gone or too slow to keep, sync_data is set for video sync samples
+    pub(crate) fn send(&mut self, data: &Arc<Vec<u8>>, sync_data: Option<&Arc<Vec<u8>>>, media_type_str: &str) -> bool {
+        let data = match (self.need_sync, sync_data) {
+            (true, Some(sync_data)) => sync_data,
+            (true, None) => return true,
+            (false, _) => data,
+        };
+
+        match self.tx.try_send(data.clone()) {
+            Ok(_) => {
+                self.skipped = 0;
+                self.need_sync = false;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                self.skipped += 1;
+                // after a dropped video frame only an IDR decodes cleanly again
+                if self.video {
+                    self.need_sync = true;
                 }
 
-                // Send the combined data
-                if self.include_header {
-                    let mut data_to_send = Vec::new();
-                    let data_length = combined_data.len() as u32;
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .expect("Time went backwards")
-                        .as_millis() as u64;
-                    data_to_send.extend_from_slice(&data_length.to_le_bytes());
-                    data_to_send.extend_from_slice(&timestamp.to_le_bytes());
-                    data_to_send.append(&mut combined_data);
-                    stream
-                        .write_all(&data_to_send)
-                        .expect("write combined data with length and timestamp");
-                } else {
-                    stream
-                        .write_all(&combined_data)
-                        .expect("write combined data");
+                if self.skipped < CLIENT_MAX_SKIPPED {
+                    return true;
                 }
+
+                warn!("{} client {} too slow, dropped", media_type_str, self.addr);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("{} client {} disconnected", media_type_str, self.addr);
+                false
             }
         }
     }
 }
+
+// one thread per client, a slow socket only backs up its own queue
+fn handle_send(mut stream: TcpStream, rx: Receiver<Arc<Vec<u8>>>) {
+    for data in rx.iter() {
+        match stream.write_all(&data) {
+            Err(e) => {
+                warn!("write to client: {}", e);
+                return;
+            }
+            _ => {}
+        };
+    }
+}
@@ -0,0 +1,311 @@
+use crate::coremedia::audio_desc::AudioStreamDescription;
+use crate::coremedia::sample::{SampleBuffer, MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use crate::coremedia::time::Time;
+use crate::devices::json_string;
+use crate::error::QTError;
+use crate::packet_buffer::PacketBytes;
+use crate::qt_pkt::{fourcc, AsynMessage, Message, QTPacket, SyncMessage};
+use crate::qt_value::QTValue;
+use log::info;
+use std::time::Instant;
+
+// replies this short are clock refs, times or error codes, shown as hex
+const SHORT_PAYLOAD: usize = 32;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+// one traced packet before it is printed
+struct TraceLine {
+    incoming: bool,
+    kind: &'static str,
+    magic: Option<u32>,
+    clock_ref: Option<u64>,
+    correlation_id: Option<u64>,
+    detail: String,
+    hex: Option<String>,
+}
+
+// prints every packet exchanged with the device on stderr, so the trace stays apart from the
+// normal output. text is for reading along, json is one object per line
+#[derive(Clone)]
+pub struct Trace {
+    format: TraceFormat,
+    // hex dump packets we can't decode
+    hex: bool,
+    start: Instant,
+}
+
+fn time_str(t: &Time) -> String {
+    format!("{}/{}", t.value(), t.scale())
+}
+
+// a dictionary or key value pair, or None when the payload isn't exactly one value
+fn value_str(payload: &PacketBytes) -> Option<String> {
+    let bytes = payload.as_ref();
+    if bytes.len() < 8 {
+        return None;
+    }
+
+    let value_len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    if value_len != bytes.len() {
+        return None;
+    }
+
+    match QTValue::from_qt_packet(&mut QTPacket::from_payload(payload.clone())) {
+        Ok(value) => Some(value.to_str(String::new())),
+        Err(_) => None,
+    }
+}
+
+fn audio_desc_str(payload: &PacketBytes) -> String {
+    match AudioStreamDescription::from_qt_packet(&mut QTPacket::from_payload(payload.clone())) {
+        Ok(asd) => format!(
+            "{} Hz {} channels {} bits format {} flags {:#x}",
+            asd.sample_rate(),
+            asd.channels_per_frame(),
+            asd.bits_per_channel(),
+            fourcc(asd.format_id()),
+            asd.format_flags()
+        ),
+        Err(e) => format!("invalid audio description: {}", e),
+    }
+}
+
+fn sample_str(payload: &PacketBytes, media_type: u32) -> String {
+    let sample = match SampleBuffer::from_qt_packet(&mut QTPacket::from_payload(payload.clone()), media_type) {
+        Ok(e) => e,
+        Err(e) => return format!("invalid sample buffer: {}", e),
+    };
+
+    let mut s = format!(
+        "samples {} bytes {}",
+        sample.num_samples(),
+        sample.sample_data().map(|d| d.len()).unwrap_or(0)
+    );
+
+    if let Some(pts) = sample.output_presentation_time_stamp() {
+        s += format!(" pts {}", time_str(&pts)).as_str();
+    }
+
+    if let Some(sti) = sample.sample_timing_info_array().and_then(|arr| arr.first()) {
+        s += format!(
+            " dts {} duration {}",
+            time_str(sti.decode_time_stamp()),
+            time_str(sti.duration())
+        )
+        .as_str();
+    }
+
+    if sample.format_description().is_some() {
+        s += " with format";
+    }
+
+    s
+}
+
+impl Trace {
+    pub fn new(format: TraceFormat, hex: bool) -> Trace {
+        Trace {
+            format,
+            hex,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn message(&self, incoming: bool, msg: &Message) {
+        let mut line = TraceLine {
+            incoming,
+            kind: "",
+            magic: Some(msg.magic()),
+            clock_ref: None,
+            correlation_id: None,
+            detail: String::new(),
+            hex: None,
+        };
+
+        match msg {
+            Message::Ping { header } => {
+                line.kind = "ping";
+                line.detail = format!("header {:#x}", header);
+            }
+            Message::Sync {
+                clock_ref,
+                correlation_id,
+                message,
+            } => {
+                line.kind = "sync";
+                line.clock_ref = Some(*clock_ref);
+                line.correlation_id = Some(*correlation_id);
+                self.sync_detail(&mut line, message);
+            }
+            Message::Asyn { clock_ref, message } => {
+                line.kind = "asyn";
+                line.clock_ref = Some(*clock_ref);
+                self.asyn_detail(&mut line, message);
+            }
+            Message::Reply {
+                correlation_id,
+                payload,
+                ..
+            } => {
+                line.kind = "rply";
+                line.correlation_id = Some(*correlation_id);
+                line.detail = match value_str(payload) {
+                    Some(e) => e,
+                    None if payload.len() <= SHORT_PAYLOAD => hex::encode(payload.as_ref()),
+                    None => format!("{} bytes", payload.len()),
+                };
+            }
+        };
+
+        self.print(&line);
+    }
+
+    // a packet Message could not decode, always dumped since it is what we are looking for
+    pub fn undecoded(&self, incoming: bool, data: &[u8], error: &QTError) {
+        let line = TraceLine {
+            incoming,
+            kind: "????",
+            magic: None,
+            clock_ref: None,
+            correlation_id: None,
+            detail: format!("{}", error),
+            hex: Some(hex::encode(data)),
+        };
+
+        self.print(&line);
+    }
+
+    fn sync_detail(&self, line: &mut TraceLine, msg: &SyncMessage) {
+        line.detail = match msg {
+            SyncMessage::Cwpa { device_clock_ref } => {
+                format!("device clock {:#x}", device_clock_ref)
+            }
+            SyncMessage::Cvrp {
+                device_clock_ref,
+                payload,
+            } => format!(
+                "device clock {:#x}\n{}",
+                device_clock_ref,
+                value_str(payload).unwrap_or_default()
+            ),
+            SyncMessage::Afmt { payload } => audio_desc_str(payload),
+            SyncMessage::Og { unknown } => format!("unknown {:#x}", unknown),
+            SyncMessage::Clok | SyncMessage::Time | SyncMessage::Skew | SyncMessage::Stop => {
+                String::new()
+            }
+            SyncMessage::Unknown { payload, .. } => self.unknown(line, payload),
+        };
+    }
+
+    fn asyn_detail(&self, line: &mut TraceLine, msg: &AsynMessage) {
+        line.detail = match msg {
+            AsynMessage::Feed { payload } => sample_str(payload, MEDIA_TYPE_VIDEO),
+            AsynMessage::Eat { payload } => sample_str(payload, MEDIA_TYPE_SOUND),
+            AsynMessage::Sprp { payload }
+            | AsynMessage::Hpd1 { payload }
+            | AsynMessage::Hpa1 { payload } => value_str(payload).unwrap_or_default(),
+            AsynMessage::Srat { rate1, rate2, time } => {
+                format!("rate {} {} anchor {}", rate1, rate2, time_str(time))
+            }
+            AsynMessage::Tbas { time_base_ref } => format!("time base {:#x}", time_base_ref),
+            AsynMessage::Tjmp { payload } => hex::encode(payload.as_ref()),
+            AsynMessage::Rels
+            | AsynMessage::Hpd0
+            | AsynMessage::Hpa0
+            | AsynMessage::Need => String::new(),
+            AsynMessage::Unknown { payload, .. } => self.unknown(line, payload),
+        };
+    }
+
+    fn unknown(&self, line: &mut TraceLine, payload: &PacketBytes) -> String {
+        if self.hex {
+            line.hex = Some(hex::encode(payload.as_ref()));
+        }
+
+        format!("unknown, {} bytes", payload.len())
+    }
+
+    fn print(&self, line: &TraceLine) {
+        let elapsed = self.start.elapsed().as_secs_f64() * 1000.0;
+
+        match self.format {
+            TraceFormat::Text => {
+                let mut s = format!(
+                    "{:>12.3} {} {} {}",
+                    elapsed,
+                    match line.incoming {
+                        true => "<-",
+                        false => "->",
+                    },
+                    line.kind,
+                    line.magic.map(fourcc).unwrap_or_else(|| String::from("????"))
+                );
+
+                if let Some(clock_ref) = line.clock_ref {
+                    s += format!(" clock {:#x}", clock_ref).as_str();
+                }
+
+                if let Some(correlation_id) = line.correlation_id {
+                    s += format!(" id {:#x}", correlation_id).as_str();
+                }
+
+                // values are printed over several lines, indented under the packet
+                let mut detail = line.detail.lines();
+                if let Some(first) = detail.next() {
+                    s += format!("  {}", first).as_str();
+                }
+                for l in detail {
+                    s += format!("\n{:>14}{}", "", l).as_str();
+                }
+
+                if let Some(hex) = &line.hex {
+                    for chunk in hex.as_bytes().chunks(64) {
+                        s += format!("\n{:>14}{}", "", String::from_utf8_lossy(chunk)).as_str();
+                    }
+                }
+
+                info!(target: "trace", "{}", s);
+            }
+            TraceFormat::Json => {
+                let mut fields: Vec<String> = Vec::new();
+                fields.push(format!("\"time_ms\":{:.3}", elapsed));
+                fields.push(format!(
+                    "\"dir\":\"{}\"",
+                    match line.incoming {
+                        true => "in",
+                        false => "out",
+                    }
+                ));
+                fields.push(format!("\"type\":\"{}\"", line.kind));
+
+                if let Some(magic) = line.magic {
+                    fields.push(format!("\"magic\":{}", json_string(fourcc(magic).as_str())));
+                }
+
+                // clock refs are pointers on the device, too big for a json number
+                if let Some(clock_ref) = line.clock_ref {
+                    fields.push(format!("\"clock_ref\":\"{:#x}\"", clock_ref));
+                }
+
+                if let Some(correlation_id) = line.correlation_id {
+                    fields.push(format!("\"correlation_id\":\"{:#x}\"", correlation_id));
+                }
+
+                if !line.detail.is_empty() {
+                    fields.push(format!("\"payload\":{}", json_string(line.detail.as_str())));
+                }
+
+                if let Some(hex) = &line.hex {
+                    fields.push(format!("\"hex\":\"{}\"", hex));
+                }
+
+                info!(target: "trace", "{{{}}}", fields.join(","));
+            }
+        };
+    }
+}
@@ -0,0 +1,35 @@
+#![allow(clippy::question_mark)]
+
+use crate::error::QTError;
+use crate::packet_buffer::PacketBytes;
+use std::sync::atomic::AtomicBool;
+
+pub trait Transport {
+    fn init(&mut self) -> Result<(), QTError>;
+
+    fn max_read_packet_size(&self) -> usize;
+
+    fn read_frame(&mut self, buf: &mut [u8]) -> Result<usize, QTError>;
+
+    // the next read as shared bytes, empty when nothing arrived in time. transports that own
+    // their read buffers hand them out as they are, the default reads into a new one
+    fn read_bytes(&mut self) -> Result<PacketBytes, QTError> {
+        let mut buf = vec![0u8; self.max_read_packet_size()];
+        let size = match self.read_frame(&mut buf) {
+            Ok(e) => e,
+            Err(e) => return Err(e),
+        };
+
+        buf.truncate(size);
+        Ok(PacketBytes::from_vec(buf))
+    }
+
+    fn write_frame(&mut self, buf: &[u8]) -> Result<usize, QTError>;
+
+    // waits for the device to come back after it went away, false when it can't or term was set
+    fn reconnect(&mut self, _term: &AtomicBool) -> Result<bool, QTError> {
+        Ok(false)
+    }
+
+    fn teardown(&mut self) -> Result<(), QTError>;
+}
@@ -0,0 +1,52 @@
+#![cfg(feature = "async")]
+
+use scrmiror::sim_device::SimulatedDevice;
+use scrmiror::{AsyncMirror, DisplayConfig, MirrorConfig};
+use std::time::Duration;
+
+// more frames than the stream queues hold, nobody reads them
+const FRAMES: u32 = 600;
+
+fn config() -> MirrorConfig {
+    let mut config = MirrorConfig::default();
+    config.set_display(DisplayConfig::new(1920, 1200, false));
+    config
+}
+
+#[tokio::test]
+async fn stop_with_unread_streams() {
+    let device = SimulatedDevice::new(FRAMES, true, false).unwrap();
+    let report = device.report();
+
+    let (mirror, video, audio) = AsyncMirror::start(device, config()).await.unwrap();
+
+    // let the queues fill up
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let stopped = tokio::time::timeout(Duration::from_secs(30), mirror.stop()).await;
+    assert!(stopped.is_ok(), "stop hung on full streams");
+    assert!(report.lock().unwrap().finished());
+
+    drop((video, audio));
+}
+
+#[tokio::test]
+async fn drop_with_unread_streams() {
+    let device = SimulatedDevice::new(FRAMES, true, false).unwrap();
+    let report = device.report();
+
+    let (mirror, _video, _audio) = AsyncMirror::start(device, config()).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    drop(mirror);
+
+    // the session thread tears the device down by itself
+    for _ in 0..300 {
+        if report.lock().unwrap().finished() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("session thread still running after drop");
+}
@@ -0,0 +1,69 @@
+use scrmiror::coremedia::sample::{MEDIA_TYPE_SOUND, MEDIA_TYPE_VIDEO};
+use scrmiror::sim_device::SimulatedDevice;
+use scrmiror::{DisplayConfig, Mirror, MirrorConfig};
+
+const FRAMES: u32 = 30;
+
+const NALU_TYPE_IDR: u8 = 5;
+// BLA, IDR and CRA pictures
+const HEVC_NALU_TYPE_IRAP_FIRST: u8 = 16;
+const HEVC_NALU_TYPE_IRAP_LAST: u8 = 23;
+
+// what the NALU types say, to check the satt attachment against
+fn is_key_frame(nal_units: &[&[u8]], hevc: bool) -> bool {
+    nal_units.iter().filter_map(|nalu| nalu.first()).any(|t| match hevc {
+        true => (HEVC_NALU_TYPE_IRAP_FIRST..=HEVC_NALU_TYPE_IRAP_LAST).contains(&((t >> 1) & 0x3F)),
+        false => t & 0x1F == NALU_TYPE_IDR,
+    })
+}
+
+// runs the whole handshake against the simulated device, video and audio sample counts
+fn simulate(audio: bool, hevc: bool) -> (usize, usize) {
+    let device = SimulatedDevice::new(FRAMES, audio, hevc).unwrap();
+    let report = device.report();
+
+    let mut config = MirrorConfig::default();
+    config.set_no_audio(!audio);
+    config.set_display(DisplayConfig::new(1920, 1200, hevc));
+    // the script ends the session by flipping term, like ctrl-c would
+    config.set_term(device.term());
+
+    let mut mirror = Mirror::start(device, config).unwrap();
+
+    let mut video = 0;
+    let mut audio_samples = 0;
+    for sample in mirror.samples() {
+        match sample.media_type() {
+            MEDIA_TYPE_VIDEO => {
+                assert!(!sample.nal_units().unwrap().is_empty());
+                // the satt NotSync attachment agrees with the NALU type
+                assert_eq!(sample.is_sync(), is_key_frame(&sample.nal_units().unwrap(), hevc));
+                video += 1;
+            }
+            MEDIA_TYPE_SOUND => audio_samples += 1,
+            t => panic!("unexpected media type {:#x}", t),
+        };
+    }
+
+    mirror.wait().unwrap();
+
+    let report = report.lock().unwrap();
+    assert!(report.passed(), "simulation errors: {:?}", report.errors());
+
+    (video, audio_samples)
+}
+
+#[test]
+fn h264_session() {
+    assert_eq!(simulate(true, false), (FRAMES as usize, FRAMES as usize));
+}
+
+#[test]
+fn hevc_session() {
+    assert_eq!(simulate(true, true), (FRAMES as usize, FRAMES as usize));
+}
+
+#[test]
+fn no_audio_session() {
+    assert_eq!(simulate(false, false), (FRAMES as usize, 0));
+}